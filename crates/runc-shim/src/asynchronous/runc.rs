@@ -17,25 +17,26 @@
 use std::{
     convert::TryFrom,
     os::{
-        fd::AsRawFd,
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
         unix::prelude::ExitStatusExt,
     },
     path::{Path, PathBuf},
     process::ExitStatus,
     sync::Arc,
+    time::Instant,
 };
 
 use async_trait::async_trait;
 use containerd_shim::{
     api::{CreateTaskRequest, ExecProcessRequest, Options, Status},
     asynchronous::{
-        console::ConsoleSocket,
+        console::{ConsoleBuffer, ConsoleSocket},
         container::{ContainerFactory, ContainerTemplate, ProcessFactory},
         monitor::{monitor_subscribe, monitor_unsubscribe, Subscription},
         processes::{ProcessLifecycle, ProcessTemplate},
     },
-    io::Stdio,
-    io_error,
+    io::{splice, Stdio},
+    io_error, metrics,
     monitor::{ExitEvent, Subject, Topic},
     other, other_error,
     protos::{
@@ -43,12 +44,14 @@ use containerd_shim::{
         cgroups::metrics::Metrics,
         protobuf::{CodedInputStream, Message},
     },
-    util::{asyncify, mkdir, mount_rootfs, read_file_to_str, write_options, write_runtime},
+    util::{
+        asyncify, mkdir, mount_rootfs, read_file_to_str, read_spec, write_options, write_runtime,
+    },
     Console, Error, ExitSignal, Result,
 };
 use log::{debug, error};
 use nix::{sys::signal::kill, unistd::Pid};
-use oci_spec::runtime::{LinuxResources, Process};
+use oci_spec::runtime::{LinuxResources, Process, Spec};
 use runc::{Command, Runc, Spawner};
 use tokio::{
     fs::{File, OpenOptions},
@@ -57,10 +60,12 @@ use tokio::{
 };
 
 use crate::common::{
-    check_kill_error, create_io, create_runc, get_spec_from_request, receive_socket, CreateConfig,
-    ProcessIO, ShimExecutor, INIT_PID_FILE,
+    check_kill_error, console_buffer_size, create_io, create_runc, get_spec_from_request,
+    receive_socket, runtime_subcommand, CreateConfig, ProcessIO, ShimExecutor, INIT_PID_FILE,
 };
 
+const CONSOLE_READ_SIZE: usize = 8 * 1024;
+
 pub type ExecProcess = ProcessTemplate<RuncExecLifecycle>;
 pub type InitProcess = ProcessTemplate<RuncInitLifecycle>;
 
@@ -88,6 +93,8 @@ impl ContainerFactory<RuncContainer> for RuncFactory {
         let runtime = opts.binary_name.as_str();
         write_options(bundle, &opts).await?;
         write_runtime(bundle, runtime).await?;
+        let spec: Spec = read_spec(bundle).await?;
+        let console_buffer_size = console_buffer_size(&spec);
 
         let rootfs_vec = req.rootfs().to_vec();
         let rootfs = if !rootfs_vec.is_empty() {
@@ -116,7 +123,13 @@ impl ContainerFactory<RuncContainer> for RuncFactory {
         let mut init = InitProcess::new(
             id,
             stdio,
-            RuncInitLifecycle::new(runc.clone(), opts.clone(), bundle),
+            RuncInitLifecycle::new(
+                runc.clone(),
+                opts.clone(),
+                bundle,
+                rootfs,
+                console_buffer_size,
+            ),
         );
 
         let config = CreateConfig::default();
@@ -130,6 +143,7 @@ impl ContainerFactory<RuncContainer> for RuncFactory {
                 bundle: bundle.to_string(),
                 io_uid: opts.io_uid,
                 io_gid: opts.io_gid,
+                console_buffer_size,
             },
             processes: Default::default(),
         };
@@ -147,6 +161,7 @@ impl RuncFactory {
         let stdio = &init.stdio;
         let opts = &init.lifecycle.opts;
         let bundle = &init.lifecycle.bundle;
+        let console_buffer_size = init.lifecycle.console_buffer_size;
         let pid_path = Path::new(bundle).join(INIT_PID_FILE);
         let mut create_opts = runc::options::CreateOpts::new()
             .pid_file(&pid_path)
@@ -174,7 +189,14 @@ impl RuncFactory {
             }
             return Err(other!("failed to create runc container: {}", e));
         }
-        copy_io_or_console(init, socket, pio, init.lifecycle.exit_signal.clone()).await?;
+        copy_io_or_console(
+            init,
+            socket,
+            pio,
+            init.lifecycle.exit_signal.clone(),
+            console_buffer_size,
+        )
+        .await?;
         let pid = read_file_to_str(pid_path).await?.parse::<i32>()?;
         init.pid = pid;
         Ok(())
@@ -186,6 +208,7 @@ pub struct RuncExecFactory {
     bundle: String,
     io_uid: u32,
     io_gid: u32,
+    console_buffer_size: usize,
 }
 
 #[async_trait]
@@ -206,12 +229,14 @@ impl ProcessFactory<ExecProcess> for RuncExecFactory {
             exited_at: None,
             wait_chan_tx: vec![],
             console: None,
+            console_buffer: None,
             lifecycle: Arc::from(RuncExecLifecycle {
                 runtime: self.runtime.clone(),
                 bundle: self.bundle.to_string(),
                 container_id: req.id.to_string(),
                 io_uid: self.io_uid,
                 io_gid: self.io_gid,
+                console_buffer_size: self.console_buffer_size,
                 spec: p,
                 exit_signal: Default::default(),
             }),
@@ -224,6 +249,9 @@ pub struct RuncInitLifecycle {
     runtime: Runc,
     opts: Options,
     bundle: String,
+    /// The rootfs mounted by the shim, empty if the rootfs is not passed in the request.
+    rootfs: PathBuf,
+    console_buffer_size: usize,
     exit_signal: Arc<ExitSignal>,
 }
 
@@ -270,6 +298,19 @@ impl ProcessLifecycle<InitProcess> for RuncInitLifecycle {
             })
             .map_err(other_error!(e, "failed delete"))?;
         self.exit_signal.signal();
+        #[cfg(target_os = "linux")]
+        if !self.rootfs.as_os_str().is_empty() {
+            let rootfs = self.rootfs.clone();
+            // the container is deleted already, so don't fail the delete for a rootfs that
+            // failed to unmount, it is left to the cleanup of the bundle
+            let res = asyncify(move || {
+                containerd_shim::mount::unmount_all(&rootfs, nix::mount::MntFlags::empty())
+            })
+            .await;
+            if let Err(e) = res {
+                error!("failed to unmount rootfs of {}: {}", p.id, e);
+            }
+        }
         Ok(())
     }
 
@@ -322,7 +363,13 @@ impl ProcessLifecycle<InitProcess> for RuncInitLifecycle {
 }
 
 impl RuncInitLifecycle {
-    pub fn new(runtime: Runc, opts: Options, bundle: &str) -> Self {
+    pub fn new(
+        runtime: Runc,
+        opts: Options,
+        bundle: &str,
+        rootfs: PathBuf,
+        console_buffer_size: usize,
+    ) -> Self {
         let work_dir = Path::new(bundle).join("work");
         let mut opts = opts;
         if opts.criu_path().is_empty() {
@@ -332,6 +379,8 @@ impl RuncInitLifecycle {
             runtime,
             opts,
             bundle: bundle.to_string(),
+            rootfs,
+            console_buffer_size,
             exit_signal: Default::default(),
         }
     }
@@ -343,6 +392,7 @@ pub struct RuncExecLifecycle {
     container_id: String,
     io_uid: u32,
     io_gid: u32,
+    console_buffer_size: usize,
     spec: Process,
     exit_signal: Arc<ExitSignal>,
 }
@@ -377,7 +427,14 @@ impl ProcessLifecycle<ExecProcess> for RuncExecLifecycle {
             }
             return Err(other!("failed to start runc exec: {}", e));
         }
-        copy_io_or_console(p, socket, pio, p.lifecycle.exit_signal.clone()).await?;
+        copy_io_or_console(
+            p,
+            socket,
+            pio,
+            p.lifecycle.exit_signal.clone(),
+            self.console_buffer_size,
+        )
+        .await?;
         let pid = read_file_to_str(pid_path).await?.parse::<i32>()?;
         p.pid = pid;
         p.state = Status::RUNNING;
@@ -428,7 +485,8 @@ async fn copy_console(
     console_socket: &ConsoleSocket,
     stdio: &Stdio,
     exit_signal: Arc<ExitSignal>,
-) -> Result<Console> {
+    console_buffer_size: usize,
+) -> Result<(Console, Option<Arc<ConsoleBuffer>>)> {
     debug!("copy_console: waiting for runtime to send console fd");
     let stream = console_socket.accept().await?;
     let f = asyncify(move || -> Result<std::fs::File> { receive_socket(stream.as_raw_fd()) }).await?;
@@ -463,6 +521,7 @@ async fn copy_console(
         );
     }
 
+    let mut console_buffer = None;
     if !stdio.stdout.is_empty() {
         let console_stdout = f
             .try_clone()
@@ -481,19 +540,27 @@ async fn copy_console(
             .open(stdio.stdout.as_str())
             .await
             .map_err(io_error!(e, "open stdout for read"))?;
-        spawn_copy(
-            console_stdout,
-            stdout,
-            exit_signal,
-            Some(move || {
+        if console_buffer_size > 0 {
+            let buffer = Arc::new(ConsoleBuffer::new(console_buffer_size));
+            spawn_buffered_copy(console_stdout, stdout, buffer.clone(), move || {
                 drop(stdout_r);
-            }),
-        );
+            });
+            console_buffer = Some(buffer);
+        } else {
+            spawn_copy(
+                console_stdout,
+                stdout,
+                exit_signal,
+                Some(move || {
+                    drop(stdout_r);
+                }),
+            );
+        }
     }
     let console = Console {
         file: f.into_std().await,
     };
-    Ok(console)
+    Ok((console, console_buffer))
 }
 
 pub async fn copy_io(pio: &ProcessIO, stdio: &Stdio, exit_signal: Arc<ExitSignal>) -> Result<()> {
@@ -513,62 +580,104 @@ pub async fn copy_io(pio: &ProcessIO, stdio: &Stdio, exit_signal: Arc<ExitSignal
             }
         }
 
-        if let Some(r) = io.stdout() {
-            debug!("copy_io: pipe stdout from to {}", stdio.stdout.as_str());
-            if !stdio.stdout.is_empty() {
-                let stdout = OpenOptions::new()
-                    .write(true)
-                    .open(stdio.stdout.as_str())
-                    .await
-                    .map_err(io_error!(e, "open stdout"))?;
-                // open a read to make sure even if the read end of containerd shutdown,
-                // copy still continue until the restart of containerd succeed
-                let stdout_r = OpenOptions::new()
-                    .read(true)
-                    .open(stdio.stdout.as_str())
-                    .await
-                    .map_err(io_error!(e, "open stdout for read"))?;
-                spawn_copy(
-                    r,
-                    stdout,
-                    exit_signal.clone(),
-                    Some(move || {
-                        drop(stdout_r);
-                    }),
-                );
+        copy_output(
+            "stdout",
+            io.stdout_fd(),
+            || io.stdout(),
+            stdio.stdout.as_str(),
+            exit_signal.clone(),
+        )
+        .await?;
+        copy_output(
+            "stderr",
+            io.stderr_fd(),
+            || io.stderr(),
+            stdio.stderr.as_str(),
+            exit_signal,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Open the FIFO at `path` to forward output of the process to.
+///
+/// A read side is also opened to make sure the copy continues even if containerd
+/// shuts down the read end, until containerd restarts.
+async fn open_output(name: &str, path: &str) -> Result<(File, File)> {
+    let w = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .await
+        .map_err(io_error!(e, "open {}", name))?;
+    let r = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .await
+        .map_err(io_error!(e, "open {} for read", name))?;
+    Ok((w, r))
+}
+
+/// Forward an output of the process to `path`, moving data in the kernel with splice(2)
+/// when both the output `fd` and `path` are pipes, or copying through `reader` otherwise.
+async fn copy_output<F>(
+    name: &str,
+    fd: Option<RawFd>,
+    reader: F,
+    path: &str,
+    exit_signal: Arc<ExitSignal>,
+) -> Result<()>
+where
+    F: FnOnce() -> Option<Box<dyn AsyncRead + Send + Sync + Unpin>>,
+{
+    if path.is_empty() {
+        let _ = reader();
+        return Ok(());
+    }
+    debug!("copy_io: pipe {} to {}", name, path);
+    match fd {
+        Some(fd) if splice::is_pipe(fd) => {
+            let (to, to_r) = open_output(name, path).await?;
+            if splice::is_pipe(to.as_raw_fd()) {
+                // SAFETY: the fd is handed over by the Io, the same way `Io::stdout` does.
+                let from = unsafe { OwnedFd::from_raw_fd(fd) };
+                spawn_splice(from, to.into_std().await, exit_signal, move || drop(to_r));
+            } else if let Some(from) = reader() {
+                spawn_copy(from, to, exit_signal, Some(move || drop(to_r)));
             }
         }
-
-        if let Some(r) = io.stderr() {
-            if !stdio.stderr.is_empty() {
-                debug!("copy_io: pipe stderr from to {}", stdio.stderr.as_str());
-                let stderr = OpenOptions::new()
-                    .write(true)
-                    .open(stdio.stderr.as_str())
-                    .await
-                    .map_err(io_error!(e, "open stderr"))?;
-                // open a read to make sure even if the read end of containerd shutdown,
-                // copy still continue until the restart of containerd succeed
-                let stderr_r = OpenOptions::new()
-                    .read(true)
-                    .open(stdio.stderr.as_str())
-                    .await
-                    .map_err(io_error!(e, "open stderr for read"))?;
-                spawn_copy(
-                    r,
-                    stderr,
-                    exit_signal,
-                    Some(move || {
-                        drop(stderr_r);
-                    }),
-                );
+        _ => {
+            if let Some(from) = reader() {
+                let (to, to_r) = open_output(name, path).await?;
+                spawn_copy(from, to, exit_signal, Some(move || drop(to_r)));
             }
         }
     }
-
     Ok(())
 }
 
+fn spawn_splice<R, W, F>(from: R, to: W, exit_signal: Arc<ExitSignal>, on_close: F)
+where
+    R: AsRawFd + Send + 'static,
+    W: AsRawFd + Send + 'static,
+    F: FnOnce() + Send + 'static,
+{
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = exit_signal.wait() => {
+                debug!("container exit, splice task should exit too");
+            },
+            res = splice::copy_async(from, to) => {
+               if let Err(e) = res {
+                    error!("splice io failed {}", e);
+                }
+            }
+        }
+        on_close();
+    });
+}
+
 fn spawn_copy<R, W, F>(from: R, to: W, exit_signal: Arc<ExitSignal>, on_close: Option<F>)
 where
     R: AsyncRead + Send + Unpin + 'static,
@@ -594,19 +703,61 @@ where
     });
 }
 
+/// Drain the console into `buffer` so the process is never blocked on its terminal,
+/// and copy the buffered output to `to` at the pace it is read.
+///
+/// Both tasks run until the console is closed, which is once every process holding the
+/// terminal exited, so that the last output of the process is never lost.
+fn spawn_buffered_copy<R, W, F>(from: R, to: W, buffer: Arc<ConsoleBuffer>, on_close: F)
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+    F: FnOnce() + Send + 'static,
+{
+    let mut src = from;
+    let src_buffer = buffer.clone();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; CONSOLE_READ_SIZE];
+        loop {
+            match src.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => src_buffer.write(&buf[..n]),
+                Err(e) => {
+                    // reading a console returns EIO once the other side is closed
+                    debug!("read console finished: {}", e);
+                    break;
+                }
+            }
+        }
+        src_buffer.close();
+    });
+
+    let mut dst = to;
+    tokio::spawn(async move {
+        let offset = buffer.start_offset();
+        if let Err(e) = buffer.copy_to(offset, &mut dst).await {
+            error!("copy console output failed {}", e);
+        }
+        on_close();
+    });
+}
+
 async fn copy_io_or_console<P>(
     p: &mut ProcessTemplate<P>,
     socket: Option<ConsoleSocket>,
     pio: Option<ProcessIO>,
     exit_signal: Arc<ExitSignal>,
+    console_buffer_size: usize,
 ) -> Result<()> {
     if p.stdio.terminal {
         if let Some(console_socket) = socket {
-            let console_result = copy_console(&console_socket, &p.stdio, exit_signal).await;
+            let console_result =
+                copy_console(&console_socket, &p.stdio, exit_signal, console_buffer_size).await;
             console_socket.clean().await;
             match console_result {
-                Ok(c) => {
+                Ok((c, buffer)) => {
                     p.console = Some(c);
+                    p.console_buffer = buffer;
                 }
                 Err(e) => {
                     return Err(e);
@@ -623,6 +774,8 @@ async fn copy_io_or_console<P>(
 impl Spawner for ShimExecutor {
     async fn execute(&self, cmd: Command, after_start: Box<dyn Fn()+Send>, wait_output: bool) -> runc::Result<(ExitStatus, u32, String, String)> {
         let mut cmd = cmd;
+        let command = runtime_subcommand(cmd.as_std().get_args());
+        let start = Instant::now();
         let subscription = monitor_subscribe(Topic::Pid)
             .await
             .map_err(|e| runc::error::Error::Other(Box::new(e)))?;
@@ -631,6 +784,7 @@ impl Spawner for ShimExecutor {
             Ok(c) => c,
             Err(e) => {
                 monitor_unsubscribe(sid).await.unwrap_or_default();
+                metrics::observe_runtime_command(&command, start.elapsed(), false);
                 return Err(runc::error::Error::ProcessSpawnFailed(e));
             }
         };
@@ -647,6 +801,7 @@ impl Spawner for ShimExecutor {
         };
         let status = ExitStatus::from_raw(exit_code);
         monitor_unsubscribe(sid).await.unwrap_or_default();
+        metrics::observe_runtime_command(&command, start.elapsed(), status.success());
         Ok((status, pid, stdout, stderr))
     }
 }
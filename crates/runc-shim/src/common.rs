@@ -14,12 +14,12 @@
    limitations under the License.
 */
 
-use std::{fs::File, io::IoSliceMut, ops::Deref, os::{fd::FromRawFd, unix::io::RawFd}, path::Path, sync::Arc};
+use std::{ffi::OsStr, fs::File, io::IoSliceMut, ops::Deref, os::{fd::FromRawFd, unix::io::RawFd}, path::{Path, PathBuf}, sync::Arc};
 use containerd_shim::{
     api::{ExecProcessRequest, Options},
     io::Stdio,
     io_error, other, other_error,
-    util::IntoOption,
+    util::{is_rootless, xdg_runtime_dir, IntoOption},
     Error,
 };
 use log::{debug, warn};
@@ -42,6 +42,11 @@ pub const GROUP_LABELS: [&str; 2] = [
     "io.kubernetes.cri.sandbox-id",
 ];
 pub const INIT_PID_FILE: &str = "init.pid";
+/// Annotation of the size in bytes of the ring buffer keeping the console output of terminal
+/// processes for replay on attach, the console is not buffered if it is unset or 0.
+pub const CONSOLE_BUFFER_SIZE_ANNOTATION: &str = "io.containerd.runc.v2.console-buffer-size";
+/// Upper bound of the console buffer size, larger sizes are clamped to it.
+pub const MAX_CONSOLE_BUFFER_SIZE: usize = 4 << 20;
 
 pub struct ProcessIO {
     pub _uri: Option<String>,
@@ -106,6 +111,25 @@ pub fn create_io(
 #[derive(Default, Debug)]
 pub struct ShimExecutor {}
 
+// runc global flags that take a separate value argument
+const RUNC_FLAGS_WITH_VALUE: [&str; 3] = ["--root", "--log", "--log-format"];
+
+/// Find the runtime subcommand, such as `create` or `exec`, in the arguments of a runc command.
+pub fn runtime_subcommand<'a>(args: impl IntoIterator<Item = &'a OsStr>) -> String {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if RUNC_FLAGS_WITH_VALUE.contains(&arg.as_ref()) {
+            args.next();
+            continue;
+        }
+        if !arg.starts_with('-') {
+            return arg.to_string();
+        }
+    }
+    "unknown".to_string()
+}
+
 pub fn get_spec_from_request(
     req: &ExecProcessRequest,
 ) -> containerd_shim::Result<oci_spec::runtime::Process> {
@@ -133,6 +157,8 @@ pub fn check_kill_error(emsg: String) -> Error {
 }
 
 const DEFAULT_RUNC_ROOT: &str = "/run/containerd/runc";
+/// Root of runc states in rootless mode, relative to `$XDG_RUNTIME_DIR`.
+const ROOTLESS_RUNC_ROOT: &str = "containerd/runc";
 const DEFAULT_COMMAND: &str = "runc";
 
 pub fn create_runc(
@@ -147,12 +173,13 @@ pub fn create_runc(
     } else {
         runtime
     };
-    let root = opts.root.as_str();
-    let root = Path::new(if root.is_empty() {
-        DEFAULT_RUNC_ROOT
+    let root = if !opts.root.is_empty() {
+        PathBuf::from(opts.root.as_str())
+    } else if is_rootless() {
+        Path::new(&xdg_runtime_dir()).join(ROOTLESS_RUNC_ROOT)
     } else {
-        root
-    })
+        PathBuf::from(DEFAULT_RUNC_ROOT)
+    }
     .join(namespace);
 
     let log = bundle.as_ref().join("log.json");
@@ -162,6 +189,9 @@ pub fn create_runc(
         .log(log)
         .log_json()
         .systemd_cgroup(opts.systemd_cgroup);
+    if is_rootless() {
+        gopts = gopts.rootless(true);
+    }
     if let Some(s) = spawner {
         gopts.custom_spawner(s);
     }
@@ -224,3 +254,25 @@ pub fn has_shared_pid_namespace(spec: &Spec) -> bool {
         },
     }
 }
+
+pub fn console_buffer_size(spec: &Spec) -> usize {
+    let size = spec
+        .annotations()
+        .as_ref()
+        .and_then(|a| a.get(CONSOLE_BUFFER_SIZE_ANNOTATION));
+    match size.map(|s| s.parse::<usize>()) {
+        Some(Ok(size)) if size > MAX_CONSOLE_BUFFER_SIZE => {
+            warn!(
+                "{} {} exceeds the maximum, clamped to {}",
+                CONSOLE_BUFFER_SIZE_ANNOTATION, size, MAX_CONSOLE_BUFFER_SIZE
+            );
+            MAX_CONSOLE_BUFFER_SIZE
+        }
+        Some(Ok(size)) => size,
+        Some(Err(e)) => {
+            warn!("invalid {}: {}", CONSOLE_BUFFER_SIZE_ANNOTATION, e);
+            0
+        }
+        None => 0,
+    }
+}
@@ -15,14 +15,15 @@
 */
 
 use std::{
-    fs::OpenOptions,
+    fs::{File, OpenOptions},
     io::{ErrorKind, Read, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
     thread::JoinHandle,
 };
 
 use containerd_shim::{
     error::{Error, Result},
-    io::Stdio,
+    io::{splice, Stdio},
     io_error,
 };
 use crossbeam::sync::WaitGroup;
@@ -94,6 +95,82 @@ where
     }
 }
 
+/// Move data from pipe `from` to pipe `to` with splice(2) in a new thread.
+pub fn spawn_splice<R: AsRawFd + Send + 'static, W: AsRawFd + Send + 'static>(
+    from: R,
+    to: W,
+    wg_opt: Option<&WaitGroup>,
+    on_close_opt: Option<Box<dyn FnOnce() + Send + Sync>>,
+) -> JoinHandle<()> {
+    let wg_opt_clone = wg_opt.cloned();
+    std::thread::spawn(move || {
+        if let Err(e) = splice::copy(&from, &to) {
+            debug!("splice io error: {}", e);
+        }
+        if let Some(x) = on_close_opt {
+            x()
+        };
+        if let Some(x) = wg_opt_clone {
+            std::mem::drop(x)
+        };
+    })
+}
+
+/// Open the FIFO at `path` to forward output of the process to.
+///
+/// A read side is also opened to make sure the copy continues even if containerd
+/// shuts down the read end, until containerd restarts.
+fn open_output(name: &str, path: &str) -> Result<(File, File)> {
+    let w = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(io_error!(e, "open {}", name))?;
+    let r =
+        OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(io_error!(e, "open {} for read", name))?;
+    Ok((w, r))
+}
+
+/// Forward an output of the process to `path`, moving data in the kernel with splice(2)
+/// when both the output `fd` and `path` are pipes, or copying through `reader` otherwise.
+fn copy_output<F>(
+    name: &str,
+    fd: Option<RawFd>,
+    reader: F,
+    path: &str,
+    wg: &WaitGroup,
+) -> Result<()>
+where
+    F: FnOnce() -> Option<Box<dyn Read + Send>>,
+{
+    if path.is_empty() {
+        let _ = reader();
+        return Ok(());
+    }
+    debug!("copy_io: pipe {} to {}", name, path);
+    match fd {
+        Some(fd) if splice::is_pipe(fd) => {
+            let (to, to_r) = open_output(name, path)?;
+            if splice::is_pipe(to.as_raw_fd()) {
+                // SAFETY: the fd is handed over by the Io, the same way `Io::stdout` does.
+                let from = unsafe { OwnedFd::from_raw_fd(fd) };
+                spawn_splice(from, to, Some(wg), Some(Box::new(move || drop(to_r))));
+            } else if let Some(from) = reader() {
+                spawn_copy(from, to, Some(wg), Some(Box::new(move || drop(to_r))));
+            }
+        }
+        _ => {
+            if let Some(from) = reader() {
+                let (to, to_r) = open_output(name, path)?;
+                spawn_copy(from, to, Some(wg), Some(Box::new(move || drop(to_r))));
+            }
+        }
+    }
+    Ok(())
+}
+
 impl ProcessIO {
     pub fn copy(&self, stdio: &Stdio) -> Result<WaitGroup> {
         let wg = WaitGroup::new();
@@ -112,53 +189,20 @@ impl ProcessIO {
                 }
             }
 
-            if let Some(r) = pio.stdout() {
-                debug!("copy_io: pipe stdout from to {}", stdio.stdout.as_str());
-                if !stdio.stdout.is_empty() {
-                    let stdout = OpenOptions::new()
-                        .write(true)
-                        .open(stdio.stdout.as_str())
-                        .map_err(io_error!(e, "open stdout"))?;
-                    // open a read to make sure even if the read end of containerd shutdown,
-                    // copy still continue until the restart of containerd succeed
-                    let stdout_r = OpenOptions::new()
-                        .read(true)
-                        .open(stdio.stdout.as_str())
-                        .map_err(io_error!(e, "open stdout for read"))?;
-                    spawn_copy(
-                        r,
-                        stdout,
-                        Some(&wg),
-                        Some(Box::new(move || {
-                            drop(stdout_r);
-                        })),
-                    );
-                }
-            }
-
-            if let Some(r) = pio.stderr() {
-                if !stdio.stderr.is_empty() {
-                    debug!("copy_io: pipe stderr from to {}", stdio.stderr.as_str());
-                    let stderr = OpenOptions::new()
-                        .write(true)
-                        .open(stdio.stderr.as_str())
-                        .map_err(io_error!(e, "open stderr"))?;
-                    // open a read to make sure even if the read end of containerd shutdown,
-                    // copy still continue until the restart of containerd succeed
-                    let stderr_r = OpenOptions::new()
-                        .read(true)
-                        .open(stdio.stderr.as_str())
-                        .map_err(io_error!(e, "open stderr for read"))?;
-                    spawn_copy(
-                        r,
-                        stderr,
-                        Some(&wg),
-                        Some(Box::new(move || {
-                            drop(stderr_r);
-                        })),
-                    );
-                }
-            }
+            copy_output(
+                "stdout",
+                pio.stdout_fd(),
+                || pio.stdout(),
+                stdio.stdout.as_str(),
+                &wg,
+            )?;
+            copy_output(
+                "stderr",
+                pio.stderr_fd(),
+                || pio.stderr(),
+                stdio.stderr.as_str(),
+                &wg,
+            )?;
         }
 
         Ok(wg)
@@ -25,6 +25,7 @@ use std::{
         mpsc::{Receiver, SyncSender},
         Arc,
     },
+    time::Instant,
 };
 
 use containerd_shim as shim;
@@ -40,6 +41,7 @@ use shim::{
     console::ConsoleSocket,
     error::{Error, Result},
     io::Stdio,
+    metrics,
     monitor::{monitor_subscribe, wait_pid, ExitEvent, Subject, Subscription, Topic},
     mount::mount_rootfs,
     other, other_error,
@@ -56,7 +58,10 @@ use time::OffsetDateTime;
 
 use crate::{
     common,
-    common::{create_io, has_shared_pid_namespace, CreateConfig, ShimExecutor, INIT_PID_FILE},
+    common::{
+        create_io, has_shared_pid_namespace, runtime_subcommand, CreateConfig, ShimExecutor,
+        INIT_PID_FILE,
+    },
     synchronous::container::{
         CommonContainer, CommonProcess, Container, ContainerFactory, Process,
     },
@@ -280,6 +285,17 @@ impl Container for RuncContainer {
                         }
                     })
                     .map_err(other_error!(e, "failed delete"))?;
+                #[cfg(target_os = "linux")]
+                if !self.common.init.rootfs.is_empty() {
+                    // the container is deleted already, so don't fail the delete for a rootfs
+                    // that failed to unmount, it is left to the cleanup of the bundle
+                    if let Err(e) = shim::mount::unmount_all(
+                        &self.common.init.rootfs,
+                        nix::mount::MntFlags::empty(),
+                    ) {
+                        error!("failed to unmount rootfs of {}: {}", self.common.id, e);
+                    }
+                }
             }
         };
         Ok((pid, code, exited_at))
@@ -645,11 +661,14 @@ impl TryFrom<ExecProcessRequest> for ExecProcess {
 impl Spawner for ShimExecutor {
     fn execute(&self, cmd: Command) -> runc::Result<(ExitStatus, u32, String, String)> {
         let mut cmd = cmd;
+        let command = runtime_subcommand(cmd.get_args());
+        let start = Instant::now();
         let subscription =
             monitor_subscribe(Topic::Pid).map_err(|e| runc::error::Error::Other(Box::new(e)))?;
         let child = match cmd.spawn() {
             Ok(c) => c,
             Err(e) => {
+                metrics::observe_runtime_command(&command, start.elapsed(), false);
                 return Err(runc::error::Error::ProcessSpawnFailed(e));
             }
         };
@@ -661,6 +680,7 @@ impl Spawner for ShimExecutor {
             wait_pid(pid as i32, subscription),
         );
         let status = ExitStatus::from_raw(exit_code);
+        metrics::observe_runtime_command(&command, start.elapsed(), status.success());
         Ok((status, pid, stdout, stderr))
     }
 }
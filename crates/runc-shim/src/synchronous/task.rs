@@ -26,7 +26,7 @@ use oci_spec::runtime::LinuxResources;
 use shim::{
     api::*,
     event::Event,
-    other_error,
+    metrics, other_error,
     protos::{
         events::task::{TaskCreate, TaskDelete, TaskExecAdded, TaskExecStarted, TaskIO, TaskStart},
         protobuf::MessageDyn,
@@ -82,6 +82,7 @@ where
     C: Container,
 {
     fn state(&self, _ctx: &TtrpcContext, req: StateRequest) -> TtrpcResult<StateResponse> {
+        let _timer = metrics::rpc_timer("task", "state");
         let containers = self.containers.lock().unwrap();
         let container = containers.get(req.id.as_str()).ok_or_else(|| {
             Error::NotFoundError(format!("can not find container by id {}", req.id.as_str()))
@@ -96,6 +97,7 @@ where
         _ctx: &TtrpcContext,
         req: CreateTaskRequest,
     ) -> TtrpcResult<CreateTaskResponse> {
+        let _timer = metrics::rpc_timer("task", "create");
         info!("Create request for {:?}", &req);
         // Note: Get containers here is for getting the lock,
         // to make sure no other threads manipulate the containers metadata;
@@ -110,6 +112,7 @@ where
         resp.pid = pid;
 
         containers.insert(id.to_string(), container);
+        metrics::inc_containers();
 
         self.send_event(TaskCreate {
             container_id: req.id.to_string(),
@@ -133,6 +136,7 @@ where
     }
 
     fn start(&self, _ctx: &TtrpcContext, req: StartRequest) -> TtrpcResult<StartResponse> {
+        let _timer = metrics::rpc_timer("task", "start");
         info!("Start request for {:?}", &req);
         let mut containers = self.containers.lock().unwrap();
         let container = containers.get_mut(req.id()).ok_or_else(|| {
@@ -163,6 +167,7 @@ where
     }
 
     fn delete(&self, _ctx: &TtrpcContext, req: DeleteRequest) -> TtrpcResult<DeleteResponse> {
+        let _timer = metrics::rpc_timer("task", "delete");
         info!("Delete request for {:?}", &req);
         let mut containers = self.containers.lock().unwrap();
         let container = containers.get_mut(req.id()).ok_or_else(|| {
@@ -173,6 +178,9 @@ where
         let (pid, exit_status, exited_at) = container.delete(exec_id_opt)?;
         if req.exec_id().is_empty() {
             containers.remove(req.id.as_str());
+            metrics::dec_containers();
+        } else {
+            metrics::dec_processes();
         }
 
         let ts = convert_to_timestamp(exited_at);
@@ -198,6 +206,7 @@ where
     }
 
     fn pids(&self, _ctx: &TtrpcContext, req: PidsRequest) -> TtrpcResult<PidsResponse> {
+        let _timer = metrics::rpc_timer("task", "pids");
         debug!("Pids request for {:?}", req);
         let containers = self.containers.lock().unwrap();
         let container = containers
@@ -209,6 +218,7 @@ where
     }
 
     fn kill(&self, _ctx: &TtrpcContext, req: KillRequest) -> TtrpcResult<Empty> {
+        let _timer = metrics::rpc_timer("task", "kill");
         info!("Kill request for {:?}", req);
         let mut containers = self.containers.lock().unwrap();
         let container = containers.get_mut(req.id()).ok_or_else(|| {
@@ -224,6 +234,7 @@ where
     }
 
     fn exec(&self, _ctx: &TtrpcContext, req: ExecProcessRequest) -> TtrpcResult<Empty> {
+        let _timer = metrics::rpc_timer("task", "exec");
         let exec_id = req.exec_id().to_string();
         info!(
             "Exec request for id: {} exec_id: {}",
@@ -235,6 +246,7 @@ where
             .get_mut(req.id())
             .ok_or_else(|| Error::Other(format!("can not find container by id {}", req.id())))?;
         container.exec(req)?;
+        metrics::inc_processes();
 
         self.send_event(TaskExecAdded {
             container_id: container.id(),
@@ -246,6 +258,7 @@ where
     }
 
     fn resize_pty(&self, _ctx: &TtrpcContext, req: ResizePtyRequest) -> TtrpcResult<Empty> {
+        let _timer = metrics::rpc_timer("task", "resize_pty");
         debug!(
             "Resize pty request for container {}, exec_id: {}",
             &req.id, &req.exec_id
@@ -263,11 +276,13 @@ where
     }
 
     fn close_io(&self, _ctx: &TtrpcContext, _req: CloseIORequest) -> TtrpcResult<Empty> {
+        let _timer = metrics::rpc_timer("task", "close_io");
         // unnecessary close io here since fd was closed automatically after object was destroyed.
         Ok(Empty::new())
     }
 
     fn update(&self, _ctx: &TtrpcContext, req: UpdateTaskRequest) -> TtrpcResult<Empty> {
+        let _timer = metrics::rpc_timer("task", "update");
         debug!("Update request for {:?}", req);
         let mut containers = self.containers.lock().unwrap();
         let container = containers
@@ -325,6 +340,7 @@ where
     }
 
     fn stats(&self, _ctx: &TtrpcContext, req: StatsRequest) -> TtrpcResult<StatsResponse> {
+        let _timer = metrics::rpc_timer("task", "stats");
         debug!("Stats request for {:?}", req);
         let containers = self.containers.lock().unwrap();
         let container = containers
@@ -338,6 +354,7 @@ where
     }
 
     fn shutdown(&self, _ctx: &TtrpcContext, _req: ShutdownRequest) -> TtrpcResult<Empty> {
+        let _timer = metrics::rpc_timer("task", "shutdown");
         debug!("Shutdown request");
         let containers = self.containers.lock().unwrap();
         if containers.len() > 0 {
@@ -352,6 +369,7 @@ where
     }
 
     fn connect(&self, _ctx: &TtrpcContext, req: ConnectRequest) -> TtrpcResult<ConnectResponse> {
+        let _timer = metrics::rpc_timer("task", "connect");
         info!("Connect request for {:?}", req);
 
         let containers = self.containers.lock().unwrap();
@@ -73,6 +73,21 @@ pub trait Io: Debug + Send + Sync {
         None
     }
 
+    /// Return the raw fd of the read side of stdout, if it is a pipe.
+    ///
+    /// It's an alternative to [`Io::stdout`] for callers moving data with splice(2),
+    /// the caller takes ownership of the fd the same way.
+    fn stdout_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Return the raw fd of the read side of stderr, if it is a pipe.
+    ///
+    /// See [`Io::stdout_fd`].
+    fn stderr_fd(&self) -> Option<RawFd> {
+        None
+    }
+
     /// Set IO for passed command.
     /// Read side of stdin, write side of stdout and write side of stderr should be provided to command.
     fn set(&self, cmd: &mut Command) -> Result<()>;
@@ -208,6 +223,14 @@ impl Io for PipedIo {
         })
     }
 
+    fn stdout_fd(&self) -> Option<RawFd> {
+        self.stdout.as_ref().map(|pipe| pipe.rd)
+    }
+
+    fn stderr_fd(&self) -> Option<RawFd> {
+        self.stderr.as_ref().map(|pipe| pipe.rd)
+    }
+
     // Note that this internally use [`std::fs::File`]'s `try_clone()`.
     // Thus, the files passed to commands will be not closed after command exit.
     fn set(&self, cmd: &mut Command) -> std::io::Result<()> {
@@ -1,7 +1,16 @@
 fn main() {
     tonic_build::configure()
         .build_server(true)
-        .compile(&["src/protos/sandbox.proto"], &["src/protos"])
+        .compile(
+            &[
+                // api/services/sandbox/v1/sandbox.proto of containerd 2.0, served to 1.7 as well
+                "src/protos/sandbox.proto",
+                "src/protos/events.proto",
+                "src/protos/extension.proto",
+                "src/protos/github.com/containerd/cgroups/cgroup2/stats/metrics.proto",
+            ],
+            &["src/protos"],
+        )
         .unwrap();
 
     tonic_build::configure()
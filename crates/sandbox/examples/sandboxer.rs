@@ -5,8 +5,11 @@ use anyhow::anyhow;
 use async_trait::async_trait;
 use tokio::sync::{Mutex, RwLock};
 
+use containerd_sandbox::cgroup::collect_metrics;
+use containerd_sandbox::cgroups::v2::Metrics;
 use containerd_sandbox::data::{ContainerData, SandboxData};
-use containerd_sandbox::error::Result;
+use containerd_sandbox::error::{Error, Result};
+use containerd_sandbox::event::{Event, EventPublisher};
 use containerd_sandbox::signal::ExitSignal;
 use containerd_sandbox::{
     run, Container, ContainerOption, Sandbox, SandboxOption, SandboxStatus, Sandboxer,
@@ -21,6 +24,7 @@ pub struct ExampleSandbox {
     status: SandboxStatus,
     data: SandboxData,
     containers: HashMap<String, ExampleContainer>,
+    events: EventPublisher,
 }
 
 #[derive(Clone, Debug)]
@@ -50,7 +54,9 @@ impl Sandboxer for ExampleSandboxer {
             status: SandboxStatus::Created,
             data: s.sandbox,
             containers: Default::default(),
+            events: s.events,
         };
+        sandbox.events.publish(id, Event::Created);
         self.sandboxes
             .write()
             .await
@@ -68,6 +74,7 @@ impl Sandboxer for ExampleSandboxer {
             .clone();
         let mut sandbox = sandbox_mutex.lock().await;
         sandbox.status = SandboxStatus::Running(7000000);
+        sandbox.events.publish(id, Event::Ready(7000000));
         Ok(())
     }
 
@@ -99,6 +106,29 @@ impl Sandboxer for ExampleSandboxer {
         self.sandboxes.write().await.remove(id);
         Ok(())
     }
+
+    async fn recover(&self, id: &str, s: SandboxOption, c: Vec<ContainerData>) -> Result<()> {
+        // There is no process behind the example sandbox, a real sandboxer should check
+        // whether the sandbox process survived the restart.
+        let status = match (s.sandbox.started_at, s.sandbox.exited_at) {
+            (_, Some(_)) | (None, None) => SandboxStatus::Stopped(0, 0),
+            (Some(_), None) => SandboxStatus::Running(7000000),
+        };
+        let sandbox = ExampleSandbox {
+            status,
+            data: s.sandbox,
+            containers: c
+                .into_iter()
+                .map(|data| (data.id.clone(), ExampleContainer { data }))
+                .collect(),
+            events: s.events,
+        };
+        self.sandboxes
+            .write()
+            .await
+            .insert(id.to_string(), Arc::new(Mutex::new(sandbox)));
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -153,6 +183,19 @@ impl Sandbox for ExampleSandbox {
     fn get_data(&self) -> Result<SandboxData> {
         Ok(self.data.clone())
     }
+
+    async fn metrics(&self) -> Result<Metrics> {
+        // Processes of the sandbox, such as the VMM, are expected to be in the pod cgroup.
+        let cgroup = self
+            .data
+            .config
+            .as_ref()
+            .and_then(|c| c.linux.as_ref())
+            .map(|l| l.cgroup_parent.as_str())
+            .filter(|p| !p.is_empty())
+            .ok_or_else(|| Error::NotFound(format!("cgroup parent of sandbox {}", self.data.id)))?;
+        collect_metrics(cgroup).await
+    }
 }
 
 impl Container for ExampleContainer {
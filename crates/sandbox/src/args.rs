@@ -6,6 +6,8 @@ use crate::error::{Error, Result};
 pub struct Flags {
     pub listen: String,
     pub dir: String,
+    /// Optional unix socket to serve metrics on.
+    pub metrics: String,
 }
 
 pub fn parse<S: AsRef<OsStr>>(args: &[S]) -> Result<Flags> {
@@ -14,6 +16,7 @@ pub fn parse<S: AsRef<OsStr>>(args: &[S]) -> Result<Flags> {
     let _: Vec<String> = go_flag::parse_args(args, |f| {
         f.add_flag("listen", &mut flags.listen);
         f.add_flag("dir", &mut flags.dir);
+        f.add_flag("metrics", &mut flags.metrics);
     })
     .map_err(|e| Error::InvalidArgument(e.to_string()))?;
 
@@ -0,0 +1,90 @@
+//! Authorization of the callers of the controller by the credentials of the peer process,
+//! see [`crate::unix::peer_cred`].
+
+use log::warn;
+use tonic::{Request, Status};
+
+use crate::unix::peer_cred;
+
+/// Processes allowed to call the controller, a caller matching any of the uids, gids or pids
+/// is allowed. An empty list allows any caller.
+///
+/// Mirrors the `PeerAllowList` of `containerd-shim`, which has no dependency shared with this
+/// crate, so changes to the matching rules have to be made to both.
+#[derive(Clone, Debug, Default)]
+pub struct PeerAllowList {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+    pub pids: Vec<i32>,
+}
+
+impl PeerAllowList {
+    pub fn is_empty(&self) -> bool {
+        self.uids.is_empty() && self.gids.is_empty() && self.pids.is_empty()
+    }
+
+    /// Whether a caller is allowed, the pid is `None` if it is unknown.
+    pub fn allows(&self, uid: u32, gid: u32, pid: Option<i32>) -> bool {
+        self.is_empty()
+            || self.uids.contains(&uid)
+            || self.gids.contains(&gid)
+            || pid.map(|p| self.pids.contains(&p)).unwrap_or_default()
+    }
+
+    /// Check the caller of the request before dispatching `method`, callers without
+    /// credentials, e.g. over vsock, are only allowed by an empty list. Rejected callers are
+    /// logged for auditing.
+    pub fn authorize<T>(&self, request: &Request<T>, method: &str) -> Result<(), Status> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        match peer_cred(request) {
+            Some(c) if self.allows(c.uid(), c.gid(), c.pid()) => Ok(()),
+            Some(c) => {
+                warn!(
+                    "audit: rejected {} from uid {} gid {} pid {:?}",
+                    method,
+                    c.uid(),
+                    c.gid(),
+                    c.pid()
+                );
+                Err(Status::permission_denied(format!(
+                    "caller is not allowed to call {}",
+                    method
+                )))
+            }
+            None => {
+                warn!("audit: rejected {} from unknown peer", method);
+                Err(Status::permission_denied(format!(
+                    "caller of {} is unknown",
+                    method
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_allow_list() {
+        assert!(PeerAllowList::default().allows(1000, 1000, None));
+        let peers = PeerAllowList {
+            uids: vec![0],
+            gids: vec![27],
+            pids: vec![42],
+        };
+        assert!(peers.allows(0, 0, None));
+        assert!(peers.allows(1000, 27, None));
+        assert!(peers.allows(1000, 1000, Some(42)));
+        assert!(!peers.allows(1000, 1000, Some(1)));
+        assert!(!peers.allows(1000, 1000, None));
+        // requests not over a unix socket carry no credentials
+        assert!(peers.authorize(&Request::new(()), "create").is_err());
+        assert!(PeerAllowList::default()
+            .authorize(&Request::new(()), "create")
+            .is_ok());
+    }
+}
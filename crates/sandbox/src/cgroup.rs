@@ -0,0 +1,227 @@
+//! Collect resource usage of a cgroup v2, to report pod level metrics of sandboxes.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::anyhow;
+
+use crate::cgroups::v2::{CpuStat, IoEntry, IoStat, MemoryEvents, MemoryStat, Metrics, PidsStat};
+use crate::error::Result;
+
+/// Mount point of the cgroup v2 unified hierarchy.
+pub const CGROUP_V2_ROOT: &str = "/sys/fs/cgroup";
+
+/// Type url of the metrics in `containerd.types.Metric`, which containerd decodes the data with.
+pub const METRICS_TYPE_URL: &str = "io.containerd.cgroups.v2.Metrics";
+
+/// Collect the metrics of the cgroup at `path`, which is relative to [`CGROUP_V2_ROOT`]
+/// unless it is absolute, e.g. the `cgroup_parent` of a pod.
+///
+/// Stats of controllers not enabled in the cgroup are left empty. Limits set to `max` are
+/// reported as `u64::MAX`.
+pub async fn collect_metrics(path: impl AsRef<Path>) -> Result<Metrics> {
+    let path = path.as_ref();
+    let dir = if path.starts_with(CGROUP_V2_ROOT) {
+        path.to_path_buf()
+    } else {
+        Path::new(CGROUP_V2_ROOT).join(path.strip_prefix("/").unwrap_or(path))
+    };
+    if !dir.join("cgroup.controllers").exists() {
+        return Err(anyhow!("{} is not a cgroup v2 directory", dir.display()).into());
+    }
+
+    let mut metrics = Metrics::default();
+
+    if let Some(current) = read_value(&dir, "pids.current").await? {
+        metrics.pids = Some(PidsStat {
+            current,
+            limit: read_value(&dir, "pids.max").await?.unwrap_or_default(),
+        });
+    }
+
+    if let Some(stat) = read_flat_keyed(&dir, "cpu.stat").await? {
+        let get = |k: &str| stat.get(k).copied().unwrap_or_default();
+        metrics.cpu = Some(CpuStat {
+            usage_usec: get("usage_usec"),
+            user_usec: get("user_usec"),
+            system_usec: get("system_usec"),
+            nr_periods: get("nr_periods"),
+            nr_throttled: get("nr_throttled"),
+            throttled_usec: get("throttled_usec"),
+            psi: None,
+        });
+    }
+
+    if let Some(stat) = read_flat_keyed(&dir, "memory.stat").await? {
+        let get = |k: &str| stat.get(k).copied().unwrap_or_default();
+        metrics.memory = Some(MemoryStat {
+            anon: get("anon"),
+            file: get("file"),
+            kernel_stack: get("kernel_stack"),
+            slab: get("slab"),
+            sock: get("sock"),
+            shmem: get("shmem"),
+            file_mapped: get("file_mapped"),
+            file_dirty: get("file_dirty"),
+            file_writeback: get("file_writeback"),
+            anon_thp: get("anon_thp"),
+            inactive_anon: get("inactive_anon"),
+            active_anon: get("active_anon"),
+            inactive_file: get("inactive_file"),
+            active_file: get("active_file"),
+            unevictable: get("unevictable"),
+            slab_reclaimable: get("slab_reclaimable"),
+            slab_unreclaimable: get("slab_unreclaimable"),
+            pgfault: get("pgfault"),
+            pgmajfault: get("pgmajfault"),
+            workingset_refault: get("workingset_refault"),
+            workingset_activate: get("workingset_activate"),
+            workingset_nodereclaim: get("workingset_nodereclaim"),
+            pgrefill: get("pgrefill"),
+            pgscan: get("pgscan"),
+            pgsteal: get("pgsteal"),
+            pgactivate: get("pgactivate"),
+            pgdeactivate: get("pgdeactivate"),
+            pglazyfree: get("pglazyfree"),
+            pglazyfreed: get("pglazyfreed"),
+            thp_fault_alloc: get("thp_fault_alloc"),
+            thp_collapse_alloc: get("thp_collapse_alloc"),
+            usage: read_value(&dir, "memory.current")
+                .await?
+                .unwrap_or_default(),
+            usage_limit: read_value(&dir, "memory.max").await?.unwrap_or_default(),
+            swap_usage: read_value(&dir, "memory.swap.current")
+                .await?
+                .unwrap_or_default(),
+            swap_limit: read_value(&dir, "memory.swap.max")
+                .await?
+                .unwrap_or_default(),
+            max_usage: read_value(&dir, "memory.peak").await?.unwrap_or_default(),
+            swap_max_usage: read_value(&dir, "memory.swap.peak")
+                .await?
+                .unwrap_or_default(),
+            psi: None,
+        });
+    }
+
+    if let Some(events) = read_flat_keyed(&dir, "memory.events").await? {
+        let get = |k: &str| events.get(k).copied().unwrap_or_default();
+        metrics.memory_events = Some(MemoryEvents {
+            low: get("low"),
+            high: get("high"),
+            max: get("max"),
+            oom: get("oom"),
+            oom_kill: get("oom_kill"),
+        });
+    }
+
+    if let Some(content) = read_file(&dir, "io.stat").await? {
+        metrics.io = Some(IoStat {
+            usage: parse_io_stat(&content),
+            psi: None,
+        });
+    }
+
+    Ok(metrics)
+}
+
+async fn read_file(dir: &Path, name: &str) -> Result<Option<String>> {
+    match tokio::fs::read_to_string(dir.join(name)).await {
+        Ok(content) => Ok(Some(content)),
+        // the controller is not enabled in the cgroup
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn parse_value(s: &str) -> Result<u64> {
+    let s = s.trim();
+    if s == "max" {
+        return Ok(u64::MAX);
+    }
+    s.parse::<u64>()
+        .map_err(|e| anyhow!("failed to parse cgroup value {:?}: {}", s, e).into())
+}
+
+async fn read_value(dir: &Path, name: &str) -> Result<Option<u64>> {
+    match read_file(dir, name).await? {
+        Some(content) => Ok(Some(parse_value(&content)?)),
+        None => Ok(None),
+    }
+}
+
+/// Parse files of `<key> <value>` lines, such as `cpu.stat` and `memory.stat`.
+fn parse_flat_keyed(content: &str) -> HashMap<String, u64> {
+    content
+        .lines()
+        .filter_map(|l| {
+            let (k, v) = l.split_once(' ')?;
+            Some((k.to_string(), parse_value(v).ok()?))
+        })
+        .collect()
+}
+
+async fn read_flat_keyed(dir: &Path, name: &str) -> Result<Option<HashMap<String, u64>>> {
+    Ok(read_file(dir, name)
+        .await?
+        .map(|content| parse_flat_keyed(&content)))
+}
+
+/// Parse `io.stat`, which has a `<major>:<minor> <key>=<value>...` line per device.
+fn parse_io_stat(content: &str) -> Vec<IoEntry> {
+    content
+        .lines()
+        .filter_map(|l| {
+            let mut fields = l.split_whitespace();
+            let (major, minor) = fields.next()?.split_once(':')?;
+            let mut entry = IoEntry {
+                major: major.parse().ok()?,
+                minor: minor.parse().ok()?,
+                ..Default::default()
+            };
+            for (k, v) in fields.filter_map(|f| f.split_once('=')) {
+                let v = v.parse().unwrap_or_default();
+                match k {
+                    "rbytes" => entry.rbytes = v,
+                    "wbytes" => entry.wbytes = v,
+                    "rios" => entry.rios = v,
+                    "wios" => entry.wios = v,
+                    _ => {}
+                }
+            }
+            Some(entry)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_flat_keyed() {
+        let stat = parse_flat_keyed("usage_usec 1234\nuser_usec 1000\nsystem_usec 234\n");
+        assert_eq!(stat.get("usage_usec"), Some(&1234));
+        assert_eq!(stat.get("system_usec"), Some(&234));
+        assert_eq!(stat.get("nr_periods"), None);
+    }
+
+    #[test]
+    fn test_parse_io_stat() {
+        let entries = parse_io_stat(
+            "8:0 rbytes=4096 wbytes=8192 rios=1 wios=2 dbytes=0 dios=0\n253:1 rbytes=10 wbytes=0 rios=3 wios=0\n",
+        );
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].major, entries[0].minor), (8, 0));
+        assert_eq!(entries[0].wbytes, 8192);
+        assert_eq!(entries[1].rios, 3);
+    }
+
+    #[test]
+    fn test_parse_value() {
+        assert_eq!(parse_value("max\n").unwrap(), u64::MAX);
+        assert_eq!(parse_value("1048576\n").unwrap(), 1048576);
+        assert!(parse_value("abc").is_err());
+    }
+}
@@ -1,13 +1,81 @@
+//! A typed view of the CRI `PodSandboxConfig`, validated when converted from the prost
+//! message in the create request, see [`crate::data::SandboxData::pod_config`].
+
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::cri::api::v1 as cri;
+use crate::error::{Error, Result};
+use crate::spec::{
+    JsonSpec, Linux, LinuxCPU, LinuxIDMapping, LinuxNamespace, LinuxResources, Mount, Process,
+    Root, CONTAINER_TYPE_SANDBOX, CRI_CONTAINERD_CONTAINER_TYPE_KEY, CRI_CONTAINERD_SANDBOX_ID_KEY,
+};
+
+const SANDBOX_NAME_KEY: &str = "io.kubernetes.cri.sandbox-name";
+const SANDBOX_NAMESPACE_KEY: &str = "io.kubernetes.cri.sandbox-namespace";
+const SANDBOX_UID_KEY: &str = "io.kubernetes.cri.sandbox-uid";
+const SANDBOX_LOG_DIR_KEY: &str = "io.kubernetes.cri.sandbox-log-directory";
+const SANDBOX_CPU_PERIOD_KEY: &str = "io.kubernetes.cri.sandbox-cpu-period";
+const SANDBOX_CPU_QUOTA_KEY: &str = "io.kubernetes.cri.sandbox-cpu-quota";
+const SANDBOX_CPU_SHARES_KEY: &str = "io.kubernetes.cri.sandbox-cpu-shares";
+const SANDBOX_MEMORY_KEY: &str = "io.kubernetes.cri.sandbox-memory";
+
+/// Same as the sandbox container of containerd, which only holds namespaces.
+const SANDBOX_CPU_SHARES: u64 = 2;
+const SANDBOX_OOM_SCORE_ADJ: i32 = -998;
+const DEFAULT_PATH_ENV: &str = "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+const DEFAULT_APPARMOR_PROFILE: &str = "cri-containerd.apparmor.d";
+
+/// Sysctls isolated by the ipc namespace, the `fs.mqueue.` ones are matched by prefix.
+const IPC_SYSCTLS: [&str; 8] = [
+    "kernel.shmall",
+    "kernel.shmmax",
+    "kernel.shmmni",
+    "kernel.shm_rmid_forced",
+    "kernel.msgmax",
+    "kernel.msgmnb",
+    "kernel.msgmni",
+    "kernel.sem",
+];
+/// Sysctls isolated by the uts namespace.
+const UTS_SYSCTLS: [&str; 2] = ["kernel.hostname", "kernel.domainname"];
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum NamespaceMode {
+    Pod,
+    Container,
+    Node,
+    Target,
+}
+
+impl Default for NamespaceMode {
+    fn default() -> Self {
+        Self::Pod
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Sctp,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileType {
+    RuntimeDefault,
+    Unconfined,
+    Localhost,
+}
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct PodSandboxConfig {
     pub metadata: PodSandboxMetadata,
     pub hostname: String,
     pub log_directory: String,
-    pub dns_config: DNSConfig,
+    pub dns_config: Option<DNSConfig>,
     pub port_mappings: Vec<PortMapping>,
     pub labels: HashMap<String, String>,
     pub annotations: HashMap<String, String>,
@@ -32,7 +100,7 @@ pub struct DNSConfig {
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct PortMapping {
-    pub protocol: i32,
+    pub protocol: Protocol,
     pub container_port: i32,
     pub host_port: i32,
     pub host_ip: String,
@@ -43,35 +111,38 @@ pub struct LinuxPodSandboxConfig {
     pub cgroup_parent: String,
     pub security_context: Option<LinuxSecurityContext>,
     pub sysctls: HashMap<String, String>,
+    /// Resources consumed by the sandbox itself, e.g. the VMM, besides the containers.
     pub overhead: Option<LinuxContainerResources>,
+    /// Sum of the resources of all the containers in the pod.
     pub resources: Option<LinuxContainerResources>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct LinuxSecurityContext {
-    namespace_options: Option<NamespaceOption>,
-    selinux_options: Option<SELinuxOption>,
-    run_as_user: Option<Int64Value>,
-    run_as_group: Option<Int64Value>,
-    readonly_rootfs: bool,
-    supplemental_groups: Vec<i32>,
-    privileged: bool,
-    seccomp: Option<SecurityProfile>,
-    apparmor: Option<SecurityProfile>,
-    seccomp_profile_path: String,
+    pub namespace_options: NamespaceOption,
+    pub selinux_options: Option<SELinuxOption>,
+    pub run_as_user: Option<i64>,
+    pub run_as_group: Option<i64>,
+    pub readonly_rootfs: bool,
+    pub supplemental_groups: Vec<i64>,
+    pub privileged: bool,
+    pub seccomp: Option<SecurityProfile>,
+    pub apparmor: Option<SecurityProfile>,
+    pub seccomp_profile_path: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct NamespaceOption {
-    pub network: i32,
-    pub pid: i32,
-    pub target_id: i32,
+    pub network: NamespaceMode,
+    pub pid: NamespaceMode,
+    pub ipc: NamespaceMode,
+    pub target_id: String,
     pub userns_options: Option<UserNamespace>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct UserNamespace {
-    pub mode: i32,
+    pub mode: NamespaceMode,
     pub uids: Vec<IDMapping>,
     pub gids: Vec<IDMapping>,
 }
@@ -91,14 +162,9 @@ pub struct SELinuxOption {
     pub level: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct Int64Value {
-    pub value: i64,
-}
-
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct SecurityProfile {
-    pub profile_type: i32,
+    pub profile_type: ProfileType,
     pub localhost_ref: String,
 }
 
@@ -137,5 +203,667 @@ pub struct WindowsSandboxSecurityContext {
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct WindowsNamespaceOption {
-    pub network: i32,
+    pub network: NamespaceMode,
+}
+
+impl PodSandboxConfig {
+    pub fn security_context(&self) -> Option<&LinuxSecurityContext> {
+        self.linux
+            .as_ref()
+            .and_then(|l| l.security_context.as_ref())
+    }
+
+    pub fn namespace_options(&self) -> NamespaceOption {
+        self.security_context()
+            .map(|s| s.namespace_options.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn host_network(&self) -> bool {
+        self.namespace_options().network == NamespaceMode::Node
+    }
+
+    pub fn host_pid(&self) -> bool {
+        self.namespace_options().pid == NamespaceMode::Node
+    }
+
+    pub fn host_ipc(&self) -> bool {
+        self.namespace_options().ipc == NamespaceMode::Node
+    }
+
+    /// The user namespace of the pod, if the pod does not run in the user namespace of the node.
+    pub fn userns(&self) -> Option<UserNamespace> {
+        self.namespace_options()
+            .userns_options
+            .filter(|u| u.mode == NamespaceMode::Pod)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.metadata.name.is_empty() || self.metadata.uid.is_empty() {
+            return Err(Error::InvalidArgument(
+                "name and uid of pod sandbox metadata are required".to_string(),
+            ));
+        }
+        let ns = self.namespace_options();
+        // kubelet sets the CONTAINER pid mode for pods not sharing the process namespace, in
+        // which the sandbox gets a pid namespace of its own, the same as in the POD mode
+        let modes = [("network", ns.network), ("pid", ns.pid), ("ipc", ns.ipc)];
+        for (name, mode) in modes {
+            let allowed = match mode {
+                NamespaceMode::Target => false,
+                NamespaceMode::Container => name == "pid",
+                _ => true,
+            };
+            if !allowed {
+                return Err(Error::InvalidArgument(format!(
+                    "{:?} {} namespace mode is not allowed for pod sandbox",
+                    mode, name
+                )));
+            }
+        }
+        if let Some(u) = ns.userns_options.as_ref() {
+            match u.mode {
+                NamespaceMode::Node if !u.uids.is_empty() || !u.gids.is_empty() => {
+                    return Err(Error::InvalidArgument(
+                        "user namespace mappings are not allowed in node mode".to_string(),
+                    ))
+                }
+                NamespaceMode::Node => {}
+                NamespaceMode::Pod => {
+                    if u.uids.is_empty() || u.gids.is_empty() {
+                        return Err(Error::InvalidArgument(
+                            "both uid and gid mappings are required for pod user namespace"
+                                .to_string(),
+                        ));
+                    }
+                    if u.uids.iter().chain(u.gids.iter()).any(|m| m.length == 0) {
+                        return Err(Error::InvalidArgument(
+                            "user namespace mapping of zero length".to_string(),
+                        ));
+                    }
+                }
+                mode => {
+                    return Err(Error::InvalidArgument(format!(
+                        "{:?} user namespace mode is not supported",
+                        mode
+                    )))
+                }
+            }
+        }
+        if let Some(linux) = &self.linux {
+            for key in linux.sysctls.keys() {
+                validate_sysctl(key, &ns)?;
+            }
+            for r in linux.overhead.iter().chain(linux.resources.iter()) {
+                if r.cpu_period < 0
+                    || r.cpu_shares < 0
+                    || r.memory_limit_in_bytes < 0
+                    || r.memory_swap_limit_in_bytes < 0
+                {
+                    return Err(Error::InvalidArgument(format!(
+                        "negative pod resources {:?}",
+                        r
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Build the OCI spec of the sandbox with the given id and network namespace, like the
+    /// sandbox container spec built by the CRI plugin of containerd. Seccomp profiles are
+    /// left to the runtime as there is no default one to generate here.
+    pub fn sandbox_spec(&self, id: &str, netns: &str) -> Result<JsonSpec> {
+        let ns = self.namespace_options();
+        let ctx = self.security_context().cloned().unwrap_or_default();
+        let linux_config = self.linux.as_ref();
+
+        let mut annotations = HashMap::new();
+        annotations.insert(
+            CRI_CONTAINERD_CONTAINER_TYPE_KEY.to_string(),
+            CONTAINER_TYPE_SANDBOX.to_string(),
+        );
+        annotations.insert(CRI_CONTAINERD_SANDBOX_ID_KEY.to_string(), id.to_string());
+        annotations.insert(SANDBOX_NAME_KEY.to_string(), self.metadata.name.clone());
+        annotations.insert(
+            SANDBOX_NAMESPACE_KEY.to_string(),
+            self.metadata.namespace.clone(),
+        );
+        annotations.insert(SANDBOX_UID_KEY.to_string(), self.metadata.uid.clone());
+        annotations.insert(SANDBOX_LOG_DIR_KEY.to_string(), self.log_directory.clone());
+        // let a VM sandbox size itself to the pod
+        if let Some(r) = linux_config.and_then(|l| l.resources.as_ref()) {
+            for (k, v) in [
+                (SANDBOX_CPU_PERIOD_KEY, r.cpu_period),
+                (SANDBOX_CPU_QUOTA_KEY, r.cpu_quota),
+                (SANDBOX_CPU_SHARES_KEY, r.cpu_shares),
+                (SANDBOX_MEMORY_KEY, r.memory_limit_in_bytes),
+            ] {
+                annotations.insert(k.to_string(), v.to_string());
+            }
+        }
+
+        let mut process = Process::new();
+        process.cwd = "/".to_string();
+        process.env = vec![DEFAULT_PATH_ENV.to_string()];
+        process.no_new_privileges = true;
+        process.oom_score_adj = Some(SANDBOX_OOM_SCORE_ADJ);
+        process.user.uid = ctx.run_as_user.unwrap_or_default() as u32;
+        process.user.gid = ctx.run_as_group.unwrap_or_default() as u32;
+        process.user.additional_gids = ctx.supplemental_groups.iter().map(|g| *g as u32).collect();
+        let selinux_label = ctx
+            .selinux_options
+            .as_ref()
+            .map(|o| format!("{}:{}:{}:{}", o.user, o.role, o.r#type, o.level))
+            .unwrap_or_default();
+        process.selinux_label = selinux_label.clone();
+        if !ctx.privileged {
+            process.apparmor_profile = match &ctx.apparmor {
+                Some(p) if p.profile_type == ProfileType::Unconfined => String::new(),
+                Some(p) if p.profile_type == ProfileType::Localhost => p.localhost_ref.clone(),
+                _ => DEFAULT_APPARMOR_PROFILE.to_string(),
+            };
+        }
+
+        let mut namespaces = vec![namespace("mount", "")];
+        if !self.host_network() {
+            namespaces.push(namespace("network", netns));
+            namespaces.push(namespace("uts", ""));
+        }
+        if ns.pid != NamespaceMode::Node {
+            namespaces.push(namespace("pid", ""));
+        }
+        if ns.ipc != NamespaceMode::Node {
+            namespaces.push(namespace("ipc", ""));
+        }
+        let mut linux = Linux {
+            sysctl: linux_config.map(|l| l.sysctls.clone()).unwrap_or_default(),
+            resources: Some(LinuxResources {
+                cpu: Some(LinuxCPU {
+                    shares: Some(SANDBOX_CPU_SHARES),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            cgroups_path: linux_config
+                .map(|l| cgroups_path(&l.cgroup_parent, id))
+                .unwrap_or_default(),
+            mount_label: selinux_label,
+            ..Default::default()
+        };
+        if let Some(u) = self.userns() {
+            namespaces.push(namespace("user", ""));
+            linux.uid_mappings = u.uids.iter().map(LinuxIDMapping::from).collect();
+            linux.gid_mappings = u.gids.iter().map(LinuxIDMapping::from).collect();
+        }
+        linux.namespaces = namespaces;
+
+        Ok(JsonSpec {
+            version: "1.0.2".to_string(),
+            process: Some(process),
+            root: Some(Root {
+                path: "rootfs".to_string(),
+                readonly: ctx.readonly_rootfs,
+            }),
+            hostname: self.hostname.clone(),
+            mounts: default_mounts(self.host_ipc()),
+            annotations,
+            linux: Some(linux),
+            ..Default::default()
+        })
+    }
+}
+
+impl TryFrom<&cri::PodSandboxConfig> for PodSandboxConfig {
+    type Error = Error;
+
+    fn try_from(c: &cri::PodSandboxConfig) -> Result<Self> {
+        let metadata = c.metadata.as_ref().ok_or_else(|| {
+            Error::InvalidArgument("pod sandbox metadata is required".to_string())
+        })?;
+        let config = Self {
+            metadata: PodSandboxMetadata {
+                name: metadata.name.clone(),
+                uid: metadata.uid.clone(),
+                namespace: metadata.namespace.clone(),
+                attempt: metadata.attempt,
+            },
+            hostname: c.hostname.clone(),
+            log_directory: c.log_directory.clone(),
+            dns_config: c.dns_config.as_ref().map(|d| DNSConfig {
+                servers: d.servers.clone(),
+                searches: d.searches.clone(),
+                options: d.options.clone(),
+            }),
+            port_mappings: c
+                .port_mappings
+                .iter()
+                .map(|p| {
+                    Ok(PortMapping {
+                        protocol: protocol(p.protocol)?,
+                        container_port: p.container_port,
+                        host_port: p.host_port,
+                        host_ip: p.host_ip.clone(),
+                    })
+                })
+                .collect::<Result<_>>()?,
+            labels: c.labels.clone(),
+            annotations: c.annotations.clone(),
+            linux: c.linux.as_ref().map(linux_config).transpose()?,
+            windows: c
+                .windows
+                .as_ref()
+                .map(|w| -> Result<_> {
+                    Ok(WindowsPodSandboxConfig {
+                        security_context: w
+                            .security_context
+                            .as_ref()
+                            .map(|s| -> Result<_> {
+                                Ok(WindowsSandboxSecurityContext {
+                                    run_as_username: s.run_as_username.clone(),
+                                    credential_spec: s.credential_spec.clone(),
+                                    host_process: s.host_process,
+                                    namespace_options: s
+                                        .namespace_options
+                                        .as_ref()
+                                        .map(|n| -> Result<_> {
+                                            Ok(WindowsNamespaceOption {
+                                                network: namespace_mode(n.network)?,
+                                            })
+                                        })
+                                        .transpose()?,
+                                })
+                            })
+                            .transpose()?,
+                    })
+                })
+                .transpose()?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn linux_config(l: &cri::LinuxPodSandboxConfig) -> Result<LinuxPodSandboxConfig> {
+    Ok(LinuxPodSandboxConfig {
+        cgroup_parent: l.cgroup_parent.clone(),
+        security_context: l
+            .security_context
+            .as_ref()
+            .map(security_context)
+            .transpose()?,
+        sysctls: l.sysctls.clone(),
+        overhead: l.overhead.as_ref().map(LinuxContainerResources::from),
+        resources: l.resources.as_ref().map(LinuxContainerResources::from),
+    })
+}
+
+fn security_context(s: &cri::LinuxSandboxSecurityContext) -> Result<LinuxSecurityContext> {
+    let namespace_options = match &s.namespace_options {
+        Some(n) => NamespaceOption {
+            network: namespace_mode(n.network)?,
+            pid: namespace_mode(n.pid)?,
+            ipc: namespace_mode(n.ipc)?,
+            target_id: n.target_id.clone(),
+            userns_options: n
+                .userns_options
+                .as_ref()
+                .map(|u| -> Result<_> {
+                    Ok(UserNamespace {
+                        mode: namespace_mode(u.mode)?,
+                        uids: u.uids.iter().map(IDMapping::from).collect(),
+                        gids: u.gids.iter().map(IDMapping::from).collect(),
+                    })
+                })
+                .transpose()?,
+        },
+        None => Default::default(),
+    };
+    Ok(LinuxSecurityContext {
+        namespace_options,
+        selinux_options: s.selinux_options.as_ref().map(|o| SELinuxOption {
+            user: o.user.clone(),
+            role: o.role.clone(),
+            r#type: o.r#type.clone(),
+            level: o.level.clone(),
+        }),
+        run_as_user: s.run_as_user.as_ref().map(|v| v.value),
+        run_as_group: s.run_as_group.as_ref().map(|v| v.value),
+        readonly_rootfs: s.readonly_rootfs,
+        supplemental_groups: s.supplemental_groups.clone(),
+        privileged: s.privileged,
+        seccomp: s.seccomp.as_ref().map(security_profile).transpose()?,
+        apparmor: s.apparmor.as_ref().map(security_profile).transpose()?,
+        #[allow(deprecated)]
+        seccomp_profile_path: s.seccomp_profile_path.clone(),
+    })
+}
+
+fn security_profile(p: &cri::SecurityProfile) -> Result<SecurityProfile> {
+    use cri::security_profile::ProfileType as P;
+    let profile_type = match P::from_i32(p.profile_type) {
+        Some(P::RuntimeDefault) => ProfileType::RuntimeDefault,
+        Some(P::Unconfined) => ProfileType::Unconfined,
+        Some(P::Localhost) => ProfileType::Localhost,
+        None => {
+            return Err(Error::InvalidArgument(format!(
+                "unknown security profile type {}",
+                p.profile_type
+            )))
+        }
+    };
+    if profile_type == ProfileType::Localhost && p.localhost_ref.is_empty() {
+        return Err(Error::InvalidArgument(
+            "localhost_ref is required for localhost security profile".to_string(),
+        ));
+    }
+    Ok(SecurityProfile {
+        profile_type,
+        localhost_ref: p.localhost_ref.clone(),
+    })
+}
+
+fn namespace_mode(mode: i32) -> Result<NamespaceMode> {
+    match cri::NamespaceMode::from_i32(mode) {
+        Some(cri::NamespaceMode::Pod) => Ok(NamespaceMode::Pod),
+        Some(cri::NamespaceMode::Container) => Ok(NamespaceMode::Container),
+        Some(cri::NamespaceMode::Node) => Ok(NamespaceMode::Node),
+        Some(cri::NamespaceMode::Target) => Ok(NamespaceMode::Target),
+        None => Err(Error::InvalidArgument(format!(
+            "unknown namespace mode {}",
+            mode
+        ))),
+    }
+}
+
+fn protocol(p: i32) -> Result<Protocol> {
+    match cri::Protocol::from_i32(p) {
+        Some(cri::Protocol::Tcp) => Ok(Protocol::Tcp),
+        Some(cri::Protocol::Udp) => Ok(Protocol::Udp),
+        Some(cri::Protocol::Sctp) => Ok(Protocol::Sctp),
+        None => Err(Error::InvalidArgument(format!("unknown protocol {}", p))),
+    }
+}
+
+/// Only namespaced sysctls are allowed, and not if the namespace is shared with the node.
+fn validate_sysctl(key: &str, ns: &NamespaceOption) -> Result<()> {
+    let (namespace, mode) = if IPC_SYSCTLS.contains(&key) || key.starts_with("fs.mqueue.") {
+        ("ipc", ns.ipc)
+    } else if key.starts_with("net.") {
+        ("network", ns.network)
+    } else if UTS_SYSCTLS.contains(&key) {
+        // the uts namespace is shared with the node along with the network namespace
+        ("uts", ns.network)
+    } else {
+        return Err(Error::InvalidArgument(format!(
+            "sysctl {} is not namespaced",
+            key
+        )));
+    };
+    if mode == NamespaceMode::Node {
+        return Err(Error::InvalidArgument(format!(
+            "sysctl {} is not allowed with the {} namespace of the node",
+            key, namespace
+        )));
+    }
+    Ok(())
+}
+
+fn cgroups_path(cgroup_parent: &str, id: &str) -> String {
+    if cgroup_parent.is_empty() {
+        return String::new();
+    }
+    // the systemd cgroup driver takes "slice:prefix:name"
+    if cgroup_parent.ends_with(".slice") {
+        return format!("{}:cri-containerd:{}", cgroup_parent, id);
+    }
+    format!("{}/{}", cgroup_parent.trim_end_matches('/'), id)
+}
+
+fn namespace(r#type: &str, path: &str) -> LinuxNamespace {
+    LinuxNamespace {
+        r#type: r#type.to_string(),
+        path: path.to_string(),
+    }
+}
+
+fn mount(destination: &str, r#type: &str, source: &str, options: &[&str]) -> Mount {
+    Mount {
+        destination: destination.to_string(),
+        r#type: r#type.to_string(),
+        source: source.to_string(),
+        options: options.iter().map(|o| o.to_string()).collect(),
+        ..Default::default()
+    }
+}
+
+/// Default mounts of containerd, with `/dev/shm` of the node if the ipc namespace is shared.
+fn default_mounts(host_ipc: bool) -> Vec<Mount> {
+    let shm = if host_ipc {
+        mount("/dev/shm", "bind", "/dev/shm", &["rbind", "ro"])
+    } else {
+        mount(
+            "/dev/shm",
+            "tmpfs",
+            "shm",
+            &["nosuid", "noexec", "nodev", "mode=1777", "size=65536k"],
+        )
+    };
+    vec![
+        mount("/proc", "proc", "proc", &["nosuid", "noexec", "nodev"]),
+        mount(
+            "/dev",
+            "tmpfs",
+            "tmpfs",
+            &["nosuid", "strictatime", "mode=755", "size=65536k"],
+        ),
+        mount(
+            "/dev/pts",
+            "devpts",
+            "devpts",
+            &[
+                "nosuid",
+                "noexec",
+                "newinstance",
+                "ptmxmode=0666",
+                "mode=0620",
+                "gid=5",
+            ],
+        ),
+        shm,
+        mount(
+            "/dev/mqueue",
+            "mqueue",
+            "mqueue",
+            &["nosuid", "noexec", "nodev"],
+        ),
+        mount(
+            "/sys",
+            "sysfs",
+            "sysfs",
+            &["nosuid", "noexec", "nodev", "ro"],
+        ),
+        mount(
+            "/run",
+            "tmpfs",
+            "tmpfs",
+            &["nosuid", "strictatime", "mode=755", "size=65536k"],
+        ),
+    ]
+}
+
+impl From<&cri::IdMapping> for IDMapping {
+    fn from(m: &cri::IdMapping) -> Self {
+        Self {
+            host_id: m.host_id,
+            container_id: m.container_id,
+            length: m.length,
+        }
+    }
+}
+
+impl From<&IDMapping> for LinuxIDMapping {
+    fn from(m: &IDMapping) -> Self {
+        Self {
+            container_id: m.container_id,
+            host_id: m.host_id,
+            size: m.length,
+        }
+    }
+}
+
+impl From<&cri::LinuxContainerResources> for LinuxContainerResources {
+    fn from(r: &cri::LinuxContainerResources) -> Self {
+        Self {
+            cpu_period: r.cpu_period,
+            cpu_quota: r.cpu_quota,
+            cpu_shares: r.cpu_shares,
+            memory_limit_in_bytes: r.memory_limit_in_bytes,
+            oom_score_adj: r.oom_score_adj,
+            cpuset_cpus: r.cpuset_cpus.clone(),
+            cpuset_mems: r.cpuset_mems.clone(),
+            hugepage_limits: r
+                .hugepage_limits
+                .iter()
+                .map(|h| HugepageLimit {
+                    page_size: h.page_size.clone(),
+                    limit: h.limit,
+                })
+                .collect(),
+            unified: r.unified.clone(),
+            memory_swap_limit_in_bytes: r.memory_swap_limit_in_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cri_config() -> cri::PodSandboxConfig {
+        cri::PodSandboxConfig {
+            metadata: Some(cri::PodSandboxMetadata {
+                name: "pod".to_string(),
+                uid: "uid".to_string(),
+                namespace: "default".to_string(),
+                attempt: 0,
+            }),
+            hostname: "pod".to_string(),
+            linux: Some(cri::LinuxPodSandboxConfig {
+                cgroup_parent: "/kubepods/pod-uid".to_string(),
+                security_context: Some(cri::LinuxSandboxSecurityContext {
+                    namespace_options: Some(cri::NamespaceOption {
+                        userns_options: Some(cri::UserNamespace {
+                            mode: cri::NamespaceMode::Pod as i32,
+                            uids: vec![cri::IdMapping {
+                                host_id: 65536,
+                                container_id: 0,
+                                length: 65536,
+                            }],
+                            gids: vec![cri::IdMapping {
+                                host_id: 65536,
+                                container_id: 0,
+                                length: 65536,
+                            }],
+                        }),
+                        ..Default::default()
+                    }),
+                    run_as_user: Some(cri::Int64Value { value: 1000 }),
+                    ..Default::default()
+                }),
+                sysctls: [("net.ipv4.ip_forward".to_string(), "1".to_string())]
+                    .iter()
+                    .cloned()
+                    .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn with_namespaces(f: impl FnOnce(&mut cri::NamespaceOption)) -> cri::PodSandboxConfig {
+        let mut c = cri_config();
+        let linux = c.linux.as_mut().unwrap();
+        let ctx = linux.security_context.as_mut().unwrap();
+        f(ctx.namespace_options.as_mut().unwrap());
+        c
+    }
+
+    #[test]
+    fn test_validate() {
+        let c = cri_config();
+        let config = PodSandboxConfig::try_from(&c).unwrap();
+        assert_eq!(config.security_context().unwrap().run_as_user, Some(1000));
+        assert!(config.userns().is_some());
+        assert!(!config.host_network());
+
+        let mut c = cri_config();
+        c.metadata = None;
+        assert!(PodSandboxConfig::try_from(&c).is_err());
+
+        let mut c = cri_config();
+        let linux = c.linux.as_mut().unwrap();
+        linux
+            .sysctls
+            .insert("vm.overcommit_memory".to_string(), "1".to_string());
+        assert!(PodSandboxConfig::try_from(&c).is_err());
+
+        let mut c = cri_config();
+        let linux = c.linux.as_mut().unwrap();
+        let ctx = linux.security_context.as_mut().unwrap();
+        ctx.namespace_options.as_mut().unwrap().network = cri::NamespaceMode::Node as i32;
+        // net sysctls are not allowed in the network namespace of the node
+        assert!(PodSandboxConfig::try_from(&c).is_err());
+
+        let mut c = cri_config();
+        let linux = c.linux.as_mut().unwrap();
+        let ctx = linux.security_context.as_mut().unwrap();
+        let ns = ctx.namespace_options.as_mut().unwrap();
+        ns.userns_options.as_mut().unwrap().uids.clear();
+        assert!(PodSandboxConfig::try_from(&c).is_err());
+
+        let c = with_namespaces(|ns| ns.ipc = cri::NamespaceMode::Container as i32);
+        assert!(PodSandboxConfig::try_from(&c).is_err());
+        let c = with_namespaces(|ns| ns.pid = cri::NamespaceMode::Target as i32);
+        assert!(PodSandboxConfig::try_from(&c).is_err());
+    }
+
+    #[test]
+    fn test_sandbox_spec() {
+        let config = PodSandboxConfig::try_from(&cri_config()).unwrap();
+        let spec = config.sandbox_spec("sb1", "/var/run/netns/sb1").unwrap();
+        assert_eq!(
+            spec.annotations
+                .get(CRI_CONTAINERD_SANDBOX_ID_KEY)
+                .map(String::as_str),
+            Some("sb1")
+        );
+        assert_eq!(spec.process.as_ref().unwrap().user.uid, 1000);
+        let linux = spec.linux.unwrap();
+        assert_eq!(linux.cgroups_path, "/kubepods/pod-uid/sb1");
+        assert_eq!(linux.uid_mappings.len(), 1);
+        let netns = linux
+            .namespaces
+            .iter()
+            .find(|n| n.r#type == "network")
+            .unwrap();
+        assert_eq!(netns.path, "/var/run/netns/sb1");
+        assert!(linux.namespaces.iter().any(|n| n.r#type == "user"));
+        assert_eq!(
+            linux.sysctl.get("net.ipv4.ip_forward").map(String::as_str),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn test_sandbox_spec_container_pid() {
+        // the pid mode of pods not sharing the process namespace
+        let c = with_namespaces(|ns| ns.pid = cri::NamespaceMode::Container as i32);
+        let config = PodSandboxConfig::try_from(&c).unwrap();
+        assert!(!config.host_pid());
+        let spec = config.sandbox_spec("sb1", "/var/run/netns/sb1").unwrap();
+        let linux = spec.linux.unwrap();
+        let pidns = linux.namespaces.iter().find(|n| n.r#type == "pid").unwrap();
+        assert!(pidns.path.is_empty());
+    }
 }
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::time::SystemTime;
 
 use log::warn;
@@ -7,6 +8,7 @@ use serde::Deserialize;
 use serde::Serialize;
 use tonic::Status;
 
+use crate::error::Error;
 use crate::spec::{JsonSpec, Mount, Process};
 use crate::PodSandboxConfig;
 
@@ -22,6 +24,40 @@ pub struct SandboxData {
     pub started_at: Option<SystemTime>,
     pub exited_at: Option<SystemTime>,
     pub extensions: HashMap<String, Any>,
+    #[serde(default)]
+    pub rootfs: Vec<Mount>,
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+    #[serde(default)]
+    pub sandboxer: String,
+    #[serde(default)]
+    pub api_version: ApiVersion,
+}
+
+/// Generation of the sandbox API of the containerd which created the sandbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ApiVersion {
+    /// containerd 1.7, or a containerd not identified as 2.x.
+    V1_7,
+    /// containerd 2.x, which connects to the task API at `address` of the sandbox.
+    V2,
+}
+
+impl Default for ApiVersion {
+    fn default() -> Self {
+        Self::V1_7
+    }
+}
+
+impl ApiVersion {
+    /// Only containerd 2.x sets the `sandbox` and `sandboxer` of create requests.
+    pub fn of(req: &crate::api::sandbox::v1::ControllerCreateRequest) -> Self {
+        if req.sandbox.is_some() || !req.sandboxer.is_empty() {
+            Self::V2
+        } else {
+            Self::V1_7
+        }
+    }
 }
 
 impl SandboxData {
@@ -50,28 +86,55 @@ impl SandboxData {
             //     }
             // }
         });
-        let extensions = if let Some(sb) = &req.sandbox {
-            sb.extensions
+        let (extensions, labels, spec, sandboxer) = if let Some(sb) = &req.sandbox {
+            let extensions = sb
+                .extensions
                 .iter()
                 .map(|(k, v)| (k.clone(), Any::from(v)))
-                .collect()
+                .collect();
+            let spec = decode_spec(sb).unwrap_or_else(|e| {
+                warn!("failed to parse spec of sandbox {}: {}", req.sandbox_id, e);
+                None
+            });
+            (
+                extensions,
+                sb.labels.clone(),
+                spec,
+                sb.sandboxer.to_string(),
+            )
         } else {
             Default::default()
         };
         Self {
             id: req.sandbox_id.to_string(),
-            spec: None,
+            spec,
             config,
             task_address: "".to_string(),
-            labels: Default::default(),
+            labels,
             created_at: Some(SystemTime::now()),
             netns: req.netns_path.to_string(),
             started_at: None,
             exited_at: None,
             extensions: extensions,
+            rootfs: req.rootfs.iter().map(Mount::from).collect(),
+            annotations: req.annotations.clone(),
+            sandboxer: if req.sandboxer.is_empty() {
+                sandboxer
+            } else {
+                req.sandboxer.to_string()
+            },
+            api_version: ApiVersion::of(req),
         }
     }
 
+    /// The validated CRI config of the pod, `None` if the sandbox is not created by CRI.
+    pub fn pod_config(&self) -> crate::error::Result<Option<crate::config::PodSandboxConfig>> {
+        self.config
+            .as_ref()
+            .map(crate::config::PodSandboxConfig::try_from)
+            .transpose()
+    }
+
     pub fn task_resources(&self) -> Result<TaskResources, Status> {
         let mut tasks = TaskResources { tasks: vec![] };
         if let Some(a) = self.extensions.get("tasks") {
@@ -81,6 +144,71 @@ impl SandboxData {
         }
         Ok(tasks)
     }
+
+    /// Apply the field paths of an update request, such as `labels`, `labels.<key>`,
+    /// `extensions`, `extensions.<key>`, `spec` and `resources`, from `sandbox` to the data.
+    ///
+    /// A key path absent in `sandbox` removes the key, `resources` replaces the linux
+    /// resources of the spec only.
+    pub fn update_fields(
+        &mut self,
+        sandbox: &crate::types::Sandbox,
+        fields: &[String],
+    ) -> crate::error::Result<()> {
+        for field in fields {
+            match field.split_once('.') {
+                None if field == "labels" => self.labels = sandbox.labels.clone(),
+                None if field == "extensions" => {
+                    self.extensions = sandbox
+                        .extensions
+                        .iter()
+                        .map(|(k, v)| (k.clone(), Any::from(v)))
+                        .collect()
+                }
+                None if field == "spec" => self.spec = decode_spec(sandbox)?,
+                None if field == "resources" => {
+                    let linux = decode_spec(sandbox)?.and_then(|s| s.linux);
+                    let spec = self.spec.get_or_insert_with(Default::default);
+                    match spec.linux.as_mut() {
+                        Some(l) => l.resources = linux.and_then(|l| l.resources),
+                        None => spec.linux = linux,
+                    }
+                }
+                Some(("labels", key)) if !key.is_empty() => match sandbox.labels.get(key) {
+                    Some(v) => {
+                        self.labels.insert(key.to_string(), v.clone());
+                    }
+                    None => {
+                        self.labels.remove(key);
+                    }
+                },
+                Some(("extensions", key)) if !key.is_empty() => match sandbox.extensions.get(key) {
+                    Some(v) => {
+                        self.extensions.insert(key.to_string(), Any::from(v));
+                    }
+                    None => {
+                        self.extensions.remove(key);
+                    }
+                },
+                _ => {
+                    return Err(Error::InvalidArgument(format!(
+                        "unsupported field path {:?} to update sandbox {}",
+                        field, self.id
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn decode_spec(sandbox: &crate::types::Sandbox) -> crate::error::Result<Option<JsonSpec>> {
+    match &sandbox.spec {
+        Some(any) if !any.value.is_empty() => serde_json::from_slice(&any.value)
+            .map(Some)
+            .map_err(|e| Error::InvalidArgument(format!("failed to unmarshal spec: {}", e))),
+        _ => Ok(None),
+    }
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -193,6 +321,130 @@ pub struct ProcessResource {
     pub stderr: String,
 }
 
+impl TaskResources {
+    /// Diff the tasks against `old` by task id, and the processes of the tasks by exec id.
+    pub fn diff<'a>(&'a self, old: &'a TaskResources) -> TaskResourcesDiff<'a> {
+        let olds = old
+            .tasks
+            .iter()
+            .map(|t| (t.task_id.as_str(), t))
+            .collect::<HashMap<_, _>>();
+        let news = self
+            .tasks
+            .iter()
+            .map(|t| (t.task_id.as_str(), t))
+            .collect::<HashMap<_, _>>();
+        let mut diff = TaskResourcesDiff::default();
+        for t in self.tasks.iter() {
+            match olds.get(t.task_id.as_str()) {
+                None => diff.added.push(t),
+                Some(&ot) => {
+                    let processes = diff_processes(&t.processes, &ot.processes);
+                    let changed = t.stdin != ot.stdin
+                        || t.stdout != ot.stdout
+                        || t.stderr != ot.stderr
+                        || !json_eq(&t.spec, &ot.spec)
+                        || !json_eq(&t.rootfs, &ot.rootfs);
+                    if changed || !processes.is_empty() {
+                        diff.modified.push(TaskDiff {
+                            old: ot,
+                            new: t,
+                            changed,
+                            processes,
+                        });
+                    }
+                }
+            }
+        }
+        diff.removed = old
+            .tasks
+            .iter()
+            .filter(|ot| !news.contains_key(ot.task_id.as_str()))
+            .collect();
+        diff
+    }
+}
+
+/// Tasks added, removed and modified in an update of [`TaskResources`].
+#[derive(Debug, Default)]
+pub struct TaskResourcesDiff<'a> {
+    pub added: Vec<&'a TaskResource>,
+    pub removed: Vec<&'a TaskResource>,
+    pub modified: Vec<TaskDiff<'a>>,
+}
+
+impl TaskResourcesDiff<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// A task present in both old and new [`TaskResources`] but modified.
+#[derive(Debug)]
+pub struct TaskDiff<'a> {
+    pub old: &'a TaskResource,
+    pub new: &'a TaskResource,
+    /// Whether the spec, rootfs or stdio of the task itself changed.
+    pub changed: bool,
+    pub processes: ProcessResourcesDiff<'a>,
+}
+
+/// Processes added, removed and modified in a task.
+#[derive(Debug, Default)]
+pub struct ProcessResourcesDiff<'a> {
+    pub added: Vec<&'a ProcessResource>,
+    pub removed: Vec<&'a ProcessResource>,
+    pub modified: Vec<&'a ProcessResource>,
+}
+
+impl ProcessResourcesDiff<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+fn diff_processes<'a>(
+    processes: &'a [ProcessResource],
+    old_processes: &'a [ProcessResource],
+) -> ProcessResourcesDiff<'a> {
+    let olds = old_processes
+        .iter()
+        .map(|p| (p.exec_id.as_str(), p))
+        .collect::<HashMap<_, _>>();
+    let news = processes
+        .iter()
+        .map(|p| (p.exec_id.as_str(), p))
+        .collect::<HashMap<_, _>>();
+    let mut diff = ProcessResourcesDiff::default();
+    for p in processes.iter() {
+        match olds.get(p.exec_id.as_str()) {
+            None => diff.added.push(p),
+            Some(op) => {
+                if p.stdin != op.stdin
+                    || p.stdout != op.stdout
+                    || p.stderr != op.stderr
+                    || !json_eq(&p.spec, &op.spec)
+                {
+                    diff.modified.push(p);
+                }
+            }
+        }
+    }
+    diff.removed = old_processes
+        .iter()
+        .filter(|op| !news.contains_key(op.exec_id.as_str()))
+        .collect();
+    diff
+}
+
+/// Compare by the json values, as the spec types do not implement `PartialEq`.
+fn json_eq<T: Serialize>(a: &T, b: &T) -> bool {
+    match (serde_json::to_value(a), serde_json::to_value(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
 impl ProcessData {
     pub fn new(req: &crate::data::ProcessResource) -> Self {
         Self {
@@ -242,3 +494,112 @@ impl From<Any> for prost_types::Any {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_task_resources_diff() {
+        let old: TaskResources = serde_json::from_str(
+            r#"{"tasks": [
+                {"task_id": "c1", "stdout": "/a", "processes": [{"exec_id": "e1"}, {"exec_id": "e2"}]},
+                {"task_id": "c2"},
+                {"task_id": "c3", "rootfs": [{"type": "overlay", "source": "overlay"}]}
+            ]}"#,
+        )
+        .unwrap();
+        let new: TaskResources = serde_json::from_str(
+            r#"{"tasks": [
+                {"task_id": "c1", "stdout": "/a", "processes": [{"exec_id": "e2", "stdout": "/b"}, {"exec_id": "e3"}]},
+                {"task_id": "c3", "rootfs": [{"type": "overlay", "source": "overlay"}]},
+                {"task_id": "c4"}
+            ]}"#,
+        )
+        .unwrap();
+
+        let diff = new.diff(&old);
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].task_id, "c4");
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0].task_id, "c2");
+        // c3 is not changed
+        assert_eq!(diff.modified.len(), 1);
+        let d = &diff.modified[0];
+        assert_eq!(d.new.task_id, "c1");
+        assert!(!d.changed);
+        assert_eq!(d.processes.added[0].exec_id, "e3");
+        assert_eq!(d.processes.removed[0].exec_id, "e1");
+        assert_eq!(d.processes.modified[0].exec_id, "e2");
+
+        assert!(new.diff(&new).is_empty());
+    }
+
+    #[test]
+    fn test_new_sandbox_data() {
+        let mut req = crate::api::sandbox::v1::ControllerCreateRequest {
+            sandbox_id: "sb1".to_string(),
+            rootfs: vec![crate::types::Mount {
+                r#type: "overlay".to_string(),
+                source: "overlay".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        req.annotations.insert("a".to_string(), "1".to_string());
+        let data = SandboxData::new(&req);
+        assert_eq!(data.api_version, ApiVersion::V1_7);
+        assert_eq!(data.rootfs[0].r#type, "overlay");
+        assert_eq!(data.annotations.get("a").map(String::as_str), Some("1"));
+
+        let mut sandbox = crate::types::Sandbox {
+            sandboxer: "example".to_string(),
+            ..Default::default()
+        };
+        sandbox.labels.insert("b".to_string(), "2".to_string());
+        req.sandbox = Some(sandbox);
+        let data = SandboxData::new(&req);
+        assert_eq!(data.api_version, ApiVersion::V2);
+        assert_eq!(data.sandboxer, "example");
+        assert_eq!(data.labels.get("b").map(String::as_str), Some("2"));
+    }
+
+    #[test]
+    fn test_update_fields() {
+        let mut data = SandboxData {
+            id: "sb1".to_string(),
+            labels: [("a", "1"), ("b", "2")]
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        let mut sandbox = crate::types::Sandbox {
+            sandbox_id: "sb1".to_string(),
+            ..Default::default()
+        };
+        sandbox.labels.insert("a".to_string(), "3".to_string());
+        sandbox.extensions.insert(
+            "foo".to_string(),
+            prost_types::Any {
+                type_url: "foo".to_string(),
+                value: b"bar".to_vec(),
+            },
+        );
+        let fields = ["labels.a", "labels.b", "extensions.foo"].map(String::from);
+        data.update_fields(&sandbox, &fields).unwrap();
+        assert_eq!(data.labels.get("a").map(String::as_str), Some("3"));
+        assert!(!data.labels.contains_key("b"));
+        assert_eq!(data.extensions.get("foo").unwrap().value, b"bar");
+
+        let fields = ["extensions".to_string()];
+        sandbox.extensions.clear();
+        data.update_fields(&sandbox, &fields).unwrap();
+        assert!(data.extensions.is_empty());
+
+        for field in ["runtime", "labels.", "spec.process"] {
+            let err = data.update_fields(&sandbox, &[field.to_string()]);
+            assert!(matches!(err, Err(Error::InvalidArgument(_))));
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Lifecycle events of sandboxes, emitted by sandbox implementations and streamed to clients
+//! by the `Events` service, see `src/protos/events.proto`.
+
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use futures::Stream;
+use log::{debug, warn};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tonic::{Request, Response, Status};
+
+use crate::api::events::v1::events_server::Events;
+use crate::api::events::v1::{self as proto, EventType, SubscribeRequest};
+use crate::auth::PeerAllowList;
+
+/// Number of events kept for slow subscribers, which miss the oldest events if exceeded.
+pub const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Created,
+    /// The sandbox is ready to run containers, with the pid of the sandbox.
+    Ready(u32),
+    Paused,
+    /// A container in the sandbox is killed by the OOM killer.
+    ContainerOom(String),
+    /// The agent in the guest of a VM sandbox is disconnected, with the reason.
+    AgentDisconnected(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct SandboxEvent {
+    pub sandbox_id: String,
+    pub timestamp: SystemTime,
+    pub event: Event,
+}
+
+impl From<SandboxEvent> for proto::SandboxEvent {
+    fn from(e: SandboxEvent) -> Self {
+        let mut event = Self {
+            sandbox_id: e.sandbox_id,
+            timestamp: Some(e.timestamp.into()),
+            ..Default::default()
+        };
+        match e.event {
+            Event::Created => event.set_type(EventType::Created),
+            Event::Ready(pid) => {
+                event.set_type(EventType::Ready);
+                event.pid = pid;
+            }
+            Event::Paused => event.set_type(EventType::Paused),
+            Event::ContainerOom(id) => {
+                event.set_type(EventType::ContainerOom);
+                event.container_id = id;
+            }
+            Event::AgentDisconnected(reason) => {
+                event.set_type(EventType::AgentDisconnected);
+                event.message = reason;
+            }
+        }
+        event
+    }
+}
+
+/// The sending side of the event channel, which is handed to sandboxes in
+/// [`crate::SandboxOption`]. Events published without any subscriber are dropped.
+#[derive(Clone, Debug)]
+pub struct EventPublisher {
+    tx: broadcast::Sender<SandboxEvent>,
+}
+
+impl Default for EventPublisher {
+    fn default() -> Self {
+        Self::new(EVENT_CHANNEL_CAPACITY)
+    }
+}
+
+impl EventPublisher {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self { tx }
+    }
+
+    pub fn publish(&self, sandbox_id: &str, event: Event) {
+        debug!("publish event {:?} of sandbox {}", event, sandbox_id);
+        let e = SandboxEvent {
+            sandbox_id: sandbox_id.to_string(),
+            timestamp: SystemTime::now(),
+            event,
+        };
+        // an error only means there is no subscriber
+        self.tx.send(e).unwrap_or_default();
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SandboxEvent> {
+        self.tx.subscribe()
+    }
+}
+
+/// The `Events` gRPC service streaming events from an [`EventPublisher`].
+pub struct EventService {
+    publisher: EventPublisher,
+    peers: PeerAllowList,
+}
+
+impl EventService {
+    pub fn new(publisher: EventPublisher) -> Self {
+        Self {
+            publisher,
+            peers: PeerAllowList::default(),
+        }
+    }
+
+    /// Only serve callers in the allow list, see [`PeerAllowList::authorize`].
+    pub fn with_allowed_peers(mut self, peers: PeerAllowList) -> Self {
+        self.peers = peers;
+        self
+    }
+}
+
+#[tonic::async_trait]
+impl Events for EventService {
+    type SubscribeStream =
+        Pin<Box<dyn Stream<Item = Result<proto::SandboxEvent, Status>> + Send + 'static>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        self.peers.authorize(&request, "subscribe")?;
+        let ids = request.into_inner().sandbox_ids;
+        let mut rx = self.publisher.subscribe();
+        let stream = async_stream::stream! {
+            loop {
+                match rx.recv().await {
+                    Ok(e) => {
+                        if ids.is_empty() || ids.contains(&e.sandbox_id) {
+                            yield Ok(e.into());
+                        }
+                    }
+                    Err(RecvError::Lagged(n)) => warn!("subscriber lagged, {} events missed", n),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        };
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish() {
+        let publisher = EventPublisher::new(4);
+        // no subscriber yet
+        publisher.publish("sb1", Event::Created);
+        let mut rx = publisher.subscribe();
+        publisher.publish("sb1", Event::Ready(100));
+        publisher.publish("sb1", Event::ContainerOom("c1".to_string()));
+
+        let e = rx.recv().await.unwrap();
+        assert_eq!(e.event, Event::Ready(100));
+        let e: proto::SandboxEvent = rx.recv().await.unwrap().into();
+        assert_eq!(e.r#type(), EventType::ContainerOom);
+        assert_eq!(e.container_id, "c1");
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_authorized() {
+        let publisher = EventPublisher::default();
+        let service = EventService::new(publisher.clone());
+        assert!(service
+            .subscribe(Request::new(SubscribeRequest::default()))
+            .await
+            .is_ok());
+
+        let peers = PeerAllowList {
+            uids: vec![0],
+            ..Default::default()
+        };
+        let service = EventService::new(publisher).with_allowed_peers(peers);
+        // a request without the credentials of the peer
+        let err = service
+            .subscribe(Request::new(SubscribeRequest::default()))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    }
+}
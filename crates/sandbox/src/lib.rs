@@ -1,32 +1,53 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::future::Future;
 use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use async_trait::async_trait;
-use futures::TryFutureExt;
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
-use tokio::net::UnixListener;
-use tokio::sync::Mutex;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{oneshot, Mutex};
 use tonic::transport::Server;
 
 pub use cri::api::v1::PodSandboxConfig;
 
+use crate::api::events::v1::events_server::EventsServer;
+use crate::api::extension::v1::sandbox_extension_server::SandboxExtensionServer;
 use crate::api::sandbox::v1::controller_server::ControllerServer;
+use crate::auth::PeerAllowList;
 use crate::data::{ContainerData, SandboxData};
-use crate::error::Result;
+use crate::error::{Error, Result};
+use crate::event::{EventPublisher, EventService};
+use crate::listener::{remove_socket, ListenAddr, Listener};
 use crate::rpc::SandboxController;
 use crate::signal::ExitSignal;
+use crate::store::{FileStore, SandboxStore};
 
 pub mod args;
+pub mod auth;
 pub mod base64;
+pub mod cgroup;
 pub mod config;
 pub mod data;
 pub mod error;
+pub mod event;
+pub mod listener;
+pub mod metrics;
+pub mod mountinfo;
+pub mod netns;
+pub mod platform;
 pub mod rpc;
 pub mod signal;
 pub mod spec;
+pub mod store;
+pub mod task;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 pub mod utils;
 
 /// Generated GRPC apis.
@@ -37,6 +58,20 @@ pub mod api {
             tonic::include_proto!("containerd.services.sandbox.v1");
         }
     }
+
+    /// Generated sandbox events bindings, see [`crate::event`].
+    pub mod events {
+        pub mod v1 {
+            tonic::include_proto!("sandbox.events.v1");
+        }
+    }
+
+    /// Generated bindings of the sandbox operations beyond the containerd controller API.
+    pub mod extension {
+        pub mod v1 {
+            tonic::include_proto!("sandbox.extension.v1");
+        }
+    }
 }
 
 pub mod cri {
@@ -51,15 +86,28 @@ pub mod types {
     tonic::include_proto!("containerd.types");
 }
 
+pub mod cgroups {
+    /// Generated bindings of cgroup v2 stats, see [`crate::cgroup::collect_metrics`].
+    pub mod v2 {
+        tonic::include_proto!("io.containerd.cgroups.v2");
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct SandboxOption {
     pub base_dir: String,
     pub sandbox: SandboxData,
+    /// Publisher of the lifecycle events of the sandbox.
+    pub events: EventPublisher,
 }
 
 impl SandboxOption {
-    fn new(base_dir: String, sandbox: SandboxData) -> Self {
-        Self { base_dir, sandbox }
+    fn new(base_dir: String, sandbox: SandboxData, events: EventPublisher) -> Self {
+        Self {
+            base_dir,
+            sandbox,
+            events,
+        }
     }
 }
 
@@ -107,7 +155,46 @@ pub trait Sandboxer {
     async fn update(&self, id: &str, s: SandboxData) -> Result<()>;
     async fn sandbox(&self, id: &str) -> Result<Arc<Mutex<Self::Sandbox>>>;
     async fn stop(&self, id: &str, force: bool) -> Result<()>;
+    /// Stop the sandbox gracefully, e.g. by shutting down the guest OS, before the deadline,
+    /// after which the controller escalates to a forced [`Sandboxer::stop`].
+    async fn stop_with_deadline(&self, id: &str, _deadline: Instant) -> Result<()>
+    where
+        Self: Sync,
+    {
+        self.stop(id, false).await
+    }
     async fn delete(&self, id: &str) -> Result<()>;
+    /// Platform of the sandbox, sandboxes running a guest OS should report the platform of it.
+    async fn platform(&self, _id: &str) -> Result<types::Platform> {
+        Ok(platform::host_platform())
+    }
+    /// Recover a sandbox from the state saved before the sandboxer restarted, called by [`run`]
+    /// for every sandbox in the [`SandboxStore`] before serving any request.
+    async fn recover(
+        &self,
+        id: &str,
+        _opt: SandboxOption,
+        _containers: Vec<ContainerData>,
+    ) -> Result<()> {
+        Err(Error::Unimplemented(format!("recover sandbox {}", id)))
+    }
+    /// Freeze all the processes of the sandbox, of which the status becomes
+    /// [`SandboxStatus::Paused`] until [`Sandboxer::resume`].
+    async fn pause(&self, id: &str) -> Result<()> {
+        Err(Error::Unimplemented(format!("pause sandbox {}", id)))
+    }
+    async fn resume(&self, id: &str) -> Result<()> {
+        Err(Error::Unimplemented(format!("resume sandbox {}", id)))
+    }
+    /// Save the state of the whole sandbox, e.g. the memory of the VM, to the directory `path`.
+    async fn checkpoint(&self, id: &str, _path: &Path) -> Result<()> {
+        Err(Error::Unimplemented(format!("checkpoint sandbox {}", id)))
+    }
+    /// Called once on shutdown of the sandboxer, after the in-flight requests are drained.
+    /// Sandboxes are left running, to be recovered by the next run.
+    async fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -121,50 +208,242 @@ pub trait Sandbox: Sync + Send {
     async fn remove_container(&mut self, id: &str) -> Result<()>;
     async fn exit_signal(&self) -> Result<Arc<ExitSignal>>;
     fn get_data(&self) -> Result<SandboxData>;
+    /// Pod level resource usage of the sandbox, usually collected from the cgroup of the sandbox,
+    /// see [`cgroup::collect_metrics`]. No metrics are returned to containerd if unimplemented.
+    async fn metrics(&self) -> Result<cgroups::v2::Metrics> {
+        Err(Error::Unimplemented("metrics".to_string()))
+    }
+    /// Runtime specific information of the sandbox, returned in the `info` map and the `extra`
+    /// field of the status response, more details are expected if `verbose` is set.
+    async fn status_info(
+        &self,
+        _verbose: bool,
+    ) -> Result<(HashMap<String, String>, Option<prost_types::Any>)> {
+        Ok((HashMap::new(), None))
+    }
+    /// Versions of the task API served at the `task_address` of the sandbox, the highest one
+    /// also supported by containerd is returned to it, see [`task::negotiate`].
+    fn task_api_versions(&self) -> Vec<u32> {
+        vec![rpc::TASK_API_VERSION]
+    }
 }
 
+/// Time to wait for the in-flight requests on shutdown if not set in [`RunOptions`].
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Options of [`run_with_options`].
+#[derive(Default)]
+pub struct RunOptions {
+    /// Store of the state of sandboxes, a [`FileStore`] in the working dir if not set.
+    pub store: Option<Box<dyn SandboxStore>>,
+    /// Processes allowed to call the controller and the events service, any local process if
+    /// empty.
+    pub allowed_peers: PeerAllowList,
+    /// Resolved to shut the sandboxer down, which is on SIGTERM or SIGINT if not set.
+    pub shutdown: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    /// Time to wait for the in-flight requests on shutdown, [`DEFAULT_DRAIN_TIMEOUT`] if zero.
+    /// Requests still running after it are dropped unanswered once [`Sandboxer::shutdown`]
+    /// returns, which may leave their sandboxes half created or stopped.
+    pub drain_timeout: Duration,
+    /// Unix socket to serve metrics on, such as the `--metrics` flag of [`args::Flags`], see
+    /// [`metrics::serve`]. Ignored unless the `metrics` feature is enabled.
+    pub metrics_address: Option<String>,
+}
+
+/// Serve the sandboxer on `listening_addr` until SIGTERM or SIGINT, see [`ListenAddr`] for
+/// the supported addresses.
 pub async fn run<S>(name: &str, listening_addr: &str, working_dir: &str, sandboxer: S) -> Result<()>
 where
     S: Sandboxer + Sync + Send + 'static,
 {
-    info!("start sandbox plugin: {}", name);
-    if Path::new(listening_addr).exists() {
-        tokio::fs::remove_file(listening_addr).await?;
-    }
+    run_with_options(
+        name,
+        listening_addr,
+        working_dir,
+        sandboxer,
+        RunOptions::default(),
+    )
+    .await
+}
+
+/// Same as [`run`], but the state of sandboxes is kept in the given store.
+pub async fn run_with_store<S>(
+    name: &str,
+    listening_addr: &str,
+    working_dir: &str,
+    sandboxer: S,
+    store: Box<dyn SandboxStore>,
+) -> Result<()>
+where
+    S: Sandboxer + Sync + Send + 'static,
+{
+    let opts = RunOptions {
+        store: Some(store),
+        ..Default::default()
+    };
+    run_with_options(name, listening_addr, working_dir, sandboxer, opts).await
+}
 
+/// Same as [`run`], with the given options.
+pub async fn run_with_options<S>(
+    name: &str,
+    listening_addr: &str,
+    working_dir: &str,
+    sandboxer: S,
+    opts: RunOptions,
+) -> Result<()>
+where
+    S: Sandboxer + Sync + Send + 'static,
+{
+    info!("start sandbox plugin: {}", name);
     if !Path::new(working_dir).exists() {
         tokio::fs::create_dir_all(working_dir).await?;
     }
 
-    let incoming = {
-        let uds = UnixListener::bind(listening_addr)?;
-        async_stream::stream! {
-            loop {
-                let item = uds.accept().map_ok(|(st, _)|unix::UnixStream(st)).await;
-                yield item;
+    let shutdown = match opts.shutdown {
+        Some(s) => s,
+        None => Box::pin(shutdown_signal()?),
+    };
+    let drain_timeout = if opts.drain_timeout.is_zero() {
+        DEFAULT_DRAIN_TIMEOUT
+    } else {
+        opts.drain_timeout
+    };
+
+    if let Some(address) = opts.metrics_address.filter(|a| !a.is_empty()) {
+        serve_metrics(address);
+    }
+
+    let listener = Listener::bind(listening_addr.parse()?).await?;
+    let addr = listener.addr().clone();
+    info!("sandbox plugin {} listening on {}", name, addr);
+    let incoming = listener.incoming();
+
+    let store = opts
+        .store
+        .unwrap_or_else(|| Box::new(FileStore::new(working_dir)));
+    let events = EventPublisher::default();
+    recover(working_dir, &sandboxer, store.as_ref(), &events).await?;
+
+    let sandbox_controller = Arc::new(
+        SandboxController::new(working_dir.to_string(), sandboxer)
+            .with_store(store)
+            .with_events(events.clone())
+            .with_allowed_peers(opts.allowed_peers.clone()),
+    );
+    let sandbox_server = ControllerServer::from_arc(sandbox_controller.clone());
+    let extension_server = SandboxExtensionServer::from_arc(sandbox_controller.clone());
+    let events_server =
+        EventsServer::new(EventService::new(events).with_allowed_peers(opts.allowed_peers));
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let server = Server::builder()
+        .add_service(sandbox_server)
+        .add_service(extension_server)
+        .add_service(events_server)
+        .serve_with_incoming_shutdown(incoming, async {
+            stop_rx.await.unwrap_or_default();
+        });
+    tokio::pin!(server);
+
+    tokio::select! {
+        res = &mut server => {
+            res.with_context(|| "gRPC server")?;
+            return Ok(());
+        }
+        _ = shutdown => {}
+    }
+
+    info!("shutdown sandbox plugin {}", name);
+    // stop accepting new connections, while the in-flight requests are served until drained
+    stop_tx.send(()).unwrap_or_default();
+    let close = sandbox_controller.close(drain_timeout);
+    tokio::pin!(close);
+    let res = tokio::select! {
+        res = &mut close => res,
+        res = &mut server => {
+            if let Err(e) = res {
+                warn!("gRPC server failed on shutdown: {}", e);
             }
+            close.await
         }
     };
+    if let ListenAddr::Unix(path) = &addr {
+        if let Err(e) = remove_socket(path).await {
+            warn!("failed to remove socket {}: {}", path.display(), e);
+        }
+    }
+    res
+}
 
-    let sandbox_controller = SandboxController::new(working_dir.to_string(), sandboxer);
-    let sandbox_server = ControllerServer::new(sandbox_controller);
-    Server::builder()
-        .add_service(sandbox_server)
-        .serve_with_incoming(incoming)
-        .await
-        .with_context(|| format!("gRPC server"))?;
+/// Resolved on the first SIGTERM or SIGINT.
+fn shutdown_signal() -> Result<impl Future<Output = ()>> {
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    Ok(async move {
+        tokio::select! {
+            _ = term.recv() => info!("received SIGTERM"),
+            _ = int.recv() => info!("received SIGINT"),
+        }
+    })
+}
+
+#[cfg(feature = "metrics")]
+fn serve_metrics(address: String) {
+    info!("serve metrics on {}", address);
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(&address).await {
+            warn!("failed to serve metrics on {}: {}", address, e);
+        }
+    });
+}
+
+#[cfg(not(feature = "metrics"))]
+fn serve_metrics(address: String) {
+    warn!(
+        "metrics are not served on {}, the metrics feature is not enabled",
+        address
+    );
+}
 
+async fn recover<S>(
+    working_dir: &str,
+    sandboxer: &S,
+    store: &dyn SandboxStore,
+    events: &EventPublisher,
+) -> Result<()>
+where
+    S: Sandboxer + Sync + Send + 'static,
+{
+    for state in store.load().await? {
+        let id = state.sandbox.id.clone();
+        let opt = SandboxOption::new(
+            format!("{}/{}", working_dir, id),
+            state.sandbox,
+            events.clone(),
+        );
+        match sandboxer.recover(&id, opt, state.containers).await {
+            Ok(()) => {
+                info!("sandbox {} recovered", id);
+                metrics::inc_sandboxes();
+            }
+            Err(e) => warn!("failed to recover sandbox {}: {}", id, e),
+        }
+    }
     Ok(())
 }
 
-mod unix {
+/// Connections of the gRPC server, with the credentials of the peer process.
+pub mod unix {
     use std::{
+        os::unix::io::AsRawFd,
         pin::Pin,
         sync::Arc,
         task::{Context, Poll},
     };
 
+    use nix::sys::socket::{getsockname, SockAddr};
     use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio::net::unix::UCred;
     use tonic::transport::server::Connected;
 
     #[derive(Debug)]
@@ -174,19 +453,35 @@ mod unix {
         type ConnectInfo = UdsConnectInfo;
 
         fn connect_info(&self) -> Self::ConnectInfo {
+            // vsock connections are served as unix streams, but have no peer process on the host
+            if !matches!(getsockname(self.0.as_raw_fd()), Ok(SockAddr::Unix(_))) {
+                return UdsConnectInfo {
+                    peer_addr: None,
+                    peer_cred: None,
+                };
+            }
             UdsConnectInfo {
-                _peer_addr: self.0.peer_addr().ok().map(Arc::new),
-                _peer_cred: self.0.peer_cred().ok(),
+                peer_addr: self.0.peer_addr().ok().map(Arc::new),
+                peer_cred: self.0.peer_cred().ok(),
             }
         }
     }
 
+    /// Connection info of a request, in the extensions of every request to the gRPC services.
     #[derive(Clone, Debug)]
     pub struct UdsConnectInfo {
-        pub _peer_addr: Option<Arc<tokio::net::unix::SocketAddr>>,
-        pub _peer_cred: Option<tokio::net::unix::UCred>,
+        pub peer_addr: Option<Arc<tokio::net::unix::SocketAddr>>,
+        pub peer_cred: Option<UCred>,
     }
 
+    /// Credentials of the process calling the request, `None` if the caller is not a process
+    /// on the host, for requests over vsock.
+    pub fn peer_cred<T>(request: &tonic::Request<T>) -> Option<UCred> {
+        request
+            .extensions()
+            .get::<UdsConnectInfo>()
+            .and_then(|info| info.peer_cred)
+    }
     impl AsyncRead for UnixStream {
         fn poll_read(
             mut self: Pin<&mut Self>,
@@ -0,0 +1,250 @@
+//! Listeners of the gRPC server of the sandboxer, on a unix socket file, an abstract unix
+//! socket, a vsock port, or a socket passed in by systemd socket activation.
+//!
+//! TCP is left out on purpose, as the peer credentials of callers can not be checked on it.
+
+use std::fmt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use futures::Stream;
+use log::warn;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::socket::{self, AddressFamily, SockAddr, SockFlag, SockType, UnixAddr};
+use nix::sys::stat::{fstat, SFlag};
+use tokio::net::UnixListener;
+
+use crate::error::{Error, Result};
+use crate::unix::UnixStream;
+
+const UNIX_SCHEME: &str = "unix";
+const UNIX_ABSTRACT_SCHEME: &str = "unix-abstract";
+const VSOCK_SCHEME: &str = "vsock";
+const FD_SCHEME: &str = "fd";
+
+/// The first fd passed by systemd, see `sd_listen_fds(3)`.
+pub const SD_LISTEN_FDS_START: RawFd = 3;
+const LISTEN_BACKLOG: usize = 128;
+
+/// Address to serve on, parsed from `unix:///path`, `unix-abstract://name`,
+/// `vsock://cid:port`, `fd://` or `fd://<fd>`. A plain path is a unix socket file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// A unix socket file, which replaces an existing socket but never any other file.
+    Unix(PathBuf),
+    /// A unix socket in the abstract namespace, named without the leading nul byte.
+    UnixAbstract(String),
+    Vsock {
+        cid: u32,
+        port: u32,
+    },
+    /// A listening socket passed by systemd socket activation, `fd://` is the first one.
+    Fd(RawFd),
+}
+
+impl FromStr for ListenAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidArgument(format!("invalid listen address {:?}", s));
+        let (scheme, rest) = match s.split_once("://") {
+            Some(x) => x,
+            None if !s.is_empty() => return Ok(Self::Unix(PathBuf::from(s))),
+            None => return Err(invalid()),
+        };
+        match scheme {
+            UNIX_SCHEME if !rest.is_empty() => Ok(Self::Unix(PathBuf::from(rest))),
+            UNIX_ABSTRACT_SCHEME if !rest.is_empty() => Ok(Self::UnixAbstract(rest.to_string())),
+            VSOCK_SCHEME => {
+                let (cid, port) = rest.split_once(':').ok_or_else(invalid)?;
+                Ok(Self::Vsock {
+                    cid: cid.parse().map_err(|_| invalid())?,
+                    port: port.parse().map_err(|_| invalid())?,
+                })
+            }
+            FD_SCHEME if rest.is_empty() => Ok(Self::Fd(SD_LISTEN_FDS_START)),
+            FD_SCHEME => Ok(Self::Fd(rest.parse().map_err(|_| invalid())?)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "{}://{}", UNIX_SCHEME, path.display()),
+            Self::UnixAbstract(name) => write!(f, "{}://{}", UNIX_ABSTRACT_SCHEME, name),
+            Self::Vsock { cid, port } => write!(f, "{}://{}:{}", VSOCK_SCHEME, cid, port),
+            Self::Fd(fd) => write!(f, "{}://{}", FD_SCHEME, fd),
+        }
+    }
+}
+
+/// Fds passed to this process by systemd socket activation, empty if there are none.
+///
+/// `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES` are left set, as changing the environment
+/// races with other threads reading it. Child processes inheriting them ignore them, as their
+/// pid differs from `LISTEN_PID`, and the fds are made close-on-exec once bound.
+pub fn listen_fds() -> Vec<RawFd> {
+    let pid = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|p| p.parse().ok());
+    if pid != Some(std::process::id()) {
+        return vec![];
+    }
+    let n: RawFd = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or_default();
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + n).collect()
+}
+
+pub struct Listener {
+    addr: ListenAddr,
+    listener: UnixListener,
+}
+
+impl Listener {
+    pub async fn bind(addr: ListenAddr) -> Result<Self> {
+        let listener = match &addr {
+            ListenAddr::Unix(path) => {
+                remove_socket(path).await?;
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                UnixListener::bind(path)?
+            }
+            ListenAddr::UnixAbstract(name) => {
+                let sock_addr = UnixAddr::new_abstract(name.as_bytes())
+                    .map_err(|e| anyhow!("invalid abstract socket name {}: {}", name, e))?;
+                listen(AddressFamily::Unix, &SockAddr::Unix(sock_addr))?
+            }
+            ListenAddr::Vsock { cid, port } => {
+                listen(AddressFamily::Vsock, &SockAddr::new_vsock(*cid, *port))?
+            }
+            ListenAddr::Fd(fd) => {
+                if !listen_fds().contains(fd) {
+                    return Err(Error::InvalidArgument(format!(
+                        "fd {} is not passed by socket activation",
+                        fd
+                    )));
+                }
+                let stat = fstat(*fd).map_err(std::io::Error::from)?;
+                if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT != SFlag::S_IFSOCK {
+                    return Err(Error::InvalidArgument(format!("fd {} is not a socket", fd)));
+                }
+                fcntl(*fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(std::io::Error::from)?;
+                from_fd(*fd)?
+            }
+        };
+        Ok(Self { addr, listener })
+    }
+
+    pub fn addr(&self) -> &ListenAddr {
+        &self.addr
+    }
+
+    /// Accepted connections, to be served by `serve_with_incoming`.
+    pub fn incoming(self) -> impl Stream<Item = std::io::Result<UnixStream>> {
+        async_stream::stream! {
+            loop {
+                yield self.listener.accept().await.map(|(st, _)| UnixStream(st));
+            }
+        }
+    }
+}
+
+/// Remove the socket file left by a previous run, any other kind of file is kept.
+pub(crate) async fn remove_socket(path: &std::path::Path) -> Result<()> {
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(m) if m.file_type().is_socket() => Ok(tokio::fs::remove_file(path).await?),
+        Ok(_) => Err(Error::InvalidArgument(format!(
+            "refuse to remove {} which is not a socket",
+            path.display()
+        ))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A listening socket not supported by tokio directly, accepted streams of which are read and
+/// written as unix streams, but their peer credentials and addresses are not available.
+fn listen(family: AddressFamily, addr: &SockAddr) -> Result<UnixListener> {
+    let fd = socket::socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)
+        .map_err(std::io::Error::from)?;
+    let res = socket::bind(fd, addr).and_then(|_| socket::listen(fd, LISTEN_BACKLOG));
+    if let Err(e) = res {
+        nix::unistd::close(fd).unwrap_or_else(|e| warn!("failed to close socket: {}", e));
+        return Err(anyhow!("failed to listen on {}: {}", addr, e).into());
+    }
+    from_fd(fd)
+}
+
+fn from_fd(fd: RawFd) -> Result<UnixListener> {
+    let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    Ok(UnixListener::from_std(listener)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_addr() {
+        let cases = [
+            (
+                "/run/sandboxer.sock",
+                ListenAddr::Unix("/run/sandboxer.sock".into()),
+            ),
+            (
+                "unix:///run/sandboxer.sock",
+                ListenAddr::Unix("/run/sandboxer.sock".into()),
+            ),
+            (
+                "unix-abstract://sandboxer",
+                ListenAddr::UnixAbstract("sandboxer".to_string()),
+            ),
+            ("vsock://3:1024", ListenAddr::Vsock { cid: 3, port: 1024 }),
+            ("fd://", ListenAddr::Fd(SD_LISTEN_FDS_START)),
+            ("fd://4", ListenAddr::Fd(4)),
+        ];
+        for (s, addr) in cases {
+            assert_eq!(s.parse::<ListenAddr>().unwrap(), addr);
+        }
+        for s in [
+            "",
+            "unix://",
+            "vsock://3",
+            "vsock://a:1",
+            "tcp://127.0.0.1:80",
+        ] {
+            assert!(s.parse::<ListenAddr>().is_err(), "{}", s);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix() {
+        let dir = std::env::temp_dir().join(format!("sandbox-listener-{}", std::process::id()));
+        let path = dir.join("sandboxer.sock");
+        let listener = Listener::bind(ListenAddr::Unix(path.clone()))
+            .await
+            .unwrap();
+        drop(listener);
+        // the socket left behind is replaced
+        Listener::bind(ListenAddr::Unix(path.clone()))
+            .await
+            .unwrap();
+
+        let file = dir.join("file");
+        tokio::fs::write(&file, "data").await.unwrap();
+        assert!(Listener::bind(ListenAddr::Unix(file.clone()))
+            .await
+            .is_err());
+        assert!(file.exists());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
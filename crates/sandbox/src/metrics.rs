@@ -0,0 +1,118 @@
+//! Opt-in Prometheus metrics of the sandboxer process.
+//!
+//! Metrics are only collected when the `metrics` feature is enabled, otherwise the recording
+//! helpers here are no-ops. Use [`serve`] to expose them in the text exposition format.
+
+#[cfg(feature = "metrics")]
+mod registry {
+    use lazy_static::lazy_static;
+    use prometheus::{exponential_buckets, HistogramOpts, HistogramVec, IntGauge, Registry};
+
+    pub(super) struct Metrics {
+        pub registry: Registry,
+        pub rpc_duration: HistogramVec,
+        pub sandboxes: IntGauge,
+    }
+
+    impl Metrics {
+        fn new() -> prometheus::Result<Self> {
+            let registry = Registry::new_custom(Some("containerd_sandboxer".to_string()), None)?;
+            let rpc_duration = HistogramVec::new(
+                HistogramOpts::new("rpc_duration_seconds", "Latency of controller requests")
+                    .buckets(exponential_buckets(0.001, 2.0, 15)?),
+                &["method"],
+            )?;
+            let sandboxes = IntGauge::new("sandboxes", "Number of live sandboxes")?;
+
+            registry.register(Box::new(rpc_duration.clone()))?;
+            registry.register(Box::new(sandboxes.clone()))?;
+
+            Ok(Self {
+                registry,
+                rpc_duration,
+                sandboxes,
+            })
+        }
+    }
+
+    lazy_static! {
+        pub(super) static ref METRICS: Metrics =
+            Metrics::new().expect("failed to register sandboxer metrics");
+    }
+}
+
+/// Records the latency of a controller request when dropped.
+pub struct RpcTimer {
+    #[cfg(feature = "metrics")]
+    _timer: prometheus::HistogramTimer,
+}
+
+/// Start timing a controller request, the latency is observed when the returned timer is dropped.
+pub fn rpc_timer(_method: &str) -> RpcTimer {
+    RpcTimer {
+        #[cfg(feature = "metrics")]
+        _timer: registry::METRICS
+            .rpc_duration
+            .with_label_values(&[_method])
+            .start_timer(),
+    }
+}
+
+/// Record a sandbox being created.
+pub fn inc_sandboxes() {
+    #[cfg(feature = "metrics")]
+    registry::METRICS.sandboxes.inc();
+}
+
+/// Record a sandbox being shut down.
+pub fn dec_sandboxes() {
+    #[cfg(feature = "metrics")]
+    registry::METRICS.sandboxes.dec();
+}
+
+/// Encode all collected metrics in the Prometheus text exposition format.
+#[cfg(feature = "metrics")]
+pub fn gather() -> crate::error::Result<String> {
+    use anyhow::anyhow;
+    use prometheus::{Encoder, TextEncoder};
+
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&registry::METRICS.registry.gather(), &mut buf)
+        .map_err(|e| anyhow!("failed to encode metrics: {}", e))?;
+    Ok(String::from_utf8(buf).map_err(|e| anyhow!("{}", e))?)
+}
+
+/// Serve metrics over HTTP on the unix socket at `address`.
+///
+/// Every connection is answered with the current metrics regardless of the request path.
+#[cfg(feature = "metrics")]
+pub async fn serve(address: &str) -> crate::error::Result<()> {
+    use log::warn;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixListener;
+
+    crate::listener::remove_socket(std::path::Path::new(address)).await?;
+    let listener = UnixListener::bind(address)?;
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            // The request is not interpreted, only drain what the client sent.
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            let body = gather().unwrap_or_else(|e| {
+                warn!("failed to gather metrics: {}", e);
+                String::new()
+            });
+            let resp = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream
+                .write_all(resp.as_bytes())
+                .await
+                .unwrap_or_else(|e| warn!("failed to write metrics: {}", e));
+        });
+    }
+}
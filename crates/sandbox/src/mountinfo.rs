@@ -0,0 +1,148 @@
+//! Parser of `/proc/self/mountinfo`, see `proc(5)`.
+
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+
+pub const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+const OPTIONAL_FIELDS_END: &str = "-";
+
+/// A line of the mountinfo, with the octal escapes in paths decoded.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MountInfo {
+    pub id: u32,
+    pub parent_id: u32,
+    /// `major:minor` of the device.
+    pub device: String,
+    /// Root of the mount in the filesystem.
+    pub root: String,
+    pub mount_point: String,
+    pub options: String,
+    /// Optional fields such as `shared:1` or `master:2`.
+    pub optional: Vec<String>,
+    pub fs_type: String,
+    pub source: String,
+    pub super_options: String,
+}
+
+impl FromStr for MountInfo {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let invalid = || Error::InvalidArgument(format!("invalid mountinfo line {:?}", line));
+        let mut fields = line.split(' ');
+        let mut next = || fields.next().ok_or_else(invalid);
+        let mut info = Self {
+            id: next()?.parse().map_err(|_| invalid())?,
+            parent_id: next()?.parse().map_err(|_| invalid())?,
+            device: next()?.to_string(),
+            root: unescape(next()?),
+            mount_point: unescape(next()?),
+            options: next()?.to_string(),
+            ..Default::default()
+        };
+        loop {
+            match next()? {
+                OPTIONAL_FIELDS_END => break,
+                f => info.optional.push(f.to_string()),
+            }
+        }
+        info.fs_type = unescape(next()?);
+        info.source = unescape(next()?);
+        info.super_options = next()?.to_string();
+        Ok(info)
+    }
+}
+
+/// Parse the content of a mountinfo file.
+pub fn parse(content: &str) -> Result<Vec<MountInfo>> {
+    content
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(MountInfo::from_str)
+        .collect()
+}
+
+/// Mounts in the mount namespace of this process, in the order they are mounted.
+pub async fn mounts() -> Result<Vec<MountInfo>> {
+    let content = tokio::fs::read_to_string(MOUNTINFO_PATH).await?;
+    parse(&content)
+}
+
+/// Decode the `\ooo` octal escapes of space, tab, newline and backslash in a path.
+pub fn unescape(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 4).filter(|_| bytes[i] == b'\\');
+        match escape.and_then(|o| u8::from_str_radix(std::str::from_utf8(o).ok()?, 8).ok()) {
+            Some(b) => {
+                decoded.push(b);
+                i += 4;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Whether `path` is `dir` or under it, matching whole path components, so that `/dir/abc2`
+/// is not under `/dir/abc`.
+pub fn is_within(path: &str, dir: &str) -> bool {
+    let dir = dir.trim_end_matches('/');
+    match path.strip_prefix(dir) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let content = "\
+22 1 0:21 / /proc rw,nosuid shared:13 - proc proc rw
+36 22 8:1 /a\\040b /run/dir\\040with\\011tab rw shared:1 master:2 - ext4 /dev/sda1 rw,errors=remount-ro
+37 22 0:4 net:[4026532281] /run/netns/sb1 rw - nsfs nsfs rw
+";
+        let mounts = parse(content).unwrap();
+        assert_eq!(mounts.len(), 3);
+        assert_eq!(mounts[0].mount_point, "/proc");
+        assert!(mounts[0].optional == vec!["shared:13"]);
+        let m = &mounts[1];
+        assert_eq!((m.id, m.parent_id), (36, 22));
+        assert_eq!(m.device, "8:1");
+        assert_eq!(m.root, "/a b");
+        assert_eq!(m.mount_point, "/run/dir with\ttab");
+        assert_eq!(m.optional, vec!["shared:1", "master:2"]);
+        assert_eq!(m.fs_type, "ext4");
+        assert_eq!(m.source, "/dev/sda1");
+        assert_eq!(m.super_options, "rw,errors=remount-ro");
+        assert_eq!(mounts[2].fs_type, "nsfs");
+
+        assert!(parse("22 1 0:21 / /proc rw shared:13 proc proc rw").is_err());
+        assert!(parse("a 1 0:21 / /proc rw - proc proc rw").is_err());
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape("/a\\040b\\134c"), "/a b\\c");
+        assert_eq!(unescape("/a\\04"), "/a\\04");
+        assert_eq!(unescape("/a\\x40"), "/a\\x40");
+    }
+
+    #[test]
+    fn test_is_within() {
+        assert!(is_within("/dir/abc", "/dir/abc"));
+        assert!(is_within("/dir/abc/rootfs", "/dir/abc/"));
+        assert!(!is_within("/dir/abc2", "/dir/abc"));
+        assert!(!is_within("/dir", "/dir/abc"));
+        assert!(is_within("/dir", "/"));
+    }
+}
@@ -0,0 +1,186 @@
+//! Persistent network namespaces of sandboxes. A namespace is created on a dedicated thread
+//! and pinned by a bind mount of its nsfs file at [`NETNS_FILE`] in the base dir of the
+//! sandbox, so that it outlives the thread and is removed together with the base dir by the
+//! controller on shutdown. Sandboxers set the path as the `netns` of the sandbox data.
+
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use log::{debug, warn};
+use nix::mount::{mount, MsFlags};
+use nix::sched::{setns, unshare, CloneFlags};
+use nix::unistd::gettid;
+use tokio::sync::oneshot;
+
+use crate::error::{Error, Result};
+use crate::utils::unmount;
+
+/// Name of the file pinning the network namespace in the base dir of a sandbox.
+pub const NETNS_FILE: &str = "netns";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NetNs {
+    path: PathBuf,
+}
+
+impl NetNs {
+    /// The network namespace pinned in `base_dir`, which may not be created yet.
+    pub fn in_base_dir(base_dir: &str) -> Self {
+        Self {
+            path: Path::new(base_dir).join(NETNS_FILE),
+        }
+    }
+
+    /// An existing network namespace, e.g. the `netns` of a recovered sandbox.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(Error::NotFound(format!("netns {}", path.display())));
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Create a new network namespace pinned in `base_dir`.
+    pub async fn create(base_dir: &str) -> Result<Self> {
+        let netns = Self::in_base_dir(base_dir);
+        let path = netns.path.clone();
+        debug!("create netns {}", path.display());
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| anyhow!("failed to create {}: {}", path.display(), e))?;
+        let res = in_new_thread(move || {
+            unshare(CloneFlags::CLONE_NEWNET).map_err(|e| anyhow!("failed to unshare: {}", e))?;
+            let source = format!("/proc/self/task/{}/ns/net", gettid());
+            mount(
+                Some(source.as_str()),
+                &path,
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            )
+            .map_err(|e| anyhow!("failed to bind {} to {}: {}", source, path.display(), e))?;
+            Ok(())
+        })
+        .await;
+        if let Err(e) = res {
+            netns
+                .remove()
+                .await
+                .unwrap_or_else(|e| warn!("failed to remove netns {}: {}", netns, e));
+            return Err(e);
+        }
+        Ok(netns)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Run `f` on a dedicated thread in the network namespace, e.g. to set up its interfaces.
+    pub async fn run<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let file = std::fs::File::open(&self.path)
+            .map_err(|e| anyhow!("failed to open netns {}: {}", self, e))?;
+        let path = self.to_string();
+        in_new_thread(move || {
+            setns(file.as_raw_fd(), CloneFlags::CLONE_NEWNET)
+                .map_err(|e| anyhow!("failed to enter netns {}: {}", path, e))?;
+            f()
+        })
+        .await
+    }
+
+    /// Unpin and remove the network namespace, which is a no-op if it is removed already.
+    pub async fn remove(&self) -> Result<()> {
+        match tokio::fs::metadata(&self.path).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            _ => {}
+        }
+        debug!("remove netns {}", self);
+        let target = self.path.to_string_lossy();
+        match unmount(&target, libc::MNT_DETACH | libc::UMOUNT_NOFOLLOW) {
+            Ok(()) => {}
+            // not mounted, left by a failed create
+            Err(_) if !is_nsfs(&self.path) => {}
+            Err(e) => return Err(e),
+        }
+        match tokio::fs::remove_file(&self.path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Display for NetNs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
+
+fn is_nsfs(path: &Path) -> bool {
+    const NSFS_MAGIC: libc::c_long = 0x6e736673;
+    nix::sys::statfs::statfs(path)
+        .map(|s| s.filesystem_type().0 as libc::c_long == NSFS_MAGIC)
+        .unwrap_or_default()
+}
+
+/// Run `f` on a new thread which exits after it, so that the namespaces it enters never leak
+/// into the threads of the runtime.
+async fn in_new_thread<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    std::thread::Builder::new()
+        .name("netns".to_string())
+        .spawn(move || tx.send(f()).unwrap_or_default())
+        .map_err(Error::IO)?;
+    rx.await
+        .map_err(|_| anyhow!("thread in netns exited unexpectedly"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn current_netns() -> String {
+        std::fs::read_link("/proc/thread-self/ns/net")
+            .unwrap()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_netns() {
+        if !nix::unistd::Uid::effective().is_root() {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("sandbox-netns-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let base_dir = dir.to_string_lossy().to_string();
+
+        let netns = NetNs::create(&base_dir).await.unwrap();
+        assert_eq!(netns, NetNs::open(dir.join(NETNS_FILE)).unwrap());
+        assert!(NetNs::create(&base_dir).await.is_err());
+        let host = current_netns();
+        let inside = netns.run(|| Ok(current_netns())).await.unwrap();
+        assert_ne!(inside, host);
+        assert_eq!(netns.run(|| Ok(current_netns())).await.unwrap(), inside);
+        assert_eq!(current_netns(), host);
+
+        netns.remove().await.unwrap();
+        assert!(!netns.path().exists());
+        netns.remove().await.unwrap();
+        assert!(NetNs::open(netns.path()).is_err());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+//! Platform of sandboxes, in the form of OCI image index platforms.
+
+use crate::types::Platform;
+
+/// Map an OS name of [`std::env::consts::OS`] to its OCI name.
+pub fn oci_os(os: &str) -> &str {
+    match os {
+        "macos" => "darwin",
+        os => os,
+    }
+}
+
+/// Map an architecture name of [`std::env::consts::ARCH`] to its OCI architecture and variant.
+///
+/// Endianness is not part of the architecture name in Rust, so `little_endian` tells
+/// the variants apart, e.g. `ppc64` and `ppc64le`.
+pub fn oci_arch(arch: &str, little_endian: bool) -> (&str, &str) {
+    match (arch, little_endian) {
+        ("x86_64", _) => ("amd64", ""),
+        ("x86", _) => ("386", ""),
+        ("aarch64", _) => ("arm64", ""),
+        ("arm", _) => ("arm", "v7"),
+        ("riscv64", _) => ("riscv64", ""),
+        ("loongarch64", _) => ("loong64", ""),
+        ("powerpc64", true) => ("ppc64le", ""),
+        ("powerpc64", false) => ("ppc64", ""),
+        ("mips64", true) => ("mips64le", ""),
+        ("mips", true) => ("mipsle", ""),
+        (arch, _) => (arch, ""),
+    }
+}
+
+/// The platform of the host, which sandboxes run on unless they run a guest OS of their own.
+pub fn host_platform() -> Platform {
+    let (architecture, variant) = oci_arch(std::env::consts::ARCH, cfg!(target_endian = "little"));
+    Platform {
+        os: oci_os(std::env::consts::OS).to_string(),
+        architecture: architecture.to_string(),
+        variant: variant.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oci_arch() {
+        assert_eq!(oci_arch("x86_64", true), ("amd64", ""));
+        assert_eq!(oci_arch("aarch64", true), ("arm64", ""));
+        assert_eq!(oci_arch("riscv64", true), ("riscv64", ""));
+        assert_eq!(oci_arch("powerpc64", true), ("ppc64le", ""));
+        assert_eq!(oci_arch("powerpc64", false), ("ppc64", ""));
+        assert_eq!(oci_arch("s390x", false), ("s390x", ""));
+    }
+
+    #[test]
+    fn test_host_platform() {
+        let platform = host_platform();
+        assert_eq!(platform.os, oci_os(std::env::consts::OS));
+        assert!(!platform.architecture.is_empty());
+        assert_ne!(platform.architecture, "x86_64");
+        assert_ne!(platform.architecture, "aarch64");
+    }
+}
@@ -1,20 +1,51 @@
+use std::collections::HashSet;
 use std::ops::DerefMut;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
 
 use log::{debug, info, warn};
+use prost::Message;
 use prost_types::Timestamp;
 use time::OffsetDateTime;
 use tokio::fs::{create_dir_all, remove_dir_all};
+use tokio::sync::{RwLock, RwLockReadGuard};
+use tokio::time::{timeout_at, Instant};
 use tonic::{Request, Response, Status};
 
+use crate::api::extension::v1::sandbox_extension_server::SandboxExtension;
+use crate::api::extension::v1::{
+    CheckpointRequest, CheckpointResponse, PauseRequest, PauseResponse, ResumeRequest,
+    ResumeResponse,
+};
 use crate::api::sandbox::v1::controller_server::Controller;
 use crate::api::sandbox::v1::*;
-use crate::data::{ContainerData, ProcessData, ProcessResource, SandboxData, TaskResources};
+use crate::auth::PeerAllowList;
+use crate::cgroup::METRICS_TYPE_URL;
+use crate::data::{
+    ApiVersion, ContainerData, Io, ProcessData, SandboxData, TaskResources, TaskResourcesDiff,
+};
+use crate::error::Error;
+use crate::event::EventPublisher;
+use crate::metrics;
+use crate::netns::NetNs;
+use crate::store::{FileStore, SandboxStore};
+use crate::task::{negotiate, TaskAddress, CONTAINERD_TASK_API_VERSIONS};
+use crate::types::Metric;
+use crate::unix::peer_cred;
 use crate::{Container, ContainerOption, Sandbox, SandboxOption, SandboxStatus, Sandboxer};
 
 use crate::utils::cleanup_mounts;
 
 const SANDBOX_STATUS_READY: &str = "SANDBOX_READY";
 const SANDBOX_STATUS_NOTREADY: &str = "SANDBOX_NOTREADY";
+/// Key of the json of [`SandboxData`] in the info of a verbose status response.
+pub const STATUS_INFO_DATA_KEY: &str = "sandbox_data";
+/// Version of the task API served at the task address of sandboxes, unless the sandbox
+/// reports its versions by [`Sandbox::task_api_versions`].
+pub const TASK_API_VERSION: u32 = 2;
+/// Timeout of a graceful stop if none is given by the stop request.
+pub const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(10);
 
 macro_rules! ignore_not_found {
     ($res: expr) => {{
@@ -31,11 +62,123 @@ macro_rules! ignore_not_found {
 pub struct SandboxController<S> {
     dir: String,
     sandboxer: S,
+    store: Box<dyn SandboxStore>,
+    events: EventPublisher,
+    peers: PeerAllowList,
+    /// Held for read by the requests changing sandboxes, and for write to drain them.
+    inflight: RwLock<()>,
+    draining: AtomicBool,
+    /// Ids of the sandboxes being created.
+    creating: std::sync::Mutex<HashSet<String>>,
 }
 
 impl<S> SandboxController<S> {
     pub fn new(dir: String, sandboxer: S) -> Self {
-        Self { dir, sandboxer }
+        let store = Box::new(FileStore::new(&dir));
+        Self {
+            dir,
+            sandboxer,
+            store,
+            events: EventPublisher::default(),
+            peers: PeerAllowList::default(),
+            inflight: RwLock::new(()),
+            draining: AtomicBool::new(false),
+            creating: Default::default(),
+        }
+    }
+
+    /// Replace the default [`FileStore`] in the working dir.
+    pub fn with_store(mut self, store: Box<dyn SandboxStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Use the publisher of which events are served by the `Events` service.
+    pub fn with_events(mut self, events: EventPublisher) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Only serve callers in the allow list, see [`PeerAllowList::authorize`].
+    pub fn with_allowed_peers(mut self, peers: PeerAllowList) -> Self {
+        self.peers = peers;
+        self
+    }
+}
+
+impl<S> SandboxController<S>
+where
+    S: Sandboxer + Send + Sync + 'static,
+{
+    /// Track a request changing sandboxes until the guard is dropped, new requests are
+    /// rejected once draining.
+    async fn track(&self) -> Result<RwLockReadGuard<'_, ()>, Status> {
+        if self.draining.load(Ordering::SeqCst) {
+            return Err(Status::unavailable("sandboxer is shutting down"));
+        }
+        Ok(self.inflight.read().await)
+    }
+
+    /// Reject new requests changing sandboxes, wait for the in-flight ones up to the timeout,
+    /// then call [`Sandboxer::shutdown`]. Requests not drained in time keep running
+    /// concurrently with the shutdown.
+    pub async fn close(&self, timeout: Duration) -> crate::error::Result<()> {
+        self.draining.store(true, Ordering::SeqCst);
+        match tokio::time::timeout(timeout, self.inflight.write()).await {
+            Ok(_) => info!("in-flight requests drained"),
+            Err(_) => warn!("in-flight requests are not drained in {:?}", timeout),
+        }
+        self.sandboxer.shutdown().await
+    }
+
+    /// Remove the state and the base dir of the sandbox, failures are only logged.
+    async fn remove_base_dir(&self, id: &str) {
+        if let Err(e) = self.store.remove_sandbox(id).await {
+            warn!("failed to remove state of sandbox {}: {}", id, e);
+        }
+        let base_dir = format!("{}/{}", self.dir, id);
+        if let Err(e) = NetNs::in_base_dir(&base_dir).remove().await {
+            warn!("failed to remove netns of sandbox {}: {}", id, e);
+        }
+        // never remove the dir with anything still mounted in it, which would remove the
+        // content of the mounts
+        if let Err(e) = cleanup_mounts(&base_dir).await {
+            warn!("failed to cleanup mounts in {}, keep it: {}", base_dir, e);
+            return;
+        }
+        if let Err(e) = remove_dir_all(&*base_dir).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("failed to remove {}: {}", base_dir, e);
+            }
+        }
+    }
+
+    /// Stop a sandbox which failed to start, so that it can be shut down.
+    async fn rollback_start(&self, id: &str) {
+        warn!("roll back start of sandbox {}", id);
+        if let Err(e) = ignore_not_found!(self.sandboxer.stop(id, true).await) {
+            warn!("failed to stop sandbox {} in roll back: {}", id, e);
+        }
+    }
+
+    /// Persist the current data of the sandbox, failures are only logged
+    /// as the sandbox itself has been changed already.
+    async fn save_sandbox(&self, id: &str) {
+        let res = match self.sandboxer.sandbox(id).await {
+            Ok(sandbox_mutex) => {
+                let sandbox = sandbox_mutex.lock().await;
+                match sandbox.get_data() {
+                    Ok(data) => self.store.save_sandbox(&data).await,
+                    Err(e) => Err(e),
+                }
+            }
+            // nothing to save for a sandbox already gone
+            Err(Error::NotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            warn!("failed to save state of sandbox {}: {}", id, e);
+        }
     }
 }
 
@@ -48,21 +191,52 @@ where
         &self,
         request: Request<ControllerCreateRequest>,
     ) -> Result<Response<ControllerCreateResponse>, Status> {
+        let _timer = metrics::rpc_timer("create");
+        self.peers.authorize(&request, "create")?;
+        let _inflight = self.track().await?;
         let req = request.get_ref();
         let sandbox_data: SandboxData = SandboxData::new(req);
-        info!("create a new sandbox {:?}", sandbox_data);
+        info!(
+            "create a new sandbox {:?}, called by {:?}",
+            sandbox_data,
+            peer_cred(&request)
+        );
         if sandbox_data.id.is_empty() {
             return Err(tonic::Status::invalid_argument("sandbox id is empty"));
         }
+        // concurrent creates of the same id are rejected as the sandbox exists
+        let _creating = Creating::new(&self.creating, &req.sandbox_id)?;
+        match self.sandboxer.sandbox(&req.sandbox_id).await {
+            Ok(_) => {
+                return Err(Status::already_exists(format!(
+                    "sandbox {} already exists",
+                    req.sandbox_id
+                )))
+            }
+            Err(Error::NotFound(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
         let base_dir = format!("{}/{}", self.dir, sandbox_data.id);
+        // left by a create interrupted before the sandboxer created the sandbox
+        if Path::new(&base_dir).exists() {
+            warn!(
+                "remove stale dir {} of sandbox {}",
+                base_dir, req.sandbox_id
+            );
+            self.remove_base_dir(&req.sandbox_id).await;
+        }
         create_dir_all(&*base_dir).await?;
-        let opt = SandboxOption::new(base_dir.clone(), sandbox_data);
+        let opt = SandboxOption::new(base_dir.clone(), sandbox_data, self.events.clone());
         if let Err(e) = self.sandboxer.create(&*req.sandbox_id, opt).await {
-            if let Err(re) = remove_dir_all(base_dir).await {
-                warn!("roll back in sandbox create rmdir: {}", re);
-            }
+            warn!(
+                "failed to create sandbox {}: {}, roll back",
+                req.sandbox_id, e
+            );
+            self.remove_base_dir(&req.sandbox_id).await;
             return Err(e.into());
         }
+        self.save_sandbox(&req.sandbox_id).await;
+        metrics::inc_sandboxes();
         let resp = ControllerCreateResponse {
             sandbox_id: req.sandbox_id.to_string(),
         };
@@ -73,36 +247,48 @@ where
         &self,
         request: tonic::Request<ControllerStartRequest>,
     ) -> Result<tonic::Response<ControllerStartResponse>, tonic::Status> {
+        let _timer = metrics::rpc_timer("start");
+        self.peers.authorize(&request, "start")?;
+        let _inflight = self.track().await?;
         let req = request.get_ref();
         info!("start sandbox {}", req.sandbox_id);
-        self.sandboxer.start(&req.sandbox_id).await?;
-
         let sandbox_mutex = self.sandboxer.sandbox(&req.sandbox_id).await?;
+        let started = match sandbox_mutex.lock().await.status()? {
+            SandboxStatus::Created => false,
+            SandboxStatus::Running(_) | SandboxStatus::Paused => true,
+            SandboxStatus::Stopped(_, _) => {
+                return Err(Status::failed_precondition(format!(
+                    "sandbox {} is stopped",
+                    req.sandbox_id
+                )))
+            }
+        };
+        if started {
+            info!("sandbox {} is started already", req.sandbox_id);
+        } else {
+            self.sandboxer.start(&req.sandbox_id).await?;
+        }
+
         let sandbox = sandbox_mutex.lock().await;
         let res = match sandbox.get_data() {
             Ok(s) => s,
             Err(e) => {
-                self.sandboxer
-                    .stop(&req.sandbox_id, true)
-                    .await
-                    .unwrap_or_default();
+                drop(sandbox);
+                self.rollback_start(&req.sandbox_id).await;
                 return Err(e.into());
             }
         };
         let pid = match sandbox.status() {
             Ok(SandboxStatus::Running(pid)) => pid,
+            Ok(SandboxStatus::Paused) if started => 0,
             Err(e) => {
-                self.sandboxer
-                    .stop(&req.sandbox_id, true)
-                    .await
-                    .unwrap_or_default();
+                drop(sandbox);
+                self.rollback_start(&req.sandbox_id).await;
                 return Err(e.into());
             }
             Ok(status) => {
-                self.sandboxer
-                    .stop(&req.sandbox_id, true)
-                    .await
-                    .unwrap_or_default();
+                drop(sandbox);
+                self.rollback_start(&req.sandbox_id).await;
                 return Err(tonic::Status::new(
                     tonic::Code::Internal,
                     format!("sandbox status is {}", status.to_string()),
@@ -110,13 +296,19 @@ where
             }
         };
 
+        let (address, version) = task_api(&req.sandbox_id, &*sandbox, &res);
+        drop(sandbox);
+        if !started {
+            self.save_sandbox(&req.sandbox_id).await;
+        }
+
         let resp = ControllerStartResponse {
             sandbox_id: req.sandbox_id.to_string(),
             pid,
             created_at: res.created_at.map(|x| x.into()),
-            labels: Default::default(),
-            address: res.task_address.clone(),
-            version: 2,
+            labels: res.labels.clone(),
+            address,
+            version,
         };
         info!("start sandbox {:?} returns successfully", resp);
         Ok(Response::new(resp))
@@ -124,15 +316,13 @@ where
 
     async fn platform(
         &self,
-        _request: Request<ControllerPlatformRequest>,
+        request: Request<ControllerPlatformRequest>,
     ) -> Result<Response<ControllerPlatformResponse>, Status> {
-        // TODO add more os and arch support,
-        // maybe we has to add a new function to our Sandboxer trait
-        let platform = crate::types::Platform {
-            os: "linux".to_string(),
-            architecture: "x86".to_string(),
-            variant: "".to_string(),
-        };
+        let _timer = metrics::rpc_timer("platform");
+        self.peers.authorize(&request, "platform")?;
+        let req = request.get_ref();
+        let platform = self.sandboxer.platform(&req.sandbox_id).await?;
+        debug!("platform of sandbox {} is {:?}", req.sandbox_id, platform);
         let resp = ControllerPlatformResponse {
             platform: Some(platform),
         };
@@ -143,43 +333,58 @@ where
         &self,
         request: Request<ControllerUpdateRequest>,
     ) -> Result<Response<ControllerUpdateResponse>, Status> {
+        let _timer = metrics::rpc_timer("update");
+        self.peers.authorize(&request, "update")?;
+        let _inflight = self.track().await?;
         let req = request.get_ref();
-        info!("update resource of sandbox {}", req.sandbox_id);
-        // only handle extensions.tasks update
-        if !req.fields.iter().any(|x| x == "extensions.tasks") {
-            warn!("only support updating extensions.tasks");
+        info!(
+            "update fields {:?} of sandbox {}",
+            req.fields, req.sandbox_id
+        );
+        let sb = req
+            .sandbox
+            .as_ref()
+            .ok_or_else(|| Status::invalid_argument("sandbox is none when update"))?;
+        if req.fields.is_empty() {
+            debug!("no field to update of sandbox {}", req.sandbox_id);
             return Ok(Response::new(ControllerUpdateResponse {}));
         }
-        let (tasks_any, tasks) = if let Some(sb) = &req.sandbox {
-            if let Some(a) = sb.extensions.get("tasks") {
-                let tasks = serde_json::from_slice(a.value.as_slice()).map_err(|e| {
-                    Status::invalid_argument(format!("failed to unmarshal tasks: {}", e))
-                })?;
-                let ta = crate::data::Any {
-                    type_url: a.type_url.clone(),
-                    value: a.value.clone(),
-                };
-                (ta, tasks)
-            } else {
-                return Err(Status::invalid_argument(
-                    "no tasks key in sandbox extensions when update",
-                ))?;
-            }
-        } else {
-            return Err(Status::invalid_argument("sandbox is none when update"))?;
-        };
 
-        let mut data = {
+        let (data, containers, removed, undo) = {
             let sandbox_mutex = self.sandboxer.sandbox(&*req.sandbox_id).await?;
             let mut sandbox = sandbox_mutex.lock().await;
-            let data = sandbox.get_data()?;
+            let mut data = sandbox.get_data()?;
             let old_tasks = data.task_resources()?;
-            update_resources(&req.sandbox_id, sandbox.deref_mut(), tasks, old_tasks).await?;
-            data
+            data.update_fields(sb, &req.fields)?;
+            let tasks = data.task_resources()?;
+            let (containers, removed, undo) =
+                update_resources(&req.sandbox_id, sandbox.deref_mut(), &tasks, &old_tasks).await?;
+            (data, containers, removed, undo)
         };
 
-        data.extensions.insert("tasks".to_string(), tasks_any);
-        self.sandboxer.update(&req.sandbox_id, data).await?;
+        if let Err(e) = self.sandboxer.update(&req.sandbox_id, data).await {
+            if !undo.is_empty() {
+                warn!(
+                    "failed to update sandbox {}: {}, roll back its containers",
+                    req.sandbox_id, e
+                );
+                let sandbox_mutex = self.sandboxer.sandbox(&req.sandbox_id).await?;
+                let mut sandbox = sandbox_mutex.lock().await;
+                rollback_containers(&req.sandbox_id, sandbox.deref_mut(), undo).await;
+            }
+            return Err(e.into());
+        }
+        self.save_sandbox(&req.sandbox_id).await;
+        for c in containers.iter() {
+            if let Err(e) = self.store.save_container(&req.sandbox_id, c).await {
+                warn!("failed to save state of container {}: {}", c.id, e);
+            }
+        }
+        for id in removed.iter() {
+            if let Err(e) = self.store.remove_container(&req.sandbox_id, id).await {
+                warn!("failed to remove state of container {}: {}", id, e);
+            }
+        }
         info!("update sandbox {} successfully", req.sandbox_id);
         Ok(Response::new(ControllerUpdateResponse {}))
     }
@@ -188,9 +393,59 @@ where
         &self,
         request: Request<ControllerStopRequest>,
     ) -> Result<Response<ControllerStopResponse>, Status> {
+        let _timer = metrics::rpc_timer("stop");
+        self.peers.authorize(&request, "stop")?;
+        let _inflight = self.track().await?;
         let req = request.get_ref();
-        info!("stop sandbox {}", req.sandbox_id);
-        ignore_not_found!(self.sandboxer.stop(&*req.sandbox_id, true).await)?;
+        let timeout = match req.timeout_secs {
+            0 => DEFAULT_STOP_TIMEOUT,
+            secs => Duration::from_secs(secs as u64),
+        };
+        match self.sandboxer.sandbox(&req.sandbox_id).await {
+            Ok(sandbox_mutex) => {
+                if let SandboxStatus::Stopped(_, _) = sandbox_mutex.lock().await.status()? {
+                    info!("sandbox {} is stopped already", req.sandbox_id);
+                    return Ok(Response::new(ControllerStopResponse {}));
+                }
+            }
+            Err(Error::NotFound(_)) => {
+                info!("sandbox {} not found when stop", req.sandbox_id);
+                return Ok(Response::new(ControllerStopResponse {}));
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let deadline = Instant::now() + timeout;
+        info!(
+            "stop sandbox {} gracefully in {:?}",
+            req.sandbox_id, timeout
+        );
+        let graceful = self
+            .sandboxer
+            .stop_with_deadline(&req.sandbox_id, deadline.into_std());
+        match timeout_at(deadline, graceful).await {
+            Ok(Ok(())) => info!("sandbox {} stopped gracefully", req.sandbox_id),
+            Ok(Err(Error::NotFound(_))) => {
+                info!("sandbox {} not found when stop", req.sandbox_id);
+                return Ok(Response::new(ControllerStopResponse {}));
+            }
+            Ok(Err(e)) => {
+                warn!(
+                    "failed to stop sandbox {} gracefully: {}, force stop it",
+                    req.sandbox_id, e
+                );
+                ignore_not_found!(self.sandboxer.stop(&req.sandbox_id, true).await)?;
+                info!("sandbox {} stopped forcibly", req.sandbox_id);
+            }
+            Err(_) => {
+                warn!(
+                    "timeout to stop sandbox {} gracefully in {:?}, force stop it",
+                    req.sandbox_id, timeout
+                );
+                ignore_not_found!(self.sandboxer.stop(&req.sandbox_id, true).await)?;
+                info!("sandbox {} stopped forcibly", req.sandbox_id);
+            }
+        }
+        self.save_sandbox(&req.sandbox_id).await;
         info!("stop sandbox {} returns successfully", req.sandbox_id);
         Ok(Response::new(ControllerStopResponse {}))
     }
@@ -199,6 +454,7 @@ where
         &self,
         request: tonic::Request<ControllerWaitRequest>,
     ) -> Result<tonic::Response<ControllerWaitResponse>, tonic::Status> {
+        self.peers.authorize(&request, "wait")?;
         let req = request.get_ref();
         let exit_signal = {
             let sandbox_mutex = self.sandboxer.sandbox(&*req.sandbox_id).await?;
@@ -231,36 +487,37 @@ where
         &self,
         request: tonic::Request<ControllerStatusRequest>,
     ) -> Result<tonic::Response<ControllerStatusResponse>, tonic::Status> {
+        let _timer = metrics::rpc_timer("status");
+        self.peers.authorize(&request, "status")?;
         let req = request.get_ref();
         let sandbox_mutex = self.sandboxer.sandbox(&*req.sandbox_id).await?;
         let sandbox = sandbox_mutex.lock().await;
-        // TODO the state should match the definition in containerd
+        // a paused sandbox is still alive, containerd regards it as ready, like a running one
         let (state, pid) = match sandbox.status()? {
             SandboxStatus::Created => (SANDBOX_STATUS_NOTREADY.to_string(), 0),
             SandboxStatus::Running(pid) => (SANDBOX_STATUS_READY.to_string(), pid),
             SandboxStatus::Stopped(_, _) => (SANDBOX_STATUS_NOTREADY.to_string(), 0),
-            SandboxStatus::Paused => (SANDBOX_STATUS_NOTREADY.to_string(), 0),
-        };
-        let (created_at, exited_at, address) = {
-            let data = sandbox.get_data()?;
-            (
-                data.created_at.map(|x| x.into()),
-                data.exited_at.map(|x| x.into()),
-                data.task_address,
-            )
+            SandboxStatus::Paused => (SANDBOX_STATUS_READY.to_string(), 0),
         };
+        let data = sandbox.get_data()?;
+        let (mut info, extra) = sandbox.status_info(req.verbose).await?;
+        let (address, version) = task_api(&req.sandbox_id, &*sandbox, &data);
+        if req.verbose {
+            let json = serde_json::to_string(&data)
+                .map_err(|e| Status::internal(format!("failed to marshal sandbox data: {}", e)))?;
+            info.insert(STATUS_INFO_DATA_KEY.to_string(), json);
+        }
         debug!("status sandbox {} returns {:?}", req.sandbox_id, state);
-        // TODO add verbose support
         return Ok(Response::new(ControllerStatusResponse {
             sandbox_id: req.sandbox_id.to_string(),
             pid,
             state,
-            info: Default::default(),
-            created_at,
-            exited_at,
-            extra: None,
-            address: address,
-            version: 2,
+            info,
+            created_at: data.created_at.map(|x| x.into()),
+            exited_at: data.exited_at.map(|x| x.into()),
+            extra,
+            address,
+            version,
         }));
     }
 
@@ -268,119 +525,513 @@ where
         &self,
         request: tonic::Request<ControllerShutdownRequest>,
     ) -> Result<tonic::Response<ControllerShutdownResponse>, tonic::Status> {
+        let _timer = metrics::rpc_timer("shutdown");
+        self.peers.authorize(&request, "shutdown")?;
+        let _inflight = self.track().await?;
         let req = request.get_ref();
         info!("shutdown sandbox {}", req.sandbox_id);
-        ignore_not_found!(self.sandboxer.delete(&*req.sandbox_id).await)?;
-        let base_dir = format!("{}/{}", self.dir, req.sandbox_id);
-        // Ignore clean up error
-        cleanup_mounts(&base_dir).await.unwrap_or_default();
-        remove_dir_all(&*base_dir).await.unwrap_or_default();
+        match self.sandboxer.delete(&req.sandbox_id).await {
+            Ok(()) => metrics::dec_sandboxes(),
+            // removes what is left by an interrupted shutdown
+            Err(Error::NotFound(_)) => info!("sandbox {} not found when shutdown", req.sandbox_id),
+            Err(e) => return Err(e.into()),
+        }
+        self.remove_base_dir(&req.sandbox_id).await;
         return Ok(Response::new(ControllerShutdownResponse {}));
     }
 
     async fn metrics(
         &self,
-        _request: Request<ControllerMetricsRequest>,
+        request: Request<ControllerMetricsRequest>,
     ) -> Result<Response<ControllerMetricsResponse>, Status> {
-        let resp = ControllerMetricsResponse { metrics: None };
+        let _timer = metrics::rpc_timer("metrics");
+        self.peers.authorize(&request, "metrics")?;
+        let req = request.get_ref();
+        let res = {
+            let sandbox_mutex = self.sandboxer.sandbox(&req.sandbox_id).await?;
+            let sandbox = sandbox_mutex.lock().await;
+            sandbox.metrics().await
+        };
+        let stats = match res {
+            Ok(stats) => stats,
+            // containerd takes an error as the failure of the sandbox, not the lack of metrics
+            Err(Error::Unimplemented(_)) => {
+                debug!("metrics of sandbox {} are not supported", req.sandbox_id);
+                return Ok(Response::new(ControllerMetricsResponse { metrics: None }));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let metric = Metric {
+            timestamp: Some(SystemTime::now().into()),
+            id: req.sandbox_id.to_string(),
+            data: Some(prost_types::Any {
+                type_url: METRICS_TYPE_URL.to_string(),
+                value: stats.encode_to_vec(),
+            }),
+        };
+        debug!("metrics of sandbox {} returns {:?}", req.sandbox_id, stats);
+        let resp = ControllerMetricsResponse {
+            metrics: Some(metric),
+        };
         return Ok(Response::new(resp));
     }
 }
 
+#[tonic::async_trait]
+impl<S> SandboxExtension for SandboxController<S>
+where
+    S: Sandboxer + Send + Sync + 'static,
+{
+    async fn pause(
+        &self,
+        request: Request<PauseRequest>,
+    ) -> Result<Response<PauseResponse>, Status> {
+        let _timer = metrics::rpc_timer("pause");
+        self.peers.authorize(&request, "pause")?;
+        let _inflight = self.track().await?;
+        let req = request.get_ref();
+        info!("pause sandbox {}", req.sandbox_id);
+        self.sandboxer.pause(&req.sandbox_id).await?;
+        info!("sandbox {} paused", req.sandbox_id);
+        Ok(Response::new(PauseResponse {}))
+    }
+
+    async fn resume(
+        &self,
+        request: Request<ResumeRequest>,
+    ) -> Result<Response<ResumeResponse>, Status> {
+        let _timer = metrics::rpc_timer("resume");
+        self.peers.authorize(&request, "resume")?;
+        let _inflight = self.track().await?;
+        let req = request.get_ref();
+        info!("resume sandbox {}", req.sandbox_id);
+        self.sandboxer.resume(&req.sandbox_id).await?;
+        info!("sandbox {} resumed", req.sandbox_id);
+        Ok(Response::new(ResumeResponse {}))
+    }
+
+    async fn checkpoint(
+        &self,
+        request: Request<CheckpointRequest>,
+    ) -> Result<Response<CheckpointResponse>, Status> {
+        let _timer = metrics::rpc_timer("checkpoint");
+        self.peers.authorize(&request, "checkpoint")?;
+        let _inflight = self.track().await?;
+        let req = request.get_ref();
+        let path = Path::new(&req.path);
+        if !path.is_absolute() {
+            return Err(Status::invalid_argument(format!(
+                "checkpoint path {:?} of sandbox {} is not absolute",
+                req.path, req.sandbox_id
+            )));
+        }
+        info!("checkpoint sandbox {} to {}", req.sandbox_id, req.path);
+        // never create the dir for a sandbox not found
+        self.sandboxer.sandbox(&req.sandbox_id).await?;
+        create_dir_all(path).await.map_err(Error::from)?;
+        self.sandboxer.checkpoint(&req.sandbox_id, path).await?;
+        info!("sandbox {} checkpointed to {}", req.sandbox_id, req.path);
+        Ok(Response::new(CheckpointResponse {}))
+    }
+}
+
+/// Marks a sandbox id as being created until dropped.
+struct Creating<'a> {
+    ids: &'a std::sync::Mutex<HashSet<String>>,
+    id: String,
+}
+
+impl<'a> Creating<'a> {
+    fn new(ids: &'a std::sync::Mutex<HashSet<String>>, id: &str) -> Result<Self, Status> {
+        if !ids.lock().unwrap().insert(id.to_string()) {
+            return Err(Status::already_exists(format!(
+                "sandbox {} is being created",
+                id
+            )));
+        }
+        Ok(Self {
+            ids,
+            id: id.to_string(),
+        })
+    }
+}
+
+impl Drop for Creating<'_> {
+    fn drop(&mut self) {
+        self.ids.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Address and version of the task API of the sandbox. The version is only negotiated with
+/// a containerd identified as 2.x, others, such as forks of containerd 1.7 dialing the
+/// address, always get [`TASK_API_VERSION`].
+///
+/// The address set by the sandboxer is returned as is, a malformed one is only logged, as
+/// failing the start or status of a running sandbox for it helps nobody.
+fn task_api<S: Sandbox>(id: &str, sandbox: &S, data: &SandboxData) -> (String, u32) {
+    if data.api_version == ApiVersion::V1_7 {
+        return (data.task_address.clone(), TASK_API_VERSION);
+    }
+    if !data.task_address.is_empty() {
+        if let Err(e) = data.task_address.parse::<TaskAddress>() {
+            warn!("task address of sandbox {}: {}", id, e);
+        }
+    }
+    let version = negotiate(&sandbox.task_api_versions(), CONTAINERD_TASK_API_VERSIONS)
+        .unwrap_or_else(|e| {
+            warn!("sandbox {}: {}, fall back to {}", id, e, TASK_API_VERSION);
+            TASK_API_VERSION
+        });
+    (data.task_address.clone(), version)
+}
+
+/// Changes applied to the containers of a sandbox, in order to roll back.
+enum ContainerUndo {
+    Remove(String),
+    Restore(String, ContainerData),
+    Append(String, ContainerData),
+}
+
+/// Apply the diff of `tasks` against `old_tasks` to the containers of the sandbox, either
+/// all of the changes are applied or none of them.
+///
+/// Returns the data of added or modified containers, ids of removed containers, and the undo
+/// log to roll the changes back if the update of the sandbox fails later.
 async fn update_resources<S>(
     sandbox_id: &str,
     sb: &mut S,
-    tasks: TaskResources,
-    old_tasks: TaskResources,
-) -> Result<(), Status>
+    tasks: &TaskResources,
+    old_tasks: &TaskResources,
+) -> Result<(Vec<ContainerData>, Vec<String>, Vec<ContainerUndo>), Status>
 where
     S: Sandbox,
 {
-    for t in tasks.tasks.iter() {
-        let mut exist = false;
-        for ot in old_tasks.tasks.iter() {
-            if t.task_id == ot.task_id {
-                exist = true;
-                update_process_resource(sandbox_id, sb, &t.task_id, &t.processes, &ot.processes)
-                    .await?;
-            }
-        }
-        if !exist {
-            let container_data = ContainerData::new(t);
-            info!(
-                "append a container {:?} to sandbox {}",
-                container_data, sandbox_id
-            );
-            let opt: ContainerOption = ContainerOption::new(container_data);
-            sb.append_container(&t.task_id, opt).await?;
-        }
+    let diff = tasks.diff(old_tasks);
+    if diff.is_empty() {
+        return Ok((vec![], vec![], vec![]));
     }
-    for ot in old_tasks.tasks.iter() {
-        let mut exist = false;
-        for t in tasks.tasks.iter() {
-            if ot.task_id == t.task_id {
-                exist = true;
-            }
-        }
-        if !exist {
-            info!(
-                "remove container {} from sandbox {}",
-                ot.task_id, sandbox_id
+    let mut undo = vec![];
+    match apply_task_diff(sandbox_id, sb, &diff, &mut undo).await {
+        Ok((updated, removed)) => Ok((updated, removed, undo)),
+        Err(e) => {
+            warn!(
+                "failed to update containers of sandbox {}: {}, roll back",
+                sandbox_id, e
             );
-            sb.remove_container(&ot.task_id).await?;
+            rollback_containers(sandbox_id, sb, undo).await;
+            Err(e.into())
         }
     }
-    Ok(())
 }
 
-async fn update_process_resource<S>(
+async fn apply_task_diff<S>(
     sandbox_id: &str,
     sb: &mut S,
-    task_id: &str,
-    processes: &Vec<ProcessResource>,
-    old_processes: &Vec<ProcessResource>,
-) -> Result<(), Status>
+    diff: &TaskResourcesDiff<'_>,
+    undo: &mut Vec<ContainerUndo>,
+) -> crate::error::Result<(Vec<ContainerData>, Vec<String>)>
 where
     S: Sandbox,
 {
-    for p in processes.iter() {
-        let mut exist = false;
-        for op in old_processes.iter() {
-            if p.exec_id == op.exec_id {
-                exist = true;
+    let mut updated = vec![];
+    for t in diff.added.iter() {
+        let mut container_data = ContainerData::new(t);
+        container_data.processes = t.processes.iter().map(ProcessData::new).collect();
+        info!(
+            "append a container {:?} to sandbox {}",
+            container_data, sandbox_id
+        );
+        let opt = ContainerOption::new(container_data.clone());
+        sb.append_container(&t.task_id, opt).await?;
+        undo.push(ContainerUndo::Remove(t.task_id.clone()));
+        updated.push(container_data);
+    }
+    for d in diff.modified.iter() {
+        let task_id = &d.new.task_id;
+        let old_data = sb.container(task_id).await?.get_data()?;
+        let mut data = old_data.clone();
+        if d.changed {
+            info!("update container {} of sandbox {}", task_id, sandbox_id);
+            data.spec = d.new.spec.clone();
+            data.rootfs = d.new.rootfs.clone();
+            let terminal = old_data
+                .io
+                .as_ref()
+                .map(|io| io.terminal)
+                .unwrap_or_default();
+            data.io = Some(Io {
+                stdin: d.new.stdin.to_string(),
+                stdout: d.new.stdout.to_string(),
+                stderr: d.new.stderr.to_string(),
+                terminal,
+            });
+        }
+        for p in d.processes.removed.iter() {
+            info!(
+                "remove process {} from container {} of sandbox {}",
+                p.exec_id, task_id, sandbox_id
+            );
+            data.processes.retain(|x| x.id != p.exec_id);
+        }
+        for p in d.processes.modified.iter() {
+            info!(
+                "update process {} of container {} of sandbox {}",
+                p.exec_id, task_id, sandbox_id
+            );
+            let process_data = ProcessData::new(p);
+            match data.processes.iter_mut().find(|x| x.id == p.exec_id) {
+                Some(x) => *x = process_data,
+                None => data.processes.push(process_data),
             }
         }
-        if !exist {
-            let process_date = ProcessData::new(p);
+        for p in d.processes.added.iter() {
+            let process_data = ProcessData::new(p);
             info!(
                 "append a process {:?} to container {} of sandbox {}",
-                process_date, task_id, sandbox_id
+                process_data, task_id, sandbox_id
             );
-            let container = sb.container(task_id).await?;
-            let mut data = container.get_data()?;
-            data.processes.push(process_date);
-            let opt = ContainerOption::new(data);
-            sb.update_container(task_id, opt).await?;
-        }
-    }
-    for op in old_processes.iter() {
-        let mut exist = false;
-        for p in processes.iter() {
-            if op.exec_id == p.exec_id {
-                exist = true;
+            data.processes.push(process_data);
+        }
+        sb.update_container(task_id, ContainerOption::new(data.clone()))
+            .await?;
+        undo.push(ContainerUndo::Restore(task_id.clone(), old_data));
+        updated.push(data);
+    }
+    let mut removed = vec![];
+    for t in diff.removed.iter() {
+        info!("remove container {} from sandbox {}", t.task_id, sandbox_id);
+        let old_data = sb.container(&t.task_id).await?.get_data()?;
+        sb.remove_container(&t.task_id).await?;
+        undo.push(ContainerUndo::Append(t.task_id.clone(), old_data));
+        removed.push(t.task_id.clone());
+    }
+    Ok((updated, removed))
+}
+
+async fn rollback_containers<S>(sandbox_id: &str, sb: &mut S, undo: Vec<ContainerUndo>)
+where
+    S: Sandbox,
+{
+    for u in undo.into_iter().rev() {
+        let (id, res) = match u {
+            ContainerUndo::Remove(id) => {
+                let res = sb.remove_container(&id).await;
+                (id, res)
+            }
+            ContainerUndo::Restore(id, data) => {
+                let res = sb.update_container(&id, ContainerOption::new(data)).await;
+                (id, res)
+            }
+            ContainerUndo::Append(id, data) => {
+                let res = sb.append_container(&id, ContainerOption::new(data)).await;
+                (id, res)
             }
+        };
+        if let Err(e) = res {
+            warn!(
+                "failed to roll back container {} of sandbox {}: {}",
+                id, sandbox_id, e
+            );
         }
-        if !exist {
-            info!(
-                "remove process {} from container {} of sandbox {}",
-                op.exec_id, task_id, sandbox_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockSandboxer, Op, MOCK_PID};
+
+    fn controller(
+        name: &str,
+        sandboxer: MockSandboxer,
+    ) -> (SandboxController<MockSandboxer>, String) {
+        let dir = std::env::temp_dir()
+            .join(format!("sandbox-rpc-{}-{}", name, std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        (SandboxController::new(dir.clone(), sandboxer), dir)
+    }
+
+    fn create_request(id: &str) -> Request<ControllerCreateRequest> {
+        Request::new(ControllerCreateRequest {
+            sandbox_id: id.to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn start_request(id: &str) -> Request<ControllerStartRequest> {
+        Request::new(ControllerStartRequest {
+            sandbox_id: id.to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn stop_request(id: &str) -> Request<ControllerStopRequest> {
+        Request::new(ControllerStopRequest {
+            sandbox_id: id.to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn shutdown_request(id: &str) -> Request<ControllerShutdownRequest> {
+        Request::new(ControllerShutdownRequest {
+            sandbox_id: id.to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_lifecycle() {
+        let (c, dir) = controller("lifecycle", MockSandboxer::default());
+        c.create(create_request("sb1")).await.unwrap();
+        let err = c.create(create_request("sb1")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::AlreadyExists);
+        assert_eq!(c.sandboxer.count(Op::Create), 1);
+
+        let resp = c.start(start_request("sb1")).await.unwrap().into_inner();
+        assert_eq!(resp.pid, MOCK_PID);
+        let resp = c.start(start_request("sb1")).await.unwrap().into_inner();
+        assert_eq!(resp.pid, MOCK_PID);
+        assert_eq!(c.sandboxer.count(Op::Start), 1);
+
+        c.stop(stop_request("sb1")).await.unwrap();
+        c.stop(stop_request("sb1")).await.unwrap();
+        assert_eq!(c.sandboxer.count(Op::Stop), 1);
+        let err = c.start(start_request("sb1")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+
+        c.shutdown(shutdown_request("sb1")).await.unwrap();
+        c.shutdown(shutdown_request("sb1")).await.unwrap();
+        c.stop(stop_request("sb1")).await.unwrap();
+        assert!(!Path::new(&dir).join("sb1").exists());
+        tokio::fs::remove_dir_all(&dir).await.unwrap_or_default();
+    }
+
+    #[tokio::test]
+    async fn test_create_rollback() {
+        let sandboxer = MockSandboxer::default();
+        sandboxer.fail(Op::Create);
+        let (c, dir) = controller("create", sandboxer);
+        // a dir left by an interrupted create
+        let stale = Path::new(&dir).join("sb1").join("stale");
+        create_dir_all(&stale).await.unwrap();
+
+        assert!(c.create(create_request("sb1")).await.is_err());
+        assert!(!Path::new(&dir).join("sb1").exists());
+
+        c.sandboxer.clear_failures();
+        create_dir_all(&stale).await.unwrap();
+        c.create(create_request("sb1")).await.unwrap();
+        assert!(!stale.exists());
+        tokio::fs::remove_dir_all(&dir).await.unwrap_or_default();
+    }
+
+    #[tokio::test]
+    async fn test_update_rollback() {
+        let (c, dir) = controller("update", MockSandboxer::default());
+        c.create(create_request("sb1")).await.unwrap();
+        let tasks = serde_json::json!({"tasks": [{"task_id": "c1"}]});
+        let update_request = || {
+            let sandbox = crate::types::Sandbox {
+                extensions: [(
+                    "tasks".to_string(),
+                    prost_types::Any {
+                        type_url: String::new(),
+                        value: serde_json::to_vec(&tasks).unwrap(),
+                    },
+                )]
+                .into(),
+                ..Default::default()
+            };
+            Request::new(ControllerUpdateRequest {
+                sandbox_id: "sb1".to_string(),
+                sandbox: Some(sandbox),
+                fields: vec!["extensions.tasks".to_string()],
+                ..Default::default()
+            })
+        };
+        let sandbox_mutex = c.sandboxer.sandbox("sb1").await.unwrap();
+
+        c.sandboxer.fail(Op::Update);
+        assert!(c.update(update_request()).await.is_err());
+        assert!(sandbox_mutex.lock().await.containers.is_empty());
+
+        c.sandboxer.clear_failures();
+        c.update(update_request()).await.unwrap();
+        assert!(sandbox_mutex.lock().await.containers.contains_key("c1"));
+        tokio::fs::remove_dir_all(&dir).await.unwrap_or_default();
+    }
+
+    #[tokio::test]
+    async fn test_metrics_unimplemented() {
+        let (c, dir) = controller("metrics", MockSandboxer::default());
+        c.create(create_request("sb1")).await.unwrap();
+        let metrics_request = |id: &str| {
+            Request::new(ControllerMetricsRequest {
+                sandbox_id: id.to_string(),
+                ..Default::default()
+            })
+        };
+        let resp = c.metrics(metrics_request("sb1")).await.unwrap();
+        assert!(resp.get_ref().metrics.is_none());
+        let err = c.metrics(metrics_request("sb2")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+        tokio::fs::remove_dir_all(&dir).await.unwrap_or_default();
+    }
+
+    #[tokio::test]
+    async fn test_task_api() {
+        let (c, dir) = controller("task", MockSandboxer::default());
+        let mut req = create_request("sb1");
+        req.get_mut().sandboxer = "mock".to_string();
+        c.create(req).await.unwrap();
+        for address in ["ttrpc+hvsock:///run/sb1/kuasar.hvsock:1024", "malformed"] {
+            let sandbox = c.sandboxer.sandbox("sb1").await.unwrap();
+            sandbox.lock().await.data.task_address = address.to_string();
+            let resp = c.start(start_request("sb1")).await.unwrap().into_inner();
+            assert_eq!(
+                (resp.address.as_str(), resp.version),
+                (address, TASK_API_VERSION)
+            );
+            let status = Request::new(ControllerStatusRequest {
+                sandbox_id: "sb1".to_string(),
+                ..Default::default()
+            });
+            let resp = c.status(status).await.unwrap().into_inner();
+            assert_eq!(
+                (resp.address.as_str(), resp.version),
+                (address, TASK_API_VERSION)
             );
-            let container = sb.container(task_id).await?;
-            let mut data = container.get_data()?;
-            data.processes.retain(|x| x.id != op.exec_id);
-            let opt = ContainerOption::new(data);
-            sb.update_container(task_id, opt).await?;
         }
+        assert_eq!(c.sandboxer.count(Op::Stop), 0);
+        c.shutdown(shutdown_request("sb1")).await.unwrap();
+
+        // not identified as containerd 2.x, which still dials the address
+        c.create(create_request("sb2")).await.unwrap();
+        let sandbox = c.sandboxer.sandbox("sb2").await.unwrap();
+        sandbox.lock().await.data.task_address = "ttrpc+vsock://3:1024".to_string();
+        let resp = c.start(start_request("sb2")).await.unwrap().into_inner();
+        assert_eq!(resp.address, "ttrpc+vsock://3:1024");
+        assert_eq!(resp.version, TASK_API_VERSION);
+        c.shutdown(shutdown_request("sb2")).await.unwrap();
+        tokio::fs::remove_dir_all(&dir).await.unwrap_or_default();
+    }
+
+    #[tokio::test]
+    async fn test_start_rollback() {
+        let sandboxer = MockSandboxer::new().with_start_status(SandboxStatus::Created);
+        let (c, dir) = controller("start", sandboxer);
+        c.create(create_request("sb1")).await.unwrap();
+        assert!(c.start(start_request("sb1")).await.is_err());
+        // stopped forcibly, so that it can only be shut down
+        assert_eq!(c.sandboxer.count(Op::Stop), 1);
+        let err = c.start(start_request("sb1")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+
+        c.sandboxer.fail(Op::Delete);
+        assert!(c.shutdown(shutdown_request("sb1")).await.is_err());
+        c.sandboxer.clear_failures();
+        c.shutdown(shutdown_request("sb1")).await.unwrap();
+        tokio::fs::remove_dir_all(&dir).await.unwrap_or_default();
     }
-    Ok(())
 }
@@ -1,74 +1,95 @@
+//! Json model of the OCI runtime spec, converted to and from [`oci_spec::runtime::Spec`] by
+//! json, with the fields not modelled kept in the `extra` of structs.
+//!
+//! Breaking changes in 0.2.0:
+//! - `LinuxIDMapping::sieze` is renamed to `size`, `sieze` is still accepted in json.
+//! - `User::umask` is an `Option<u32>`, so that an unset umask is not taken as 0.
+//! - Most structs have a public `extra` field, which struct literals have to set.
+
 use std::collections::HashMap;
+use std::convert::TryFrom;
 
 use anyhow::anyhow;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_json::Value;
 
-use crate::error::Result;
+use crate::error::{Error, Result};
 use prost_types::Any;
 
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct JsonSpec {
     #[serde(rename = "ociVersion")]
     pub version: String,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub process: Option<Process>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub root: Option<Root>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty", default)]
     pub hostname: String,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub mounts: Vec<Mount>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub hooks: Option<Hooks>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty", default)]
     pub annotations: HashMap<String, String>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub linux: Option<Linux>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub vm: Option<VM>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub solaris: Option<Solaris>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub windows: Option<Windows>,
+    /// Fields not modelled here, kept so that specs are converted losslessly.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Default, Clone)]
 pub struct Root {
     #[serde(default)]
     pub path: String,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
     pub readonly: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Mount {
     #[serde(default)]
     pub destination: String,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty", default)]
     pub r#type: String,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty", default)]
     pub source: String,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub options: Vec<String>,
+    /// Fields not modelled here, kept so that specs are converted losslessly.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct Linux {
-    #[serde(rename = "uidMappings", default)]
+    #[serde(rename = "uidMappings", skip_serializing_if = "Vec::is_empty", default)]
     pub uid_mappings: Vec<LinuxIDMapping>,
-    #[serde(rename = "gidMappings", default)]
+    #[serde(rename = "gidMappings", skip_serializing_if = "Vec::is_empty", default)]
     pub gid_mappings: Vec<LinuxIDMapping>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty", default)]
     pub sysctl: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub resources: Option<LinuxResources>,
-    #[serde(rename = "cgroupsPath", default)]
+    #[serde(
+        rename = "cgroupsPath",
+        skip_serializing_if = "String::is_empty",
+        default
+    )]
     pub cgroups_path: String,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub namespaces: Vec<LinuxNamespace>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub devices: Vec<LinuxDevice>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub seccomp: Option<LinuxSeccomp>,
     #[serde(
         rename = "rootfsPropagation",
@@ -76,9 +97,13 @@ pub struct Linux {
         default
     )]
     pub rootfs_propagation: String,
-    #[serde(rename = "maskedPaths", default)]
+    #[serde(rename = "maskedPaths", skip_serializing_if = "Vec::is_empty", default)]
     pub masked_path: Vec<String>,
-    #[serde(rename = "readonlyPaths", default)]
+    #[serde(
+        rename = "readonlyPaths",
+        skip_serializing_if = "Vec::is_empty",
+        default
+    )]
     pub readonly_path: Vec<String>,
     #[serde(
         rename = "mountLabel",
@@ -86,8 +111,11 @@ pub struct Linux {
         default
     )]
     pub mount_label: String,
-    #[serde(rename = "intelRdt")]
+    #[serde(rename = "intelRdt", skip_serializing_if = "Option::is_none", default)]
     pub intel_rdt: Option<LinuxIntelRdt>,
+    /// Fields not modelled here, kept so that specs are converted losslessly.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -96,13 +124,14 @@ pub struct LinuxIDMapping {
     pub container_id: u32,
     #[serde(rename = "hostID", default)]
     pub host_id: u32,
-    #[serde(default)]
-    pub sieze: u32,
+    /// Renamed from `sieze` in 0.2.0.
+    #[serde(alias = "sieze", default)]
+    pub size: u32,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct LinuxResources {
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub devices: Vec<LinuxDeviceCgroup>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
     pub memory: Option<LinuxMemory>,
@@ -112,14 +141,21 @@ pub struct LinuxResources {
     pub pids: Option<LinuxPids>,
     #[serde(rename = "blockIO", skip_serializing_if = "Option::is_none")]
     pub block_io: Option<LinuxBlockIO>,
-    #[serde(rename = "hugepageLimits", default)]
+    #[serde(
+        rename = "hugepageLimits",
+        skip_serializing_if = "Vec::is_empty",
+        default
+    )]
     pub hugepage_limits: Vec<LinuxHugepageLimit>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
     pub network: Option<LinuxNetwork>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty", default)]
     pub rdma: HashMap<String, LinuxRdma>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
     pub files: Option<Files>,
+    /// Fields not modelled here, kept so that specs are converted losslessly.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -132,36 +168,62 @@ pub struct LinuxDeviceCgroup {
     pub major: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
     pub minor: Option<i64>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty", default)]
     pub access: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct LinuxMemory {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub reservation: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub swap: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub kernel: Option<u64>,
-    #[serde(rename = "kernelTCP")]
+    #[serde(rename = "kernelTCP", skip_serializing_if = "Option::is_none", default)]
     pub kernel_tcp: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub swappiness: Option<u64>,
-    #[serde(rename = "disableOOMKiller")]
+    #[serde(
+        rename = "disableOOMKiller",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
     pub disable_oom_killer: Option<bool>,
+    /// Fields not modelled here, kept so that specs are converted losslessly.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct LinuxCPU {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub shares: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub quota: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub period: Option<u64>,
-    #[serde(rename = "realtimeRuntime")]
+    #[serde(
+        rename = "realtimeRuntime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
     pub realtime_runtime: Option<i64>,
-    #[serde(rename = "realtimePeriod")]
+    #[serde(
+        rename = "realtimePeriod",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
     pub realtime_period: Option<u64>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty", default)]
     pub cpus: String,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty", default)]
     pub mems: String,
+    /// Fields not modelled here, kept so that specs are converted losslessly.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -172,19 +234,43 @@ pub struct LinuxPids {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LinuxBlockIO {
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub weight: Option<u16>,
-    #[serde(rename = "leafWeight", default)]
+    #[serde(
+        rename = "leafWeight",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
     pub leaf_weight: Option<u16>,
-    #[serde(rename = "weightDevice", default)]
+    #[serde(
+        rename = "weightDevice",
+        skip_serializing_if = "Vec::is_empty",
+        default
+    )]
     pub weight_device: Vec<LinuxWeightDevice>,
-    #[serde(rename = "throttleReadBpsDevice", default)]
+    #[serde(
+        rename = "throttleReadBpsDevice",
+        skip_serializing_if = "Vec::is_empty",
+        default
+    )]
     pub throttle_read_bps_device: Vec<LinuxThrottleDevice>,
-    #[serde(rename = "throttleWriteBpsDevice", default)]
+    #[serde(
+        rename = "throttleWriteBpsDevice",
+        skip_serializing_if = "Vec::is_empty",
+        default
+    )]
     pub throttle_write_bps_device: Vec<LinuxThrottleDevice>,
-    #[serde(rename = "throttleReadIOPSDevice", default)]
+    #[serde(
+        rename = "throttleReadIOPSDevice",
+        skip_serializing_if = "Vec::is_empty",
+        default
+    )]
     pub throttle_read_iops_device: Vec<LinuxThrottleDevice>,
-    #[serde(rename = "throttleWriteIOPSDevice", default)]
+    #[serde(
+        rename = "throttleWriteIOPSDevice",
+        skip_serializing_if = "Vec::is_empty",
+        default
+    )]
     pub throttle_write_iobs_device: Vec<LinuxThrottleDevice>,
 }
 
@@ -194,8 +280,13 @@ pub struct LinuxWeightDevice {
     pub major: i64,
     #[serde(default)]
     pub minor: i64,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub weight: Option<u16>,
-    #[serde(rename = "leafWeight")]
+    #[serde(
+        rename = "leafWeight",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
     pub leaf_weight: Option<u16>,
 }
 
@@ -219,15 +310,15 @@ pub struct LinuxHugepageLimit {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LinuxNetwork {
-    #[serde(rename = "classID", default)]
+    #[serde(rename = "classID", skip_serializing_if = "Option::is_none", default)]
     pub class_id: Option<u32>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub priorities: Vec<LinuxInterfacePriority>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LinuxInterfacePriority {
-    #[serde(rename = "classID", default)]
+    #[serde(alias = "classID", default)]
     pub name: String,
     #[serde(default)]
     pub priority: u32,
@@ -235,14 +326,23 @@ pub struct LinuxInterfacePriority {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LinuxRdma {
-    #[serde(rename = "hcaHandles", default)]
+    #[serde(
+        rename = "hcaHandles",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
     pub hca_handles: Option<u32>,
-    #[serde(rename = "hcaObjects", default)]
+    #[serde(
+        rename = "hcaObjects",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
     pub hca_objects: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Files {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub limit: Option<u64>,
 }
 
@@ -258,15 +358,17 @@ pub struct LinuxNamespace {
 pub struct LinuxDevice {
     #[serde(default)]
     pub path: String,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub r#type: Option<String>,
     #[serde(default)]
     pub major: i64,
     #[serde(default)]
     pub minor: i64,
-    #[serde(rename = "fileMode")]
+    #[serde(rename = "fileMode", skip_serializing_if = "Option::is_none", default)]
     pub file_mode: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub gid: Option<u32>,
 }
 
@@ -274,10 +376,13 @@ pub struct LinuxDevice {
 pub struct LinuxSeccomp {
     #[serde(rename = "defaultAction", default)]
     pub default_action: String,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub architectures: Vec<String>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub syscalls: Vec<LinuxSyscall>,
+    /// Fields not modelled here, kept so that specs are converted losslessly.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -285,8 +390,11 @@ pub struct LinuxSyscall {
     pub names: Vec<String>,
     #[serde(default)]
     pub action: String,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub args: Vec<LinuxSeccompArg>,
+    /// Fields not modelled here, kept so that specs are converted losslessly.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -297,18 +405,29 @@ pub struct LinuxSeccompArg {
     pub value: u64,
     #[serde(rename = "valueTwo", default)]
     pub value_two: u64,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty", default)]
     pub op: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LinuxIntelRdt {
-    #[serde(rename = "closID", default)]
+    #[serde(rename = "closID", skip_serializing_if = "String::is_empty", default)]
     pub clos_id: String,
-    #[serde(rename = "l3CacheSchema", default)]
+    #[serde(
+        rename = "l3CacheSchema",
+        skip_serializing_if = "String::is_empty",
+        default
+    )]
     pub l3cache_scheme: String,
-    #[serde(rename = "memBwSchema", default)]
+    #[serde(
+        rename = "memBwSchema",
+        skip_serializing_if = "String::is_empty",
+        default
+    )]
     pub membw_scheme: String,
+    /// Fields not modelled here, kept so that specs are converted losslessly.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -323,21 +442,27 @@ pub struct VM {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Windows {
-    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty", default)]
     pub dummy: String,
+    /// Fields not modelled here, kept so that specs are converted losslessly.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Solaris {
-    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty", default)]
     pub dummy: String,
+    /// Fields not modelled here, kept so that specs are converted losslessly.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct VMHypervisor {
     #[serde(default)]
     pub path: String,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub parameters: Vec<String>,
 }
 
@@ -345,9 +470,9 @@ pub struct VMHypervisor {
 pub struct VMKernel {
     #[serde(default)]
     pub path: String,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub parameters: Vec<String>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty", default)]
     pub initrd: String,
 }
 
@@ -361,22 +486,37 @@ pub struct VMImage {
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Process {
-    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
     pub terminal: bool,
+    #[serde(
+        rename = "consoleSize",
+        alias = "console_size",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
     pub console_size: Option<Box>,
     #[serde(default)]
     pub user: User,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub args: Vec<String>,
-    #[serde(rename = "commandLine", default)]
+    #[serde(
+        rename = "commandLine",
+        skip_serializing_if = "String::is_empty",
+        default
+    )]
     pub command_line: String,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub env: Vec<String>,
     pub cwd: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub capabilities: Option<LinuxCapabilities>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub rlimits: Vec<POSIXRlimit>,
-    #[serde(rename = "noNewPrivileges", default)]
+    #[serde(
+        rename = "noNewPrivileges",
+        skip_serializing_if = "std::ops::Not::not",
+        default
+    )]
     pub no_new_privileges: bool,
     #[serde(
         rename = "apparmorProfile",
@@ -384,10 +524,21 @@ pub struct Process {
         default
     )]
     pub apparmor_profile: String,
-    #[serde(rename = "oomScoreAdj")]
+    #[serde(
+        rename = "oomScoreAdj",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
     pub oom_score_adj: Option<i32>,
-    #[serde(rename = "selinuxLabel", default)]
+    #[serde(
+        rename = "selinuxLabel",
+        skip_serializing_if = "String::is_empty",
+        default
+    )]
     pub selinux_label: String,
+    /// Fields not modelled here, kept so that specs are converted losslessly.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -402,25 +553,30 @@ pub struct User {
     pub uid: u32,
     #[serde(default)]
     pub gid: u32,
-    #[serde(default)]
-    pub umask: u32,
-    #[serde(rename = "additionalGids", default)]
+    /// A `u32` defaulting to 0 before 0.2.0.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub umask: Option<u32>,
+    #[serde(
+        rename = "additionalGids",
+        skip_serializing_if = "Vec::is_empty",
+        default
+    )]
     pub additional_gids: Vec<u32>,
-    #[serde(rename = "username", default)]
+    #[serde(rename = "username", skip_serializing_if = "String::is_empty", default)]
     pub user_name: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct LinuxCapabilities {
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub bounding: Vec<String>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub effective: Vec<String>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub inheritable: Vec<String>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub permitted: Vec<String>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub ambient: Vec<String>,
 }
 
@@ -436,23 +592,27 @@ pub struct POSIXRlimit {
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Hooks {
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub prestart: Vec<Hook>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub poststart: Vec<Hook>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub poststop: Vec<Hook>,
+    /// Fields not modelled here, kept so that specs are converted losslessly.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Hook {
     #[serde(default)]
     pub path: String,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub args: Vec<String>,
-    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub env: Vec<String>,
-    #[serde(default)]
+    /// Timeout in seconds, which is unset if zero.
+    #[serde(skip_serializing_if = "is_zero", default)]
     pub timeout: i64,
 }
 
@@ -464,7 +624,7 @@ impl Process {
             user: User {
                 uid: 0,
                 gid: 0,
-                umask: 0,
+                umask: None,
                 additional_gids: vec![],
                 user_name: "".to_string(),
             },
@@ -478,19 +638,21 @@ impl Process {
             apparmor_profile: "".to_string(),
             oom_score_adj: None,
             selinux_label: "".to_string(),
+            extra: HashMap::new(),
         }
     }
 }
 
-const CRI_CONTAINERD_CONTAINER_TYPE_KEY: &'static str = "io.kubernetes.cri.container-type";
+pub(crate) const CRI_CONTAINERD_CONTAINER_TYPE_KEY: &'static str =
+    "io.kubernetes.cri.container-type";
 const CRIO_CONTAINER_TYPE_KEY: &'static str = "io.kubernetes.cri-o.ContainerType";
 const DOCKERSHIM_CONTAINER_TYPE_KEY: &'static str = "io.kubernetes.docker.type";
 
-const CONTAINER_TYPE_SANDBOX: &'static str = "sandbox";
+pub(crate) const CONTAINER_TYPE_SANDBOX: &'static str = "sandbox";
 const CONTAINER_TYPE_PODSANDBOX: &'static str = "podsandbox";
 const CONTAINER_TYPE_CONTAINER: &'static str = "container";
 
-const CRI_CONTAINERD_SANDBOX_ID_KEY: &'static str = "io.kubernetes.cri.sandbox-id";
+pub(crate) const CRI_CONTAINERD_SANDBOX_ID_KEY: &'static str = "io.kubernetes.cri.sandbox-id";
 const CRIO_SANDBOX_ID_KEY: &'static str = "io.kubernetes.cri-o.SandboxID";
 const DOCKERSHIM_SANDBOX_ID_KEY: &'static str = "io.kubernetes.sandbox.id";
 
@@ -556,6 +718,7 @@ impl From<&crate::types::Mount> for Mount {
             r#type: m.r#type.to_string(),
             source: m.source.to_string(),
             options: m.options.clone(),
+            ..Default::default()
         };
     }
 }
@@ -571,9 +734,137 @@ impl From<&Mount> for crate::types::Mount {
     }
 }
 
+impl JsonSpec {
+    /// Add the mount, which replaces the mount at the same destination if there is one.
+    pub fn add_mount(&mut self, mount: Mount) {
+        match self
+            .mounts
+            .iter_mut()
+            .find(|m| m.destination == mount.destination)
+        {
+            Some(m) => *m = mount,
+            None => self.mounts.push(mount),
+        }
+    }
+
+    /// Set the annotation, returning the previous value of the key.
+    pub fn set_annotation(&mut self, key: &str, value: &str) -> Option<String> {
+        self.annotations.insert(key.to_string(), value.to_string())
+    }
+
+    /// Merge the resources into those of the spec, see [`LinuxResources::merge`].
+    pub fn merge_resources(&mut self, resources: &LinuxResources) {
+        let linux = self.linux.get_or_insert_with(Linux::default);
+        linux
+            .resources
+            .get_or_insert_with(LinuxResources::default)
+            .merge(resources);
+    }
+}
+
+impl LinuxResources {
+    /// Overwrite the resources with those set in `other`, the limits of memory and cpu are
+    /// merged one by one, and any other set field replaces the one of self as a whole.
+    pub fn merge(&mut self, other: &LinuxResources) {
+        if !other.devices.is_empty() {
+            self.devices = other.devices.clone();
+        }
+        if let Some(m) = &other.memory {
+            self.memory
+                .get_or_insert_with(LinuxMemory::default)
+                .merge(m);
+        }
+        if let Some(c) = &other.cpu {
+            self.cpu.get_or_insert_with(LinuxCPU::default).merge(c);
+        }
+        if other.pids.is_some() {
+            self.pids = other.pids.clone();
+        }
+        if other.block_io.is_some() {
+            self.block_io = other.block_io.clone();
+        }
+        if !other.hugepage_limits.is_empty() {
+            self.hugepage_limits = other.hugepage_limits.clone();
+        }
+        if other.network.is_some() {
+            self.network = other.network.clone();
+        }
+        self.rdma.extend(other.rdma.clone());
+        if other.files.is_some() {
+            self.files = other.files.clone();
+        }
+        self.extra.extend(other.extra.clone());
+    }
+}
+
+impl LinuxMemory {
+    fn merge(&mut self, other: &LinuxMemory) {
+        merge_option(&mut self.limit, &other.limit);
+        merge_option(&mut self.reservation, &other.reservation);
+        merge_option(&mut self.swap, &other.swap);
+        merge_option(&mut self.kernel, &other.kernel);
+        merge_option(&mut self.kernel_tcp, &other.kernel_tcp);
+        merge_option(&mut self.swappiness, &other.swappiness);
+        merge_option(&mut self.disable_oom_killer, &other.disable_oom_killer);
+        self.extra.extend(other.extra.clone());
+    }
+}
+
+impl LinuxCPU {
+    fn merge(&mut self, other: &LinuxCPU) {
+        merge_option(&mut self.shares, &other.shares);
+        merge_option(&mut self.quota, &other.quota);
+        merge_option(&mut self.period, &other.period);
+        merge_option(&mut self.realtime_runtime, &other.realtime_runtime);
+        merge_option(&mut self.realtime_period, &other.realtime_period);
+        if !other.cpus.is_empty() {
+            self.cpus = other.cpus.clone();
+        }
+        if !other.mems.is_empty() {
+            self.mems = other.mems.clone();
+        }
+        self.extra.extend(other.extra.clone());
+    }
+}
+
+fn merge_option<T: Clone>(to: &mut Option<T>, from: &Option<T>) {
+    if from.is_some() {
+        *to = from.clone();
+    }
+}
+
+fn is_zero(v: &i64) -> bool {
+    *v == 0
+}
+
+/// Converted by json, with the fields unknown to [`JsonSpec`] kept in the `extra` of structs.
+impl TryFrom<&JsonSpec> for oci_spec::runtime::Spec {
+    type Error = Error;
+
+    fn try_from(spec: &JsonSpec) -> Result<Self> {
+        let value = serde_json::to_value(spec)
+            .map_err(|e| anyhow!("failed to marshal spec to json: {}", e))?;
+        serde_json::from_value(value)
+            .map_err(|e| Error::InvalidArgument(format!("invalid oci spec: {}", e)))
+    }
+}
+
+impl TryFrom<&oci_spec::runtime::Spec> for JsonSpec {
+    type Error = Error;
+
+    fn try_from(spec: &oci_spec::runtime::Spec) -> Result<Self> {
+        let value = serde_json::to_value(spec)
+            .map_err(|e| anyhow!("failed to marshal oci spec to json: {}", e))?;
+        serde_json::from_value(value)
+            .map_err(|e| Error::InvalidArgument(format!("invalid spec: {}", e)))
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::spec::{Box, JsonSpec, Process, User};
+    use std::convert::TryFrom;
+
+    use crate::spec::{Box, JsonSpec, LinuxCPU, LinuxMemory, LinuxResources, Mount, Process, User};
 
     #[test]
     fn test_process() {
@@ -586,7 +877,7 @@ mod tests {
             user: User {
                 uid: 1000,
                 gid: 1000,
-                umask: 0,
+                umask: None,
                 additional_gids: vec![2000],
                 user_name: "myusername".to_string(),
             },
@@ -600,6 +891,7 @@ mod tests {
             apparmor_profile: "".to_string(),
             oom_score_adj: None,
             selinux_label: "".to_string(),
+            extra: Default::default(),
         };
 
         let result = serde_json::to_string(&p).unwrap();
@@ -849,4 +1141,123 @@ mod tests {
             "/k8s.io/de9e81f4e553d095154fb34ddcb9f8812c507cc142bc3752979dfcc56a976859"
         );
     }
+
+    const FULL_SPEC: &str = r#"
+{
+  "ociVersion": "1.1.0",
+  "process": {
+    "user": {"uid": 0, "gid": 0, "umask": 18},
+    "args": ["/pause"],
+    "cwd": "/",
+    "consoleSize": {"height": 24, "width": 80},
+    "ioPriority": {"class": "IOPRIO_CLASS_RT", "priority": 1}
+  },
+  "root": {"path": "rootfs"},
+  "hostname": "sb1",
+  "domainname": "cluster.local",
+  "mounts": [
+    {"destination": "/proc", "type": "proc", "source": "proc"},
+    {
+      "destination": "/data",
+      "type": "bind",
+      "source": "/var/data",
+      "options": ["rbind"],
+      "uidMappings": [{"containerID": 0, "hostID": 1000, "size": 1}]
+    }
+  ],
+  "hooks": {
+    "createRuntime": [{"path": "/bin/hook", "args": ["hook", "create"]}],
+    "poststop": [{"path": "/bin/hook", "timeout": 5}]
+  },
+  "linux": {
+    "uidMappings": [{"containerID": 0, "hostID": 1000, "size": 65536}],
+    "resources": {
+      "memory": {"limit": 1048576, "checkBeforeUpdate": true},
+      "cpu": {"shares": 2, "burst": 1000},
+      "unified": {"memory.high": "1048576"}
+    },
+    "cgroupsPath": "/k8s.io/sb1",
+    "namespaces": [{"type": "network", "path": "/var/run/netns/sb1"}],
+    "seccomp": {
+      "defaultAction": "SCMP_ACT_ERRNO",
+      "flags": ["SECCOMP_FILTER_FLAG_LOG"],
+      "syscalls": [{"names": ["read"], "action": "SCMP_ACT_ALLOW", "errnoRet": 1}]
+    },
+    "personality": {"domain": "LINUX32"}
+  }
+}"#;
+
+    #[test]
+    fn test_spec_extra_fields() {
+        let original: serde_json::Value = serde_json::from_str(FULL_SPEC).unwrap();
+        let spec: JsonSpec = serde_json::from_str(FULL_SPEC).unwrap();
+        assert_eq!(spec.linux.as_ref().unwrap().uid_mappings[0].size, 65536);
+        assert_eq!(spec.process.as_ref().unwrap().user.umask, Some(18));
+        assert_eq!(serde_json::to_value(&spec).unwrap(), original);
+    }
+
+    #[test]
+    fn test_oci_spec_conversion() {
+        // compared with the original json, so that a field dropped by oci-spec is not hidden
+        // by dropping it on both sides
+        let original: serde_json::Value = serde_json::from_str(FULL_SPEC).unwrap();
+        let spec: JsonSpec = serde_json::from_str(FULL_SPEC).unwrap();
+        assert_eq!(serde_json::to_value(&spec).unwrap(), original);
+
+        // the only field unknown to oci-spec is the id mappings of mounts
+        let mut known = original.clone();
+        let mount = known["mounts"][1].as_object_mut().unwrap();
+        assert!(mount.remove("uidMappings").is_some());
+        let oci = oci_spec::runtime::Spec::try_from(&spec).unwrap();
+        assert_eq!(serde_json::to_value(&oci).unwrap(), known);
+        let spec = JsonSpec::try_from(&oci).unwrap();
+        assert_eq!(spec.extra["domainname"], "cluster.local");
+        assert_eq!(serde_json::to_value(&spec).unwrap(), known);
+    }
+
+    #[test]
+    fn test_spec_mutation() {
+        let mut spec: JsonSpec = serde_json::from_str(FULL_SPEC).unwrap();
+        spec.add_mount(Mount {
+            destination: "/data".to_string(),
+            r#type: "tmpfs".to_string(),
+            ..Default::default()
+        });
+        spec.add_mount(Mount {
+            destination: "/dev/shm".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(spec.mounts.len(), 3);
+        assert_eq!(spec.mounts[1].r#type, "tmpfs");
+        assert!(spec.mounts[1].extra.is_empty());
+
+        assert_eq!(spec.set_annotation("a", "1"), None);
+        assert_eq!(spec.set_annotation("a", "2"), Some("1".to_string()));
+        assert_eq!(spec.annotations["a"], "2");
+
+        spec.merge_resources(&LinuxResources {
+            memory: Some(LinuxMemory {
+                swap: Some(2097152),
+                ..Default::default()
+            }),
+            cpu: Some(LinuxCPU {
+                quota: Some(20000),
+                period: Some(100000),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let resources = spec.linux.unwrap().resources.unwrap();
+        let memory = resources.memory.unwrap();
+        assert_eq!(memory.limit, Some(1048576));
+        assert_eq!(memory.swap, Some(2097152));
+        assert_eq!(memory.extra["checkBeforeUpdate"], true);
+        let cpu = resources.cpu.unwrap();
+        assert_eq!((cpu.shares, cpu.quota), (Some(2), Some(20000)));
+        assert_eq!(resources.extra["unified"]["memory.high"], "1048576");
+
+        let mut spec = JsonSpec::default();
+        spec.merge_resources(&LinuxResources::default());
+        assert!(spec.linux.unwrap().resources.is_some());
+    }
 }
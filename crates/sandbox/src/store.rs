@@ -0,0 +1,215 @@
+//! Durable state of sandboxes, so that they can be recovered after a restart of the sandboxer.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::data::{ContainerData, SandboxData};
+use crate::error::Result;
+
+/// Directory in the base dir of a sandbox where [`FileStore`] keeps the state.
+pub const STATE_DIR: &str = "state";
+const SANDBOX_FILE_NAME: &str = "sandbox.json";
+const CONTAINERS_DIR: &str = "containers";
+
+/// A sandbox and its containers loaded from a [`SandboxStore`].
+#[derive(Clone, Debug, Default)]
+pub struct SandboxState {
+    pub sandbox: SandboxData,
+    pub containers: Vec<ContainerData>,
+}
+
+/// Storage of the state of sandboxes, which the controller saves on every change of a sandbox.
+#[async_trait]
+pub trait SandboxStore: Send + Sync {
+    async fn save_sandbox(&self, data: &SandboxData) -> Result<()>;
+    async fn save_container(&self, sandbox_id: &str, data: &ContainerData) -> Result<()>;
+    async fn remove_container(&self, sandbox_id: &str, id: &str) -> Result<()>;
+    async fn remove_sandbox(&self, sandbox_id: &str) -> Result<()>;
+    /// Load all the sandboxes in the store.
+    async fn load(&self) -> Result<Vec<SandboxState>>;
+}
+
+/// A [`SandboxStore`] keeping the state as json files in the base dir of every sandbox:
+///
+/// ```text
+/// <dir>/<sandbox id>/state/sandbox.json
+/// <dir>/<sandbox id>/state/containers/<container id>.json
+/// ```
+#[derive(Clone, Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn state_dir(&self, sandbox_id: &str) -> PathBuf {
+        self.dir.join(sandbox_id).join(STATE_DIR)
+    }
+
+    fn container_file(&self, sandbox_id: &str, id: &str) -> PathBuf {
+        self.state_dir(sandbox_id)
+            .join(CONTAINERS_DIR)
+            .join(format!("{}.json", id))
+    }
+
+    async fn load_sandbox(&self, sandbox_id: &str) -> Result<Option<SandboxState>> {
+        let state_dir = self.state_dir(sandbox_id);
+        let sandbox = match read_json::<SandboxData>(&state_dir.join(SANDBOX_FILE_NAME)).await? {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let mut containers = vec![];
+        let mut entries = match tokio::fs::read_dir(state_dir.join(CONTAINERS_DIR)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok(Some(SandboxState {
+                    sandbox,
+                    containers,
+                }))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            // skip the temp files left by a crash in the middle of write_json
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            if let Some(c) = read_json::<ContainerData>(&path).await? {
+                containers.push(c);
+            }
+        }
+        Ok(Some(SandboxState {
+            sandbox,
+            containers,
+        }))
+    }
+}
+
+#[async_trait]
+impl SandboxStore for FileStore {
+    async fn save_sandbox(&self, data: &SandboxData) -> Result<()> {
+        let path = self.state_dir(&data.id).join(SANDBOX_FILE_NAME);
+        write_json(&path, data).await
+    }
+
+    async fn save_container(&self, sandbox_id: &str, data: &ContainerData) -> Result<()> {
+        write_json(&self.container_file(sandbox_id, &data.id), data).await
+    }
+
+    async fn remove_container(&self, sandbox_id: &str, id: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.container_file(sandbox_id, id)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn remove_sandbox(&self, sandbox_id: &str) -> Result<()> {
+        match tokio::fs::remove_dir_all(self.state_dir(sandbox_id)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn load(&self) -> Result<Vec<SandboxState>> {
+        let mut sandboxes = vec![];
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(sandboxes),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+            let id = entry.file_name().to_string_lossy().to_string();
+            // a broken sandbox should not stop the others from being recovered
+            match self.load_sandbox(&id).await {
+                Ok(Some(s)) => sandboxes.push(s),
+                Ok(None) => warn!("no state of sandbox {} in {}", id, self.dir.display()),
+                Err(e) => warn!("failed to load state of sandbox {}: {}", id, e),
+            }
+        }
+        Ok(sandboxes)
+    }
+}
+
+async fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    let content = match tokio::fs::read(path).await {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let data = serde_json::from_slice(&content)
+        .map_err(|e| anyhow!("failed to parse {}: {}", path.display(), e))?;
+    Ok(Some(data))
+}
+
+/// Write by renaming a temp file, so that a crash never leaves a partially written state.
+async fn write_json<T: Serialize>(path: &Path, data: &T) -> Result<()> {
+    let content = serde_json::to_vec(data)
+        .map_err(|e| anyhow!("failed to marshal {}: {}", path.display(), e))?;
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    let mut f = tokio::fs::File::create(&tmp).await?;
+    f.write_all(&content).await?;
+    // the content must be on disk before the rename is, or a crash may leave an empty file
+    f.sync_all().await?;
+    drop(f);
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_store() {
+        let dir = std::env::temp_dir().join(format!("sandbox-store-{}", std::process::id()));
+        let store = FileStore::new(&dir);
+        let sandbox = SandboxData {
+            id: "sb1".to_string(),
+            netns: "/var/run/netns/sb1".to_string(),
+            ..Default::default()
+        };
+        store.save_sandbox(&sandbox).await.unwrap();
+        for id in ["c1", "c2"] {
+            let container = ContainerData {
+                id: id.to_string(),
+                ..Default::default()
+            };
+            store.save_container("sb1", &container).await.unwrap();
+        }
+        store.remove_container("sb1", "c1").await.unwrap();
+        // a temp file left by a crash while saving a container is skipped
+        let tmp = store.container_file("sb1", "c3").with_extension("json.tmp");
+        tokio::fs::write(tmp, "{").await.unwrap();
+        // a sandbox dir without state is skipped
+        tokio::fs::create_dir_all(dir.join("sb2")).await.unwrap();
+
+        let states = store.load().await.unwrap();
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].sandbox.netns, "/var/run/netns/sb1");
+        assert_eq!(states[0].containers.len(), 1);
+        assert_eq!(states[0].containers[0].id, "c2");
+
+        store.remove_sandbox("sb1").await.unwrap();
+        assert!(store.load().await.unwrap().is_empty());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+//! Addresses and versions of the task API of sandboxes, at which containerd 2.x connects to
+//! the shim or directly to the agent in the guest of a VM sandbox.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+use crate::rpc::TASK_API_VERSION;
+
+const TTRPC_UNIX_SCHEME: &str = "ttrpc+unix";
+const TTRPC_VSOCK_SCHEME: &str = "ttrpc+vsock";
+const TTRPC_HVSOCK_SCHEME: &str = "ttrpc+hvsock";
+const GRPC_VSOCK_SCHEME: &str = "grpc+vsock";
+const GRPC_HVSOCK_SCHEME: &str = "grpc+hvsock";
+
+/// Versions of the task API known by containerd, `containerd.task.v2` and `containerd.task.v3`.
+pub const CONTAINERD_TASK_API_VERSIONS: &[u32] = &[2, 3];
+
+/// Address of the task API, formatted as `ttrpc+unix:///path`, `ttrpc+vsock://cid:port`,
+/// `ttrpc+hvsock:///path:port`, `grpc+vsock://cid:port` or `grpc+hvsock:///path:port`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TaskAddress {
+    /// A unix socket file of the shim, or of the agent exposed on the host.
+    TtrpcUnix(PathBuf),
+    /// A vsock port of the agent in the guest.
+    TtrpcVsock {
+        cid: u32,
+        port: u32,
+    },
+    /// A port of a hybrid vsock, which is a unix socket file on the host forwarding to the
+    /// vsock port in the guest, such as those of Firecracker and Cloud Hypervisor.
+    TtrpcHvsock {
+        path: PathBuf,
+        port: u32,
+    },
+    GrpcVsock {
+        cid: u32,
+        port: u32,
+    },
+    GrpcHvsock {
+        path: PathBuf,
+        port: u32,
+    },
+}
+
+impl FromStr for TaskAddress {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason: &str| {
+            Error::InvalidArgument(format!("invalid task address {:?}: {}", s, reason))
+        };
+        let (scheme, rest) = s.split_once("://").ok_or_else(|| invalid("no scheme"))?;
+        let port = |p: &str| match p.parse::<u32>() {
+            Ok(p) if p > 0 => Ok(p),
+            _ => Err(invalid("invalid port")),
+        };
+        let path = |p: &str| {
+            let path = PathBuf::from(p);
+            if !path.is_absolute() {
+                return Err(invalid("path is not absolute"));
+            }
+            Ok(path)
+        };
+        let vsock = || {
+            let (cid, p) = rest.split_once(':').ok_or_else(|| invalid("no port"))?;
+            let cid = cid.parse::<u32>().map_err(|_| invalid("invalid cid"))?;
+            Ok((cid, port(p)?))
+        };
+        let hvsock = || {
+            let (p, port_str) = rest.rsplit_once(':').ok_or_else(|| invalid("no port"))?;
+            Ok((path(p)?, port(port_str)?))
+        };
+        match scheme {
+            TTRPC_UNIX_SCHEME => Ok(Self::TtrpcUnix(path(rest)?)),
+            TTRPC_VSOCK_SCHEME => vsock().map(|(cid, port)| Self::TtrpcVsock { cid, port }),
+            TTRPC_HVSOCK_SCHEME => hvsock().map(|(path, port)| Self::TtrpcHvsock { path, port }),
+            GRPC_VSOCK_SCHEME => vsock().map(|(cid, port)| Self::GrpcVsock { cid, port }),
+            GRPC_HVSOCK_SCHEME => hvsock().map(|(path, port)| Self::GrpcHvsock { path, port }),
+            _ => Err(invalid("unsupported scheme")),
+        }
+    }
+}
+
+impl fmt::Display for TaskAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TtrpcUnix(path) => write!(f, "{}://{}", TTRPC_UNIX_SCHEME, path.display()),
+            Self::TtrpcVsock { cid, port } => {
+                write!(f, "{}://{}:{}", TTRPC_VSOCK_SCHEME, cid, port)
+            }
+            Self::TtrpcHvsock { path, port } => {
+                write!(f, "{}://{}:{}", TTRPC_HVSOCK_SCHEME, path.display(), port)
+            }
+            Self::GrpcVsock { cid, port } => write!(f, "{}://{}:{}", GRPC_VSOCK_SCHEME, cid, port),
+            Self::GrpcHvsock { path, port } => {
+                write!(f, "{}://{}:{}", GRPC_HVSOCK_SCHEME, path.display(), port)
+            }
+        }
+    }
+}
+
+/// The highest version of the task API supported by both the sandbox and containerd,
+/// [`TASK_API_VERSION`] if the sandbox supports no version explicitly.
+pub fn negotiate(sandbox_versions: &[u32], containerd_versions: &[u32]) -> Result<u32> {
+    let sandbox_versions = if sandbox_versions.is_empty() {
+        &[TASK_API_VERSION]
+    } else {
+        sandbox_versions
+    };
+    sandbox_versions
+        .iter()
+        .filter(|v| containerd_versions.contains(v))
+        .max()
+        .copied()
+        .ok_or_else(|| {
+            Error::Unimplemented(format!(
+                "none of task API versions {:?} is supported by containerd, which supports {:?}",
+                sandbox_versions, containerd_versions
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_task_address() {
+        let cases = [
+            (
+                "ttrpc+unix:///run/sb1/task.sock",
+                TaskAddress::TtrpcUnix("/run/sb1/task.sock".into()),
+            ),
+            (
+                "ttrpc+vsock://3:1024",
+                TaskAddress::TtrpcVsock { cid: 3, port: 1024 },
+            ),
+            (
+                "ttrpc+hvsock:///run/sb1/kuasar.hvsock:1024",
+                TaskAddress::TtrpcHvsock {
+                    path: "/run/sb1/kuasar.hvsock".into(),
+                    port: 1024,
+                },
+            ),
+            (
+                "grpc+vsock://3:1024",
+                TaskAddress::GrpcVsock { cid: 3, port: 1024 },
+            ),
+            (
+                "grpc+hvsock:///run/sb1/kuasar.hvsock:1024",
+                TaskAddress::GrpcHvsock {
+                    path: "/run/sb1/kuasar.hvsock".into(),
+                    port: 1024,
+                },
+            ),
+        ];
+        for (s, addr) in cases {
+            assert_eq!(s.parse::<TaskAddress>().unwrap(), addr);
+            assert_eq!(addr.to_string(), s);
+        }
+        for s in [
+            "",
+            "/run/sb1/task.sock",
+            "ttrpc+unix://task.sock",
+            "ttrpc+vsock://3",
+            "ttrpc+vsock://3:0",
+            "grpc+hvsock:///run/sb1/kuasar.hvsock",
+            "unix:///run/sb1/task.sock",
+        ] {
+            assert!(s.parse::<TaskAddress>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(&[], CONTAINERD_TASK_API_VERSIONS).unwrap(), 2);
+        assert_eq!(negotiate(&[2, 3], CONTAINERD_TASK_API_VERSIONS).unwrap(), 3);
+        assert!(negotiate(&[3, 4], &[2]).is_err());
+    }
+}
@@ -0,0 +1,649 @@
+//! Helpers to test the controller and sandboxers built on this crate, enabled by the
+//! `test-utils` feature: an in-memory [`MockSandboxer`] recording calls and injecting errors,
+//! [`serve`] to run a sandboxer behind a temporary unix socket, and [`check_lifecycle`] as a
+//! conformance suite of a sandboxer.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tonic::codegen::Service;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tonic::Code;
+
+use crate::api::events::v1::events_client::EventsClient;
+use crate::api::extension::v1::sandbox_extension_client::SandboxExtensionClient;
+use crate::api::sandbox::v1::controller_client::ControllerClient;
+use crate::api::sandbox::v1::*;
+use crate::data::{ContainerData, SandboxData};
+use crate::error::{Error, Result};
+use crate::event::{Event, EventPublisher};
+use crate::signal::ExitSignal;
+use crate::{
+    run_with_options, Container, ContainerOption, RunOptions, Sandbox, SandboxOption,
+    SandboxStatus, Sandboxer,
+};
+
+/// Pid of the sandboxes started by [`MockSandboxer`].
+pub const MOCK_PID: u32 = 100;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Operations of [`MockSandboxer`], which are recorded and can be failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Op {
+    Create,
+    Start,
+    Update,
+    Stop,
+    Delete,
+    Recover,
+    Pause,
+    Resume,
+    Checkpoint,
+    Shutdown,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Call {
+    pub op: Op,
+    pub id: String,
+}
+
+pub struct MockContainer {
+    pub data: ContainerData,
+}
+
+impl Container for MockContainer {
+    fn get_data(&self) -> Result<ContainerData> {
+        Ok(self.data.clone())
+    }
+}
+
+pub struct MockSandbox {
+    pub data: SandboxData,
+    pub status: SandboxStatus,
+    pub containers: HashMap<String, MockContainer>,
+    pub exit_signal: Arc<ExitSignal>,
+}
+
+impl MockSandbox {
+    fn new(data: SandboxData) -> Self {
+        Self {
+            data,
+            status: SandboxStatus::Created,
+            containers: HashMap::new(),
+            exit_signal: Arc::new(ExitSignal::default()),
+        }
+    }
+}
+
+#[async_trait]
+impl Sandbox for MockSandbox {
+    type Container = MockContainer;
+
+    fn status(&self) -> Result<SandboxStatus> {
+        Ok(self.status.clone())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn container(&self, id: &str) -> Result<&Self::Container> {
+        self.containers
+            .get(id)
+            .ok_or_else(|| Error::NotFound(format!("container {}", id)))
+    }
+
+    async fn append_container(&mut self, id: &str, option: ContainerOption) -> Result<()> {
+        let container = MockContainer {
+            data: option.container,
+        };
+        self.containers.insert(id.to_string(), container);
+        Ok(())
+    }
+
+    async fn update_container(&mut self, id: &str, option: ContainerOption) -> Result<()> {
+        let container = self
+            .containers
+            .get_mut(id)
+            .ok_or_else(|| Error::NotFound(format!("container {}", id)))?;
+        container.data = option.container;
+        Ok(())
+    }
+
+    async fn remove_container(&mut self, id: &str) -> Result<()> {
+        self.containers.remove(id);
+        Ok(())
+    }
+
+    async fn exit_signal(&self) -> Result<Arc<ExitSignal>> {
+        Ok(self.exit_signal.clone())
+    }
+
+    fn get_data(&self) -> Result<SandboxData> {
+        Ok(self.data.clone())
+    }
+}
+
+type Failure = fn(&str) -> Error;
+
+#[derive(Default)]
+struct MockState {
+    sandboxes: Mutex<HashMap<String, Arc<Mutex<MockSandbox>>>>,
+    failures: std::sync::Mutex<HashMap<Op, Failure>>,
+    delays: std::sync::Mutex<HashMap<Op, Duration>>,
+    calls: std::sync::Mutex<Vec<Call>>,
+    start_status: std::sync::Mutex<Option<SandboxStatus>>,
+    events: std::sync::Mutex<Option<EventPublisher>>,
+}
+
+/// An in-memory [`Sandboxer`], clones of which share the sandboxes, so that a clone kept by
+/// a test can inspect the sandboxer served by the controller.
+#[derive(Clone, Default)]
+pub struct MockSandboxer {
+    state: Arc<MockState>,
+}
+
+impl MockSandboxer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Status of sandboxes after started, [`SandboxStatus::Running`] with [`MOCK_PID`] if not set.
+    pub fn with_start_status(self, status: SandboxStatus) -> Self {
+        *self.state.start_status.lock().unwrap() = Some(status);
+        self
+    }
+
+    /// Fail every later `op` with an internal error.
+    pub fn fail(&self, op: Op) {
+        self.fail_with(op, |id| {
+            anyhow!("injected failure of sandbox {}", id).into()
+        });
+    }
+
+    /// Fail every later `op` with the error returned by `f` for the sandbox id.
+    pub fn fail_with(&self, op: Op, f: Failure) {
+        self.state.failures.lock().unwrap().insert(op, f);
+    }
+
+    pub fn clear_failures(&self) {
+        self.state.failures.lock().unwrap().clear();
+    }
+
+    /// Delay every later `op` for `d` after it is called, e.g. to keep it in flight.
+    pub fn delay(&self, op: Op, d: Duration) {
+        self.state.delays.lock().unwrap().insert(op, d);
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.state.calls.lock().unwrap().clone()
+    }
+
+    /// Number of calls of `op`, including the failed ones.
+    pub fn count(&self, op: Op) -> usize {
+        let calls = self.state.calls.lock().unwrap();
+        calls.iter().filter(|c| c.op == op).count()
+    }
+
+    pub async fn contains(&self, id: &str) -> bool {
+        self.state.sandboxes.lock().await.contains_key(id)
+    }
+
+    async fn call(&self, op: Op, id: &str) -> Result<()> {
+        self.state.calls.lock().unwrap().push(Call {
+            op,
+            id: id.to_string(),
+        });
+        let delay = self.state.delays.lock().unwrap().get(&op).copied();
+        if let Some(d) = delay {
+            tokio::time::sleep(d).await;
+        }
+        match self.state.failures.lock().unwrap().get(&op) {
+            Some(f) => Err(f(id)),
+            None => Ok(()),
+        }
+    }
+
+    fn publish(&self, id: &str, event: Event) {
+        if let Some(events) = self.state.events.lock().unwrap().as_ref() {
+            events.publish(id, event);
+        }
+    }
+
+    async fn set_status(&self, id: &str, status: SandboxStatus) -> Result<()> {
+        self.sandbox(id).await?.lock().await.status = status;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sandboxer for MockSandboxer {
+    type Sandbox = MockSandbox;
+
+    async fn create(&self, id: &str, s: SandboxOption) -> Result<()> {
+        self.call(Op::Create, id).await?;
+        let mut sandboxes = self.state.sandboxes.lock().await;
+        if sandboxes.contains_key(id) {
+            return Err(Error::AlreadyExist(format!("sandbox {}", id)));
+        }
+        sandboxes.insert(
+            id.to_string(),
+            Arc::new(Mutex::new(MockSandbox::new(s.sandbox))),
+        );
+        *self.state.events.lock().unwrap() = Some(s.events);
+        self.publish(id, Event::Created);
+        Ok(())
+    }
+
+    async fn start(&self, id: &str) -> Result<()> {
+        self.call(Op::Start, id).await?;
+        let status = self.state.start_status.lock().unwrap().clone();
+        let status = status.unwrap_or(SandboxStatus::Running(MOCK_PID));
+        self.set_status(id, status).await?;
+        self.publish(id, Event::Ready(MOCK_PID));
+        Ok(())
+    }
+
+    async fn update(&self, id: &str, data: SandboxData) -> Result<()> {
+        self.call(Op::Update, id).await?;
+        self.sandbox(id).await?.lock().await.data = data;
+        Ok(())
+    }
+
+    async fn sandbox(&self, id: &str) -> Result<Arc<Mutex<Self::Sandbox>>> {
+        let sandboxes = self.state.sandboxes.lock().await;
+        sandboxes
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Error::NotFound(format!("sandbox {}", id)))
+    }
+
+    async fn stop(&self, id: &str, _force: bool) -> Result<()> {
+        self.call(Op::Stop, id).await?;
+        let sandbox_mutex = self.sandbox(id).await?;
+        let mut sandbox = sandbox_mutex.lock().await;
+        let exited_at = time::OffsetDateTime::now_utc().unix_timestamp_nanos();
+        sandbox.status = SandboxStatus::Stopped(0, exited_at);
+        sandbox.exit_signal.signal();
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        self.call(Op::Delete, id).await?;
+        let mut sandboxes = self.state.sandboxes.lock().await;
+        sandboxes
+            .remove(id)
+            .map(|_| ())
+            .ok_or_else(|| Error::NotFound(format!("sandbox {}", id)))
+    }
+
+    async fn recover(
+        &self,
+        id: &str,
+        opt: SandboxOption,
+        containers: Vec<ContainerData>,
+    ) -> Result<()> {
+        self.call(Op::Recover, id).await?;
+        let mut sandbox = MockSandbox::new(opt.sandbox);
+        if sandbox.data.started_at.is_some() {
+            sandbox.status = SandboxStatus::Running(MOCK_PID);
+        }
+        for c in containers {
+            sandbox
+                .containers
+                .insert(c.id.clone(), MockContainer { data: c });
+        }
+        let mut sandboxes = self.state.sandboxes.lock().await;
+        sandboxes.insert(id.to_string(), Arc::new(Mutex::new(sandbox)));
+        Ok(())
+    }
+
+    async fn pause(&self, id: &str) -> Result<()> {
+        self.call(Op::Pause, id).await?;
+        self.set_status(id, SandboxStatus::Paused).await?;
+        self.publish(id, Event::Paused);
+        Ok(())
+    }
+
+    async fn resume(&self, id: &str) -> Result<()> {
+        self.call(Op::Resume, id).await?;
+        self.set_status(id, SandboxStatus::Running(MOCK_PID)).await
+    }
+
+    async fn checkpoint(&self, id: &str, _path: &Path) -> Result<()> {
+        self.call(Op::Checkpoint, id).await?;
+        self.sandbox(id).await.map(|_| ())
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.call(Op::Shutdown, "").await
+    }
+}
+
+/// A sandboxer served by [`run_with_options`] on a unix socket in a temp dir, which is shut
+/// down and removed when dropped.
+pub struct TestServer {
+    /// The temp dir, with the socket `sandboxer.sock` and the working dir `sandboxes` in it.
+    pub dir: PathBuf,
+    channel: Channel,
+    stop: Option<oneshot::Sender<()>>,
+    handle: Option<JoinHandle<Result<()>>>,
+}
+
+impl TestServer {
+    pub fn client(&self) -> ControllerClient<Channel> {
+        ControllerClient::new(self.channel.clone())
+    }
+
+    pub fn extension_client(&self) -> SandboxExtensionClient<Channel> {
+        SandboxExtensionClient::new(self.channel.clone())
+    }
+
+    pub fn events_client(&self) -> EventsClient<Channel> {
+        EventsClient::new(self.channel.clone())
+    }
+
+    pub fn working_dir(&self) -> PathBuf {
+        self.dir.join("sandboxes")
+    }
+
+    /// Shut the sandboxer down gracefully, returning the result of [`run_with_options`].
+    pub async fn shutdown(mut self) -> Result<()> {
+        if let Some(stop) = self.stop.take() {
+            stop.send(()).unwrap_or_default();
+        }
+        match self.handle.take() {
+            Some(handle) => handle
+                .await
+                .map_err(|e| anyhow!("sandboxer panicked: {}", e))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.abort();
+        }
+        std::fs::remove_dir_all(&self.dir).unwrap_or_default();
+    }
+}
+
+/// Serve the sandboxer in a new temp dir, and connect to it.
+pub async fn serve<S>(sandboxer: S) -> Result<TestServer>
+where
+    S: Sandboxer + Sync + Send + 'static,
+{
+    static SEQ: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "sandbox-test-{}-{}",
+        std::process::id(),
+        SEQ.fetch_add(1, Ordering::SeqCst)
+    ));
+    tokio::fs::create_dir_all(&dir).await?;
+    let socket = dir.join("sandboxer.sock");
+    let working_dir = dir.join("sandboxes");
+
+    let (stop, stopped) = oneshot::channel::<()>();
+    let opts = RunOptions {
+        shutdown: Some(Box::pin(async move {
+            stopped.await.unwrap_or_default();
+        })),
+        ..Default::default()
+    };
+    let addr = socket.to_string_lossy().to_string();
+    let working = working_dir.to_string_lossy().to_string();
+    let handle =
+        tokio::spawn(
+            async move { run_with_options("test", &addr, &working, sandboxer, opts).await },
+        );
+
+    let channel = connect(&socket).await?;
+    Ok(TestServer {
+        dir,
+        channel,
+        stop: Some(stop),
+        handle: Some(handle),
+    })
+}
+
+/// Connect to the socket, retrying until the server listens on it.
+async fn connect(socket: &Path) -> Result<Channel> {
+    let deadline = tokio::time::Instant::now() + CONNECT_TIMEOUT;
+    loop {
+        let res = Endpoint::from_static("http://localhost")
+            .connect_with_connector(UnixConnector(socket.to_path_buf()))
+            .await;
+        match res {
+            Ok(channel) => return Ok(channel),
+            Err(e) if tokio::time::Instant::now() > deadline => {
+                return Err(anyhow!("failed to connect {}: {}", socket.display(), e).into())
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct UnixConnector(PathBuf);
+
+impl Service<Uri> for UnixConnector {
+    type Response = tokio::net::UnixStream;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = std::io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: Uri) -> Self::Future {
+        let path = self.0.clone();
+        Box::pin(async move { tokio::net::UnixStream::connect(path).await })
+    }
+}
+
+/// Conformance suite of the sandbox lifecycle, driving the sandboxer behind `client` through
+/// create, start, status, stop, wait and shutdown of a sandbox created by `req`, including the
+/// repeated calls which are expected to be idempotent.
+pub async fn check_lifecycle(
+    client: &mut ControllerClient<Channel>,
+    req: ControllerCreateRequest,
+) -> Result<()> {
+    let id = req.sandbox_id.clone();
+    let status = |e: tonic::Status| anyhow!("{}", e);
+    let expect = |cond: bool, msg: &str| -> Result<()> {
+        if cond {
+            Ok(())
+        } else {
+            Err(anyhow!("sandbox {}: {}", id, msg).into())
+        }
+    };
+
+    client.create(req.clone()).await.map_err(status)?;
+    let repeated = client.create(req).await;
+    expect(
+        matches!(&repeated, Err(e) if e.code() == Code::AlreadyExists),
+        "repeated create is not rejected as already exists",
+    )?;
+
+    let start = ControllerStartRequest {
+        sandbox_id: id.clone(),
+        ..Default::default()
+    };
+    let started = client
+        .start(start.clone())
+        .await
+        .map_err(status)?
+        .into_inner();
+    let again = client.start(start).await.map_err(status)?.into_inner();
+    expect(started.pid == again.pid, "repeated start changes the pid")?;
+
+    let status_req = ControllerStatusRequest {
+        sandbox_id: id.clone(),
+        ..Default::default()
+    };
+    let s = client
+        .status(status_req.clone())
+        .await
+        .map_err(status)?
+        .into_inner();
+    expect(s.state == "SANDBOX_READY", "not ready after start")?;
+
+    let stop = ControllerStopRequest {
+        sandbox_id: id.clone(),
+        ..Default::default()
+    };
+    client.stop(stop.clone()).await.map_err(status)?;
+    client.stop(stop).await.map_err(status)?;
+    let wait = ControllerWaitRequest {
+        sandbox_id: id.clone(),
+        ..Default::default()
+    };
+    let waited = tokio::time::timeout(CONNECT_TIMEOUT, client.wait(wait))
+        .await
+        .map_err(|_| anyhow!("wait is not returned after stop"))?;
+    waited.map_err(status)?;
+    let s = client
+        .status(status_req)
+        .await
+        .map_err(status)?
+        .into_inner();
+    expect(s.state == "SANDBOX_NOTREADY", "still ready after stop")?;
+
+    let shutdown = ControllerShutdownRequest {
+        sandbox_id: id.clone(),
+        ..Default::default()
+    };
+    client.shutdown(shutdown.clone()).await.map_err(status)?;
+    client.shutdown(shutdown).await.map_err(status)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::extension::v1::{CheckpointRequest, PauseRequest, ResumeRequest};
+
+    #[tokio::test]
+    async fn test_serve_mock_sandboxer() {
+        let sandboxer = MockSandboxer::new();
+        let server = serve(sandboxer.clone()).await.unwrap();
+        let req = ControllerCreateRequest {
+            sandbox_id: "sb1".to_string(),
+            ..Default::default()
+        };
+        check_lifecycle(&mut server.client(), req).await.unwrap();
+        assert!(!sandboxer.contains("sb1").await);
+        assert_eq!(sandboxer.count(Op::Create), 1);
+        assert_eq!(sandboxer.count(Op::Start), 1);
+
+        sandboxer.fail(Op::Create);
+        let req = ControllerCreateRequest {
+            sandbox_id: "sb2".to_string(),
+            ..Default::default()
+        };
+        let err = server.client().create(req).await.unwrap_err();
+        assert_eq!(err.code(), Code::Internal);
+        assert!(!server.working_dir().join("sb2").exists());
+
+        server.shutdown().await.unwrap();
+        assert_eq!(sandboxer.count(Op::Shutdown), 1);
+    }
+
+    #[tokio::test]
+    async fn test_extension() {
+        let sandboxer = MockSandboxer::new();
+        let server = serve(sandboxer.clone()).await.unwrap();
+        let mut client = server.client();
+        let req = ControllerCreateRequest {
+            sandbox_id: "sb1".to_string(),
+            ..Default::default()
+        };
+        client.create(req).await.unwrap();
+        let req = ControllerStartRequest {
+            sandbox_id: "sb1".to_string(),
+            ..Default::default()
+        };
+        client.start(req).await.unwrap();
+        let sandbox_mutex = sandboxer.sandbox("sb1").await.unwrap();
+        let mut extension = server.extension_client();
+
+        let pause = |id: &str| PauseRequest {
+            sandbox_id: id.to_string(),
+        };
+        extension.pause(pause("sb1")).await.unwrap();
+        let status = sandbox_mutex.lock().await.status.clone();
+        assert!(matches!(status, SandboxStatus::Paused));
+        let err = extension.pause(pause("sb2")).await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+
+        let resume = ResumeRequest {
+            sandbox_id: "sb1".to_string(),
+        };
+        extension.resume(resume).await.unwrap();
+        let status = sandbox_mutex.lock().await.status.clone();
+        assert!(matches!(status, SandboxStatus::Running(MOCK_PID)));
+
+        let checkpoint = |id: &str, path: &Path| CheckpointRequest {
+            sandbox_id: id.to_string(),
+            path: path.to_string_lossy().to_string(),
+        };
+        let err = extension
+            .checkpoint(checkpoint("sb1", Path::new("checkpoint")))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        let path = server.dir.join("checkpoint-sb2");
+        let err = extension
+            .checkpoint(checkpoint("sb2", &path))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+        assert!(!path.exists());
+        let path = server.dir.join("checkpoint-sb1");
+        extension
+            .checkpoint(checkpoint("sb1", &path))
+            .await
+            .unwrap();
+        assert!(path.is_dir());
+        assert_eq!(sandboxer.count(Op::Checkpoint), 1);
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let sandboxer = MockSandboxer::new();
+        sandboxer.delay(Op::Create, Duration::from_millis(300));
+        let server = serve(sandboxer.clone()).await.unwrap();
+        let mut client = server.client();
+        let create = tokio::spawn(async move {
+            let req = ControllerCreateRequest {
+                sandbox_id: "sb1".to_string(),
+                ..Default::default()
+            };
+            client.create(req).await
+        });
+        while sandboxer.count(Op::Create) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // the create in flight is answered before the server is dropped
+        server.shutdown().await.unwrap();
+        create.await.unwrap().unwrap();
+        assert!(sandboxer.contains("sb1").await);
+        assert_eq!(sandboxer.count(Op::Shutdown), 1);
+    }
+}
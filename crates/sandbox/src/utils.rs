@@ -1,30 +1,66 @@
+use std::cmp::Reverse;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::Duration;
+
 use anyhow::anyhow;
-use log::{debug, error};
+use log::debug;
 use nix::errno::Errno;
 use nix::NixPath;
 
-use crate::error::Error;
+use crate::mountinfo::{is_within, mounts};
 use crate::Result;
 
+const UNMOUNT_ATTEMPTS: usize = 3;
+const UNMOUNT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Unmount everything mounted at or under `parent_dir`, deepest first and the latest first of
+/// the mounts stacked on a path. Mounts failed to unmount are retried, and those still left
+/// are reported in the error.
 pub async fn cleanup_mounts(parent_dir: &str) -> Result<()> {
-    let parent_dir = if parent_dir.len() == 0 {
+    let parent_dir = if parent_dir.is_empty() {
         "."
     } else {
         parent_dir
     };
-    let mounts = tokio::fs::read_to_string("/proc/mounts")
-        .await
-        .map_err(Error::IO)?;
-    for line in mounts.lines() {
-        let fields = line.split_whitespace().collect::<Vec<&str>>();
-        let path = fields[1];
-        if path.starts_with(&parent_dir) {
-            unmount(path, libc::MNT_DETACH | libc::UMOUNT_NOFOLLOW).unwrap_or_else(|e| {
-                error!("failed to remove {}, err: {}", path, e);
-            });
+    // mount points in mountinfo are resolved, so must be the dir, e.g. /var/run to /run
+    let parent_dir = match tokio::fs::canonicalize(parent_dir).await {
+        Ok(p) => p,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let parent_dir = parent_dir.to_string_lossy();
+    let mut failures = vec![];
+    for attempt in 0..UNMOUNT_ATTEMPTS {
+        if attempt > 0 {
+            tokio::time::sleep(UNMOUNT_RETRY_INTERVAL).await;
+        }
+        let mut targets = mounts()
+            .await?
+            .into_iter()
+            .rev()
+            .map(|m| m.mount_point)
+            .filter(|p| is_within(p, &parent_dir))
+            .collect::<Vec<_>>();
+        targets.sort_by_key(|p| Reverse(Path::new(p).components().count()));
+        failures = targets
+            .iter()
+            .filter_map(|t| unmount(t, libc::MNT_DETACH | libc::UMOUNT_NOFOLLOW).err())
+            .collect::<Vec<_>>();
+        if failures.is_empty() {
+            return Ok(());
+        }
+        for e in &failures {
+            debug!("attempt {} to cleanup mounts: {}", attempt + 1, e);
         }
     }
-    Ok(())
+    let failures = failures.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+    Err(anyhow!(
+        "failed to cleanup mounts in {}: {}",
+        parent_dir,
+        failures.join("; ")
+    )
+    .into())
 }
 
 pub fn unmount(target: &str, flags: i32) -> Result<()> {
@@ -44,3 +80,55 @@ pub fn unmount(target: &str, flags: i32) -> Result<()> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use nix::mount::{mount, MsFlags};
+
+    use super::*;
+
+    fn mount_tmpfs(target: &Path) {
+        std::fs::create_dir_all(target).unwrap();
+        mount(
+            Some("tmpfs"),
+            target,
+            Some("tmpfs"),
+            MsFlags::empty(),
+            None::<&str>,
+        )
+        .unwrap();
+    }
+
+    async fn mounted(path: &Path) -> bool {
+        let path = path.to_string_lossy();
+        mounts()
+            .await
+            .unwrap()
+            .iter()
+            .any(|m| m.mount_point == path)
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_mounts() {
+        if !nix::unistd::Uid::effective().is_root() {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("sandbox-utils-{}", std::process::id()));
+        let base = dir.join("sb 1");
+        let sibling = dir.join("sb 12");
+        mount_tmpfs(&base.join("rootfs"));
+        mount_tmpfs(&base.join("rootfs").join("proc"));
+        mount_tmpfs(&base.join("rootfs"));
+        mount_tmpfs(&sibling);
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(&base, &link).unwrap();
+
+        cleanup_mounts(&link.to_string_lossy()).await.unwrap();
+        assert!(!mounted(&base.join("rootfs")).await);
+        assert!(mounted(&sibling).await);
+        cleanup_mounts(&sibling.to_string_lossy()).await.unwrap();
+        assert!(!mounted(&sibling).await);
+        std::fs::remove_dir_all(&dir).unwrap();
+        cleanup_mounts(&base.to_string_lossy()).await.unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+/*
+   Copyright The containerd Authors.
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+//! Compare forwarding container output through a userspace buffer with splice(2).
+//!
+//! A writer thread produces output as fast as it can into a pipe, like a log-heavy container,
+//! the copier under test forwards it to a second pipe, which is drained by a reader thread.
+//! The CPU time reported is the one spent by the copier thread, which is what the shim pays.
+//!
+//! Run with `cargo bench -p containerd-shim --bench splice`, the amount of data forwarded
+//! can be changed with `SPLICE_BENCH_MB`.
+
+// splice(2) and RUSAGE_THREAD are linux only
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        fs::File,
+        io::{Read, Write},
+        time::{Duration, Instant},
+    };
+
+    use containerd_shim::io::splice;
+
+    const BUF_SIZE: usize = 8 * 1024;
+    const WRITE_SIZE: usize = 64 * 1024;
+
+    fn pipe() -> (File, File) {
+        let (rd, wr) = nix::unistd::pipe().expect("create pipe");
+        (File::from(rd), File::from(wr))
+    }
+
+    fn thread_cpu_time() -> Duration {
+        let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+        // SAFETY: getrusage only writes into the provided buffer.
+        let usage = unsafe {
+            assert_eq!(libc::getrusage(libc::RUSAGE_THREAD, usage.as_mut_ptr()), 0);
+            usage.assume_init()
+        };
+        let tv = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+        tv(usage.ru_utime) + tv(usage.ru_stime)
+    }
+
+    fn buffered_copy(from: &mut File, to: &mut File) -> std::io::Result<u64> {
+        let mut buf = [0u8; BUF_SIZE];
+        let mut written = 0;
+        loop {
+            let n = from.read(&mut buf)?;
+            if n == 0 {
+                return Ok(written);
+            }
+            to.write_all(&buf[..n])?;
+            written += n as u64;
+        }
+    }
+
+    fn run<F>(name: &str, total: usize, copier: F)
+    where
+        F: FnOnce(&mut File, &mut File) -> std::io::Result<u64> + Send + 'static,
+    {
+        let (mut src_rd, mut src_wr) = pipe();
+        let (mut dst_rd, mut dst_wr) = pipe();
+
+        let writer = std::thread::spawn(move || {
+            let chunk = vec![b'x'; WRITE_SIZE];
+            let mut left = total;
+            while left > 0 {
+                let n = left.min(WRITE_SIZE);
+                src_wr.write_all(&chunk[..n]).expect("write source");
+                left -= n;
+            }
+        });
+        let reader = std::thread::spawn(move || {
+            let mut buf = vec![0u8; WRITE_SIZE];
+            let mut read = 0;
+            loop {
+                match dst_rd.read(&mut buf).expect("read destination") {
+                    0 => return read,
+                    n => read += n,
+                }
+            }
+        });
+
+        let start = Instant::now();
+        let (copied, cpu) = std::thread::spawn(move || {
+            let copied = copier(&mut src_rd, &mut dst_wr).expect("copy");
+            (copied, thread_cpu_time())
+        })
+        .join()
+        .expect("copier thread");
+        let elapsed = start.elapsed();
+
+        writer.join().expect("writer thread");
+        assert_eq!(reader.join().expect("reader thread"), total);
+        assert_eq!(copied, total as u64);
+
+        let mb = total as f64 / (1024.0 * 1024.0);
+        println!(
+            "{:<10} {:>8.1} MiB/s  copier cpu {:>8.2?} ({:.1}% of wall time)",
+            name,
+            mb / elapsed.as_secs_f64(),
+            cpu,
+            cpu.as_secs_f64() * 100.0 / elapsed.as_secs_f64()
+        );
+    }
+
+    pub fn main() {
+        let mb: usize = std::env::var("SPLICE_BENCH_MB")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1024);
+        let total = mb * 1024 * 1024;
+
+        println!("forwarding {} MiB through a pipe pair", mb);
+        run("buffered", total, buffered_copy);
+        run("splice", total, |from, to| splice::copy(from, to));
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn main() {
+    linux::main()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {}
@@ -25,7 +25,7 @@ use std::{
 
 use crate::{
     error::{Error, Result},
-    io_error, other_error,
+    io_error, metrics, other_error,
 };
 
 use crate::event::Event;
@@ -52,6 +52,7 @@ fn run_oom_monitor(mut rx: Receiver<String>, id: String, tx: EventSender) {
     let oom_box = Box::new(oom_event);
     spawn(async move {
         while let Some(_item) = rx.recv().await {
+            metrics::inc_oom_events();
             tx.send((topic.to_string(), oom_box.clone()))
                 .await
                 .unwrap_or_else(|e| warn!("send {} to publisher: {}", topic, e));
@@ -14,17 +14,92 @@
    limitations under the License.
 */
 
-use std::path::{Path, PathBuf};
+use std::{
+    collections::VecDeque,
+    os::unix::{
+        fs::{FileTypeExt, OpenOptionsExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
 
 use log::warn;
-use tokio::net::{UnixListener, UnixStream};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use tokio::{
+    fs::File,
+    io::{AsyncWrite, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+    sync::Notify,
+};
 use uuid::Uuid;
 
 use crate::{
-    util::{mkdir, xdg_runtime_dir},
+    util::{asyncify, mkdir, xdg_runtime_dir},
     Error, Result,
 };
 
+/// Annotation of an update request attaching a new stdout to the buffered console of a
+/// process, the buffered output is replayed to it before the live output.
+pub const CONSOLE_ATTACH_ANNOTATION: &str = "io.containerd.shim.console.attach";
+/// Annotation of the exec id of the process to attach to, the init process if unset.
+pub const CONSOLE_ATTACH_EXEC_ID_ANNOTATION: &str = "io.containerd.shim.console.attach.exec-id";
+/// Dir of the stdio fifos created by containerd.
+pub const CONTAINERD_FIFO_DIR: &str = "/run/containerd/fifo";
+
+/// Resolve the path of a stdout to attach to a console, which must be in the bundle of the
+/// container or in the fifo dir of containerd.
+pub async fn resolve_attach_path(stdout: &str, bundle: &str) -> Result<PathBuf> {
+    let path = tokio::fs::canonicalize(stdout)
+        .await
+        .map_err(io_error!(e, "resolve {}", stdout))?;
+    for dir in [bundle, CONTAINERD_FIFO_DIR] {
+        if let Ok(dir) = tokio::fs::canonicalize(dir).await {
+            if path.starts_with(&dir) {
+                return Ok(path);
+            }
+        }
+    }
+    Err(Error::InvalidArgument(format!(
+        "{} is neither in the bundle nor in {}",
+        stdout, CONTAINERD_FIFO_DIR
+    )))
+}
+
+/// Open a fifo to attach to a console for writing. Symlinks are not followed and anything but a
+/// fifo is rejected, so the console output is never written to a regular file.
+pub async fn open_attach_fifo(path: &Path) -> Result<File> {
+    let path = path.to_path_buf();
+    let file = asyncify(move || {
+        // nonblocking so that a fifo without a reader fails to open instead of hanging
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+            .open(&path)
+            .map_err(io_error!(e, "open {}", path.display()))?;
+        let metadata = file
+            .metadata()
+            .map_err(io_error!(e, "stat {}", path.display()))?;
+        if !metadata.file_type().is_fifo() {
+            return Err(Error::InvalidArgument(format!(
+                "{} is not a fifo",
+                path.display()
+            )));
+        }
+        let flags = OFlag::from_bits_truncate(fcntl(file.as_raw_fd(), FcntlArg::F_GETFL)?);
+        fcntl(
+            file.as_raw_fd(),
+            FcntlArg::F_SETFL(flags & !OFlag::O_NONBLOCK),
+        )?;
+        Ok(file)
+    })
+    .await?;
+    Ok(File::from_std(file))
+}
+
 pub struct ConsoleSocket {
     pub listener: UnixListener,
     pub path: PathBuf,
@@ -74,3 +149,172 @@ impl ConsoleSocket {
         }
     }
 }
+
+/// Ring buffer holding the last output of a console.
+///
+/// The console is drained into the buffer regardless of readers, so the process is never blocked
+/// on its terminal, and readers copy out of it at their own pace. Every byte has an absolute
+/// offset, a reader that falls behind resumes from the oldest byte still buffered, and a client
+/// attaching later can replay the whole buffer before the live output.
+pub struct ConsoleBuffer {
+    ring: Mutex<Ring>,
+    notifier: Notify,
+    closed: AtomicBool,
+}
+
+struct Ring {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    // offset of the byte after the last one written
+    end: u64,
+}
+
+impl Ring {
+    fn start(&self) -> u64 {
+        self.end - self.buf.len() as u64
+    }
+}
+
+impl ConsoleBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            ring: Mutex::new(Ring {
+                buf: VecDeque::with_capacity(capacity),
+                capacity,
+                end: 0,
+            }),
+            notifier: Notify::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Append console output, evicting the oldest bytes if the buffer is full.
+    pub fn write(&self, data: &[u8]) {
+        {
+            let mut ring = self.ring.lock().unwrap();
+            let capacity = ring.capacity;
+            let data = if data.len() > capacity {
+                &data[data.len() - capacity..]
+            } else {
+                data
+            };
+            let overflow = (ring.buf.len() + data.len()).saturating_sub(capacity);
+            ring.buf.drain(..overflow);
+            ring.buf.extend(data);
+            ring.end += data.len() as u64;
+        }
+        self.notifier.notify_waiters();
+    }
+
+    /// Mark the console as closed, readers return once they have copied everything buffered.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.notifier.notify_waiters();
+    }
+
+    /// Offset of the oldest byte still buffered.
+    pub fn start_offset(&self) -> u64 {
+        self.ring.lock().unwrap().start()
+    }
+
+    /// Offset of the byte after the newest one buffered.
+    pub fn end_offset(&self) -> u64 {
+        self.ring.lock().unwrap().end
+    }
+
+    /// Read everything buffered from `offset` on, returns the data and the offset to continue
+    /// reading from. Reading from an offset already evicted starts at the oldest buffered byte.
+    pub fn read_from(&self, offset: u64) -> (Vec<u8>, u64) {
+        let ring = self.ring.lock().unwrap();
+        let offset = offset.clamp(ring.start(), ring.end);
+        let skip = (offset - ring.start()) as usize;
+        (ring.buf.iter().skip(skip).copied().collect(), ring.end)
+    }
+
+    /// Copy the output from `offset` on to `w`, following new output until the console is closed.
+    pub async fn copy_to<W>(&self, offset: u64, w: &mut W) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut offset = offset;
+        loop {
+            let notified = self.notifier.notified();
+            let (data, next) = self.read_from(offset);
+            if data.is_empty() {
+                if self.closed.load(Ordering::SeqCst) {
+                    return w.flush().await;
+                }
+                notified.await;
+                continue;
+            }
+            w.write_all(&data).await?;
+            offset = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve_attach_path, ConsoleBuffer};
+
+    #[test]
+    fn test_console_buffer_evict() {
+        let buffer = ConsoleBuffer::new(8);
+        buffer.write(b"hello");
+        assert_eq!(buffer.read_from(0), (b"hello".to_vec(), 5));
+        buffer.write(b" world");
+        assert_eq!(buffer.start_offset(), 3);
+        assert_eq!(buffer.end_offset(), 11);
+        // offsets already evicted start at the oldest buffered byte
+        assert_eq!(buffer.read_from(0), (b"lo world".to_vec(), 11));
+        assert_eq!(buffer.read_from(9), (b"ld".to_vec(), 11));
+        assert_eq!(buffer.read_from(11), (vec![], 11));
+
+        buffer.write(b"0123456789");
+        assert_eq!(buffer.read_from(0), (b"23456789".to_vec(), 21));
+    }
+
+    #[tokio::test]
+    async fn test_console_buffer_replay() {
+        let buffer = std::sync::Arc::new(ConsoleBuffer::new(1024));
+        buffer.write(b"history\n");
+
+        let cloned = buffer.clone();
+        let handle = tokio::spawn(async move {
+            let mut out = Vec::new();
+            cloned
+                .copy_to(cloned.start_offset(), &mut out)
+                .await
+                .unwrap();
+            out
+        });
+        buffer.write(b"live\n");
+        buffer.close();
+
+        let out = handle.await.unwrap();
+        assert_eq!(out, b"history\nlive\n".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_attach_path() {
+        let bundle = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        let bundle_path = bundle.path().to_str().unwrap();
+        let stdout = bundle.path().join("stdout");
+        tokio::fs::File::create(&stdout).await.unwrap();
+        let outside = other.path().join("stdout");
+        tokio::fs::File::create(&outside).await.unwrap();
+        let link = bundle.path().join("link");
+        std::os::unix::fs::symlink(&outside, &link).unwrap();
+
+        let resolved = resolve_attach_path(stdout.to_str().unwrap(), bundle_path)
+            .await
+            .unwrap();
+        assert_eq!(resolved, stdout.canonicalize().unwrap());
+        for path in [&outside, &link] {
+            assert!(resolve_attach_path(path.to_str().unwrap(), bundle_path)
+                .await
+                .is_err());
+        }
+    }
+}
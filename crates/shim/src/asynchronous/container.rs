@@ -26,7 +26,11 @@ use oci_spec::runtime::LinuxResources;
 use time::OffsetDateTime;
 use tokio::sync::oneshot::Receiver;
 
-use crate::{asynchronous::processes::Process, error::Result, Error};
+use crate::{
+    asynchronous::{console::resolve_attach_path, processes::Process},
+    error::Result,
+    Error,
+};
 
 #[async_trait]
 pub trait Container {
@@ -50,6 +54,9 @@ pub trait Container {
     async fn stats(&self) -> Result<Metrics>;
     async fn all_processes(&self) -> Result<Vec<ProcessInfo>>;
     async fn close_io(&mut self, exec_id: Option<&str>) -> Result<()>;
+    async fn attach_console(&self, _exec_id: Option<&str>, _stdout: &str) -> Result<()> {
+        Err(Error::Unimplemented("attach console".to_string()))
+    }
 }
 
 #[async_trait]
@@ -188,6 +195,12 @@ where
         let process = self.get_mut_process(exec_id)?;
         process.close_io().await
     }
+
+    async fn attach_console(&self, exec_id: Option<&str>, stdout: &str) -> Result<()> {
+        let stdout = resolve_attach_path(stdout, &self.bundle).await?;
+        let process = self.get_process(exec_id)?;
+        process.attach_console(&stdout).await
+    }
 }
 
 impl<T, E, P> ContainerTemplate<T, E, P>
@@ -60,6 +60,7 @@ use tokio::{
 use crate::{
     args,
     asynchronous::{monitor::monitor_notify_by_pid, publisher::RemotePublisher},
+    auth::AuthorizedTask,
     error::{Error, Result},
     logger, parse_sockaddr, reap, socket_address,
     util::{asyncify, read_file_to_str, write_str_to_file},
@@ -188,8 +189,24 @@ where
                 logger::init(flags.debug)?;
             }
 
+            #[cfg(feature = "metrics")]
+            let metrics_address = config
+                .metrics_dir
+                .clone()
+                .or_else(|| env::var(crate::metrics::METRICS_DIR_ENV).ok())
+                .map(|dir| crate::metrics::metrics_socket(&dir, &flags.namespace, &flags.id));
+            #[cfg(feature = "metrics")]
+            if let Some(address) = metrics_address.clone() {
+                tokio::spawn(async move {
+                    if let Err(e) = crate::metrics::serve(&address).await {
+                        warn!("failed to serve metrics on {}: {}", address, e);
+                    }
+                });
+            }
+
             let publisher = RemotePublisher::new(&ttrpc_address).await?;
             let task = shim.create_task_service(publisher).await;
+            let task = AuthorizedTask::new(task, config.allowed_peers.clone());
             let task_service = create_task(Arc::new(Box::new(task)));
             let mut server = Server::new().register_service(task_service);
 
@@ -218,6 +235,10 @@ where
             if let Ok(address) = read_file_to_str("address").await {
                 remove_socket_silently(&address).await;
             }
+            #[cfg(feature = "metrics")]
+            if let Some(address) = metrics_address {
+                remove_socket_silently(&address).await;
+            }
             Ok(())
         }
     }
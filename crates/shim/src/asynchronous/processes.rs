@@ -14,7 +14,7 @@
    limitations under the License.
 */
 
-use std::{os::unix::io::AsRawFd, sync::Arc};
+use std::{os::unix::io::AsRawFd, path::Path, sync::Arc};
 
 use async_trait::async_trait;
 use containerd_shim_protos::{
@@ -22,13 +22,20 @@ use containerd_shim_protos::{
     cgroups::metrics::Metrics,
     protobuf::well_known_types::timestamp::Timestamp,
 };
+use log::warn;
 use oci_spec::runtime::LinuxResources;
 use time::OffsetDateTime;
 use tokio::fs::File;
 use tokio::sync::oneshot::{channel, Receiver, Sender};
 use tokio::sync::Mutex;
 
-use crate::{io::Stdio, ioctl_set_winsz, util::asyncify, Console};
+use crate::{
+    asynchronous::console::{open_attach_fifo, ConsoleBuffer},
+    io::Stdio,
+    ioctl_set_winsz,
+    util::asyncify,
+    Console, Error,
+};
 
 #[async_trait]
 pub trait Process {
@@ -46,6 +53,9 @@ pub trait Process {
     async fn stats(&self) -> crate::Result<Metrics>;
     async fn ps(&self) -> crate::Result<Vec<ProcessInfo>>;
     async fn close_io(&mut self) -> crate::Result<()>;
+    async fn attach_console(&self, _stdout: &Path) -> crate::Result<()> {
+        Err(Error::Unimplemented("attach console".to_string()))
+    }
 }
 
 #[async_trait]
@@ -67,6 +77,8 @@ pub struct ProcessTemplate<S> {
     pub exited_at: Option<OffsetDateTime>,
     pub wait_chan_tx: Vec<Sender<()>>,
     pub console: Option<Console>,
+    /// Buffered console output, only set for terminal processes with a console buffer configured.
+    pub console_buffer: Option<Arc<ConsoleBuffer>>,
     pub lifecycle: Arc<S>,
     pub stdin: Arc<Mutex<Option<File>>>,
 }
@@ -82,10 +94,28 @@ impl<S> ProcessTemplate<S> {
             exited_at: None,
             wait_chan_tx: vec![],
             console: None,
+            console_buffer: None,
             lifecycle: Arc::new(lifecycle),
             stdin: Arc::new(Mutex::new(None)),
         }
     }
+
+    /// Attach a new reader to the console output, everything still buffered is replayed
+    /// before the live output.
+    pub async fn attach_console(&self, stdout: &Path) -> crate::Result<()> {
+        let buffer = self.console_buffer.clone().ok_or_else(|| {
+            Error::FailedPreconditionError(format!("console of {} is not buffered", self.id))
+        })?;
+        let mut w = open_attach_fifo(stdout).await?;
+        tokio::spawn(async move {
+            let offset = buffer.start_offset();
+            buffer
+                .copy_to(offset, &mut w)
+                .await
+                .unwrap_or_else(|e| warn!("failed to replay console output: {}", e));
+        });
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -189,4 +219,62 @@ where
         }
         Ok(())
     }
+
+    async fn attach_console(&self, stdout: &Path) -> crate::Result<()> {
+        ProcessTemplate::attach_console(self, stdout).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+        sync::Arc,
+    };
+
+    use nix::{
+        fcntl::{fcntl, FcntlArg, OFlag},
+        sys::stat::Mode,
+        unistd::mkfifo,
+    };
+
+    use super::ProcessTemplate;
+    use crate::{asynchronous::console::ConsoleBuffer, io::Stdio};
+
+    #[tokio::test]
+    async fn test_attach_console() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        tokio::fs::File::create(&file).await.unwrap();
+        let stdout = dir.path().join("stdout");
+        mkfifo(&stdout, Mode::S_IRWXU).unwrap();
+        // open the reader first, as a fifo without one is not attached
+        let mut r = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&stdout)
+            .unwrap();
+        fcntl(r.as_raw_fd(), FcntlArg::F_SETFL(OFlag::empty())).unwrap();
+
+        let mut p = ProcessTemplate::new("p1", Stdio::new("", "", "", true), ());
+        assert!(p.attach_console(&stdout).await.is_err());
+
+        let buffer = Arc::new(ConsoleBuffer::new(1024));
+        buffer.write(b"history\n");
+        p.console_buffer = Some(buffer.clone());
+        assert!(p.attach_console(&file).await.is_err());
+        p.attach_console(&stdout).await.unwrap();
+        buffer.write(b"live\n");
+        buffer.close();
+
+        let out = tokio::task::spawn_blocking(move || {
+            let mut out = Vec::new();
+            r.read_to_end(&mut out).unwrap();
+            out
+        })
+        .await
+        .unwrap();
+        assert_eq!(out, b"history\nlive\n".to_vec());
+    }
 }
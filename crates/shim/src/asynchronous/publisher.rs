@@ -28,6 +28,7 @@ use containerd_shim_protos::{
 
 use crate::{
     error::Result,
+    metrics,
     util::{asyncify, connect, convert_to_any, timestamp},
 };
 
@@ -79,7 +80,10 @@ impl RemotePublisher {
         let mut req = events::ForwardRequest::new();
         req.set_envelope(envelope);
 
-        self.client.forward(ctx, &req).await?;
+        self.client.forward(ctx, &req).await.map_err(|e| {
+            metrics::inc_publish_failures(topic);
+            e
+        })?;
 
         Ok(())
     }
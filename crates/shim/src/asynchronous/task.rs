@@ -40,10 +40,12 @@ use crate::{
         StateResponse, Status, WaitRequest, WaitResponse,
     },
     asynchronous::{
+        console::{CONSOLE_ATTACH_ANNOTATION, CONSOLE_ATTACH_EXEC_ID_ANNOTATION},
         container::{Container, ContainerFactory},
         ExitSignal,
     },
     event::Event,
+    metrics,
     util::{convert_to_any, convert_to_timestamp, AsOption},
     TtrpcResult,
 };
@@ -105,6 +107,7 @@ where
     C: Container + Sync + Send + 'static,
 {
     async fn state(&self, _ctx: &TtrpcContext, req: StateRequest) -> TtrpcResult<StateResponse> {
+        let _timer = metrics::rpc_timer("task", "state");
         let container = self.get_container(req.id()).await?;
         let exec_id = req.exec_id().as_option();
         let resp = container.state(exec_id).await?;
@@ -116,6 +119,7 @@ where
         _ctx: &TtrpcContext,
         req: CreateTaskRequest,
     ) -> TtrpcResult<CreateTaskResponse> {
+        let _timer = metrics::rpc_timer("task", "create");
         info!("Create request for {:?}", &req);
         // Note: Get containers here is for getting the lock,
         // to make sure no other threads manipulate the containers metadata;
@@ -130,6 +134,7 @@ where
         resp.pid = pid;
 
         containers.insert(id.to_string(), container);
+        metrics::inc_containers();
 
         self.send_event(TaskCreate {
             container_id: req.id.to_string(),
@@ -153,6 +158,7 @@ where
     }
 
     async fn start(&self, _ctx: &TtrpcContext, req: StartRequest) -> TtrpcResult<StartResponse> {
+        let _timer = metrics::rpc_timer("task", "start");
         info!("Start request for {} {}", req.id(), req.exec_id());
         let mut container = self.get_container(req.id()).await?;
         let pid = container.start(req.exec_id.as_str().as_option()).await?;
@@ -191,6 +197,7 @@ where
     }
 
     async fn delete(&self, _ctx: &TtrpcContext, req: DeleteRequest) -> TtrpcResult<DeleteResponse> {
+        let _timer = metrics::rpc_timer("task", "delete");
         info!("Delete request for {} {}", req.id(), req.exec_id());
         let mut containers = self.containers.lock().await;
         let container = containers.get_mut(req.id()).ok_or_else(|| {
@@ -205,6 +212,9 @@ where
         if req.exec_id().is_empty() {
             self.factory.cleanup(&self.namespace, container).await?;
             containers.remove(req.id());
+            metrics::dec_containers();
+        } else {
+            metrics::dec_processes();
         }
 
         let exited_at_display = if let Some(time) = &exited_at {
@@ -238,6 +248,7 @@ where
     }
 
     async fn pids(&self, _ctx: &TtrpcContext, req: PidsRequest) -> TtrpcResult<PidsResponse> {
+        let _timer = metrics::rpc_timer("task", "pids");
         debug!("Pids request for {}", req.id());
         let container = self.get_container(req.id()).await?;
         let processes = container.all_processes().await?;
@@ -248,6 +259,7 @@ where
     }
 
     async fn kill(&self, _ctx: &TtrpcContext, req: KillRequest) -> TtrpcResult<Empty> {
+        let _timer = metrics::rpc_timer("task", "kill");
         info!(
             "Kill request for {} {} with signal {} and all {}",
             req.id(),
@@ -268,6 +280,7 @@ where
     }
 
     async fn exec(&self, _ctx: &TtrpcContext, req: ExecProcessRequest) -> TtrpcResult<Empty> {
+        let _timer = metrics::rpc_timer("task", "exec");
         info!(
             "Exec request for container {} with exec_id {} and terminal {}",
             req.id(),
@@ -277,6 +290,7 @@ where
         let exec_id = req.exec_id().to_string();
         let mut container = self.get_container(req.id()).await?;
         container.exec(req).await?;
+        metrics::inc_processes();
 
         self.send_event(TaskExecAdded {
             container_id: container.id().await,
@@ -289,6 +303,7 @@ where
     }
 
     async fn resize_pty(&self, _ctx: &TtrpcContext, req: ResizePtyRequest) -> TtrpcResult<Empty> {
+        let _timer = metrics::rpc_timer("task", "resize_pty");
         debug!(
             "Resize pty request for container {}, exec_id: {}",
             req.id(),
@@ -302,16 +317,32 @@ where
     }
 
     async fn close_io(&self, _ctx: &TtrpcContext, req: CloseIORequest) -> TtrpcResult<Empty> {
+        let _timer = metrics::rpc_timer("task", "close_io");
         let mut container = self.get_container(req.id()).await?;
         container.close_io(req.exec_id().as_option()).await?;
         Ok(Empty::new())
     }
 
     async fn update(&self, _ctx: &TtrpcContext, mut req: UpdateTaskRequest) -> TtrpcResult<Empty> {
+        let _timer = metrics::rpc_timer("task", "update");
         debug!("Update request for {:?}", req);
 
         let id = req.take_id();
 
+        if let Some(stdout) = req.annotations.get(CONSOLE_ATTACH_ANNOTATION) {
+            let exec_id = req
+                .annotations
+                .get(CONSOLE_ATTACH_EXEC_ID_ANNOTATION)
+                .map(String::as_str);
+            info!("Attach console of {} {:?} to {}", id, exec_id, stdout);
+            let container = self.get_container(&id).await?;
+            container.attach_console(exec_id, stdout).await?;
+            // a request only attaching a console carries no resources
+            if req.resources.is_none() {
+                return Ok(Empty::new());
+            }
+        }
+
         let data = req
             .resources
             .into_option()
@@ -369,6 +400,7 @@ where
     }
 
     async fn stats(&self, _ctx: &TtrpcContext, req: StatsRequest) -> TtrpcResult<StatsResponse> {
+        let _timer = metrics::rpc_timer("task", "stats");
         debug!("Stats request for {}", req.id());
         let container = self.get_container(req.id()).await?;
         let stats = container.stats().await?;
@@ -383,6 +415,7 @@ where
         _ctx: &TtrpcContext,
         req: ConnectRequest,
     ) -> TtrpcResult<ConnectResponse> {
+        let _timer = metrics::rpc_timer("task", "connect");
         info!("Connect request for {}", req.id());
         let container = self.get_container(req.id()).await?;
 
@@ -394,6 +427,7 @@ where
     }
 
     async fn shutdown(&self, _ctx: &TtrpcContext, req: ShutdownRequest) -> TtrpcResult<Empty> {
+        let _timer = metrics::rpc_timer("task", "shutdown");
         info!("Shutdown request for {}", req.id());
         let containers = self.containers.lock().await;
         if containers.len() > 0 {
@@ -14,11 +14,15 @@
    limitations under the License.
 */
 
-use std::path::Path;
+use std::{path::Path, process::Command};
 
 use containerd_shim_protos::{api::Mount, shim::oci::Options};
 use libc::mode_t;
-use nix::sys::stat::Mode;
+use log::warn;
+use nix::{
+    sys::stat::Mode,
+    unistd::{fork, ForkResult},
+};
 use serde::de::DeserializeOwned;
 use tokio::{
     fs::OpenOptions,
@@ -27,7 +31,13 @@ use tokio::{
 };
 
 use crate::{
+    asynchronous::monitor::{monitor_subscribe, monitor_unsubscribe, Subscription},
     error::{Error, Result},
+    monitor::{ExitEvent, Subject, Topic},
+    mount::{
+        compact_lowerdir_options, fuse_overlayfs_command, mount_exit_code, mount_exit_result,
+        mount_helper_command, use_fuse_overlayfs, MountExitCode, FUSE_OVERLAYFS,
+    },
     util::{AsOption, JsonOptions, CONFIG_FILE_NAME, OPTIONS_FILE_NAME, RUNTIME_FILE_NAME},
 };
 
@@ -117,16 +127,113 @@ pub async fn write_runtime(bundle: impl AsRef<Path>, binary_name: &str) -> Resul
 }
 
 pub async fn mount_rootfs(m: &Mount, target: impl AsRef<Path>) -> Result<()> {
-    let mount_type = m.type_.to_string();
-    let source = m.source.to_string();
+    let mount_type = m.type_.as_option();
+    let source = m.source.as_option();
     let options = m.options.to_vec();
     let rootfs = target.as_ref().to_owned();
-    asyncify(move || -> Result<()> {
-        let mount_type = mount_type.as_option();
-        let source = source.as_option();
-        crate::mount::mount_rootfs(mount_type, source, options.as_slice(), &rootfs)
-    })
-    .await
+    if let Some(cmd) = mount_helper_command(mount_type, source, &options, &rootfs) {
+        return exec_mount_helper(cmd).await;
+    }
+
+    // avoid hitting one page limit of mount argument buffer
+    let (chdir, options) = compact_lowerdir_options(mount_type, &options);
+    let res = match chdir.as_ref() {
+        Some(workdir) => mount_rootfs_in(workdir, mount_type, source, &options, &rootfs).await,
+        None => {
+            let mount_type = mount_type.map(str::to_string);
+            let source = source.map(str::to_string);
+            let options = options.clone();
+            let rootfs = rootfs.clone();
+            asyncify(move || -> Result<()> {
+                crate::mount::mount_rootfs(
+                    mount_type.as_deref(),
+                    source.as_deref(),
+                    options.as_slice(),
+                    &rootfs,
+                )
+            })
+            .await
+        }
+    };
+    match res {
+        Err(e) if use_fuse_overlayfs(mount_type) => {
+            warn!(
+                "mount overlay failed: {}, fall back to {}",
+                e, FUSE_OVERLAYFS
+            );
+            let mut cmd = fuse_overlayfs_command(&options, &rootfs);
+            if let Some(workdir) = chdir {
+                cmd.current_dir(workdir);
+            }
+            exec_mount_helper(cmd).await
+        }
+        res => res,
+    }
+}
+
+/// Mount in a forked process with `workdir` as current dir, which the relative lower dirs
+/// of compacted overlay options are resolved against.
+async fn mount_rootfs_in(
+    workdir: &str,
+    fs_type: Option<&str>,
+    source: Option<&str>,
+    options: &[String],
+    target: &Path,
+) -> Result<()> {
+    let s = monitor_subscribe(Topic::All).await?;
+    match unsafe { fork() } {
+        Ok(ForkResult::Parent { child, .. }) => {
+            mount_exit_result(wait_pid(i32::from(child), s).await)
+        }
+        Ok(ForkResult::Child) => {
+            let code = match std::env::set_current_dir(workdir) {
+                Ok(()) => {
+                    mount_exit_code(crate::mount::mount_rootfs(fs_type, source, options, target))
+                }
+                Err(_) => MountExitCode::ChdirErr.into(),
+            };
+            unsafe { libc::_exit(code) };
+        }
+        Err(e) => {
+            monitor_unsubscribe(s.id).await.unwrap_or_default();
+            Err(other!("fork mount process failed: {}", e))
+        }
+    }
+}
+
+/// Run a mount helper to completion, waiting for it through the reaper of the shim.
+async fn exec_mount_helper(mut cmd: Command) -> Result<()> {
+    let program = cmd.get_program().to_string_lossy().to_string();
+    let s = monitor_subscribe(Topic::All).await?;
+    let child = match cmd.spawn() {
+        Ok(c) => c,
+        Err(e) => {
+            monitor_unsubscribe(s.id).await.unwrap_or_default();
+            return Err(Error::IoError {
+                context: format!("spawn {}", program),
+                err: e,
+            });
+        }
+    };
+    match wait_pid(child.id() as i32, s).await {
+        0 => Ok(()),
+        code => Err(other!("{} exited with code {}", program, code)),
+    }
+}
+
+async fn wait_pid(pid: i32, mut s: Subscription) -> i32 {
+    loop {
+        if let Some(ExitEvent {
+            subject: Subject::Pid(epid),
+            exit_code: code,
+        }) = s.rx.recv().await
+        {
+            if pid == epid {
+                monitor_unsubscribe(s.id).await.unwrap_or_default();
+                return code;
+            }
+        }
+    }
 }
 
 pub async fn mkdir(path: impl AsRef<Path>, mode: mode_t) -> Result<()> {
@@ -0,0 +1,166 @@
+/*
+   Copyright The containerd Authors.
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+//! Authorization of the callers of the task service by the credentials of the peer process
+//! of the ttrpc connection.
+
+use std::os::unix::io::{BorrowedFd, RawFd};
+
+#[cfg(feature = "async")]
+use async_trait::async_trait;
+use log::warn;
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+
+use crate::{
+    api::*,
+    protos::ttrpc::{self, Code},
+    Task, TtrpcContext, TtrpcResult,
+};
+
+/// Processes allowed to call the shim, a caller matching any of the uids, gids or pids is
+/// allowed. An empty list allows any caller.
+///
+/// Mirrors the `PeerAllowList` of `containerd-sandbox`, which has no dependency shared with
+/// this crate, so changes to the matching rules have to be made to both.
+#[derive(Clone, Debug, Default)]
+pub struct PeerAllowList {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+    pub pids: Vec<i32>,
+}
+
+impl PeerAllowList {
+    pub fn is_empty(&self) -> bool {
+        self.uids.is_empty() && self.gids.is_empty() && self.pids.is_empty()
+    }
+
+    /// Whether a caller is allowed, the pid is `None` if it is unknown.
+    pub fn allows(&self, uid: u32, gid: u32, pid: Option<i32>) -> bool {
+        self.is_empty()
+            || self.uids.contains(&uid)
+            || self.gids.contains(&gid)
+            || pid.map(|p| self.pids.contains(&p)).unwrap_or_default()
+    }
+
+    /// Check the peer of the connection `fd` before dispatching `method`, rejected callers
+    /// are logged for auditing.
+    pub fn authorize(&self, fd: RawFd, method: &str) -> TtrpcResult<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        // SAFETY: the connection is kept open by the ttrpc server during the call.
+        let cred = getsockopt(&unsafe { BorrowedFd::borrow_raw(fd) }, PeerCredentials);
+        let allowed = match &cred {
+            Ok(c) => self.allows(c.uid(), c.gid(), Some(c.pid())),
+            Err(_) => false,
+        };
+        if allowed {
+            return Ok(());
+        }
+        match cred {
+            Ok(c) => warn!(
+                "audit: rejected {} from uid {} gid {} pid {}",
+                method,
+                c.uid(),
+                c.gid(),
+                c.pid()
+            ),
+            Err(e) => warn!("audit: rejected {} from unknown peer: {}", method, e),
+        }
+        Err(ttrpc::Error::RpcStatus(ttrpc::get_status(
+            Code::PERMISSION_DENIED,
+            format!("caller is not allowed to call {}", method),
+        )))
+    }
+}
+
+/// A task service authorizing every call by a [`PeerAllowList`] before dispatching it to
+/// the inner service.
+pub struct AuthorizedTask<T> {
+    inner: T,
+    peers: PeerAllowList,
+}
+
+impl<T> AuthorizedTask<T> {
+    pub fn new(inner: T, peers: PeerAllowList) -> Self {
+        Self { inner, peers }
+    }
+}
+
+macro_rules! authorized_task {
+    ($($method:ident($req:ty) -> $resp:ty;)*) => {
+        #[cfg(not(feature = "async"))]
+        impl<T: Task + Send + Sync> Task for AuthorizedTask<T> {
+            $(
+                fn $method(&self, ctx: &TtrpcContext, req: $req) -> TtrpcResult<$resp> {
+                    self.peers.authorize(ctx.fd, stringify!($method))?;
+                    self.inner.$method(ctx, req)
+                }
+            )*
+        }
+
+        #[cfg(feature = "async")]
+        #[async_trait]
+        impl<T: Task + Send + Sync> Task for AuthorizedTask<T> {
+            $(
+                async fn $method(&self, ctx: &TtrpcContext, req: $req) -> TtrpcResult<$resp> {
+                    self.peers.authorize(ctx.fd, stringify!($method))?;
+                    self.inner.$method(ctx, req).await
+                }
+            )*
+        }
+    };
+}
+
+authorized_task! {
+    state(StateRequest) -> StateResponse;
+    create(CreateTaskRequest) -> CreateTaskResponse;
+    start(StartRequest) -> StartResponse;
+    delete(DeleteRequest) -> DeleteResponse;
+    pids(PidsRequest) -> PidsResponse;
+    pause(PauseRequest) -> Empty;
+    resume(ResumeRequest) -> Empty;
+    checkpoint(CheckpointTaskRequest) -> Empty;
+    kill(KillRequest) -> Empty;
+    exec(ExecProcessRequest) -> Empty;
+    resize_pty(ResizePtyRequest) -> Empty;
+    close_io(CloseIORequest) -> Empty;
+    update(UpdateTaskRequest) -> Empty;
+    wait(WaitRequest) -> WaitResponse;
+    stats(StatsRequest) -> StatsResponse;
+    connect(ConnectRequest) -> ConnectResponse;
+    shutdown(ShutdownRequest) -> Empty;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_allow_list() {
+        assert!(PeerAllowList::default().allows(1000, 1000, None));
+        let peers = PeerAllowList {
+            uids: vec![0],
+            gids: vec![27],
+            pids: vec![42],
+        };
+        assert!(peers.allows(0, 0, None));
+        assert!(peers.allows(1000, 27, None));
+        assert!(peers.allows(1000, 1000, Some(42)));
+        assert!(!peers.allows(1000, 1000, Some(1)));
+        assert!(!peers.allows(1000, 1000, None));
+    }
+}
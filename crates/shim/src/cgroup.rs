@@ -26,9 +26,13 @@ use containerd_shim_protos::{
     protobuf::{well_known_types::any::Any, Message},
     shim::oci::Options,
 };
+use log::warn;
 use oci_spec::runtime::LinuxResources;
 
-use crate::error::{Error, Result};
+use crate::{
+    error::{Error, Result},
+    util::is_rootless,
+};
 
 // OOM_SCORE_ADJ_MAX is from https://github.com/torvalds/linux/blob/master/include/uapi/linux/oom.h#L10
 const OOM_SCORE_ADJ_MAX: i64 = 1000;
@@ -49,12 +53,21 @@ pub fn set_cgroup_and_oom_score(pid: u32) -> Result<()> {
             Any::parse_from_bytes(&data).and_then(|any| Options::parse_from_bytes(&any.value))?;
 
         if !opts.shim_cgroup.is_empty() {
-            add_task_to_cgroup(opts.shim_cgroup.as_str(), pid)?;
+            add_task_to_cgroup(opts.shim_cgroup.as_str(), pid).or_else(allow_rootless)?;
         }
     }
 
     // set oom score
-    adjust_oom_score(pid)
+    adjust_oom_score(pid).or_else(allow_rootless)
+}
+
+/// Privileged cgroup operations may fail in rootless mode, where they are best effort.
+fn allow_rootless(e: Error) -> Result<()> {
+    if is_rootless() {
+        warn!("ignore failure in rootless mode: {}", e);
+        return Ok(());
+    }
+    Err(e)
 }
 
 /// Add a process to the given relative cgroup path
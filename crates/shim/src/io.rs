@@ -36,3 +36,200 @@ impl Stdio {
         self.stdin.is_empty() && self.stdout.is_empty() && self.stderr.is_empty()
     }
 }
+
+/// Zero-copy forwarding between pipes with splice(2).
+///
+/// Stdio of containers is usually a pipe on the process side and a FIFO on the containerd side,
+/// so the data can be moved in the kernel instead of being copied through a userspace buffer.
+#[cfg(target_os = "linux")]
+pub mod splice {
+    use std::{
+        io,
+        os::unix::io::{AsRawFd, RawFd},
+    };
+
+    /// Upper bound of bytes moved by a single splice(2) call, a pipe rarely holds more than that.
+    const SPLICE_SIZE: usize = 1 << 20;
+
+    /// Check whether `fd` refers to a pipe or a FIFO.
+    pub fn is_pipe(fd: RawFd) -> bool {
+        let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+        // SAFETY: fstat only writes into the provided buffer.
+        if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } != 0 {
+            return false;
+        }
+        // SAFETY: fstat succeeded, so the buffer is initialized.
+        let stat = unsafe { stat.assume_init() };
+        stat.st_mode & libc::S_IFMT == libc::S_IFIFO
+    }
+
+    fn splice_once(from: RawFd, to: RawFd, flags: libc::c_uint) -> io::Result<usize> {
+        // SAFETY: null offsets are required for pipes, the kernel validates both fds.
+        let n = unsafe {
+            libc::splice(
+                from,
+                std::ptr::null_mut(),
+                to,
+                std::ptr::null_mut(),
+                SPLICE_SIZE,
+                flags | libc::SPLICE_F_MOVE,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    /// Move everything from pipe `from` to pipe `to` until `from` reaches EOF.
+    ///
+    /// Both fds are expected to be in blocking mode, returns the number of bytes moved.
+    pub fn copy<R: AsRawFd, W: AsRawFd>(from: &R, to: &W) -> io::Result<u64> {
+        let mut written = 0;
+        loop {
+            match splice_once(from.as_raw_fd(), to.as_raw_fd(), 0) {
+                Ok(0) => return Ok(written),
+                Ok(n) => written += n as u64,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+        use nix::fcntl::{fcntl, FcntlArg, OFlag};
+
+        let flags = fcntl(fd, FcntlArg::F_GETFL)?;
+        let flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
+        fcntl(fd, FcntlArg::F_SETFL(flags))?;
+        Ok(())
+    }
+
+    /// Check the readiness of `fd` without blocking.
+    #[cfg(feature = "async")]
+    fn poll_now(fd: RawFd, events: libc::c_short) -> io::Result<bool> {
+        let mut pfd = libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        };
+        // SAFETY: a single valid pollfd is passed, with a zero timeout.
+        if unsafe { libc::poll(&mut pfd, 1, 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(pfd.revents & (events | libc::POLLHUP | libc::POLLERR) != 0)
+    }
+
+    /// Move everything from pipe `from` to pipe `to` until `from` reaches EOF.
+    ///
+    /// Both fds are switched to non-blocking mode and registered to the tokio reactor,
+    /// so they must not be registered already, e.g. owned by a `tokio_pipe` stream.
+    #[cfg(feature = "async")]
+    pub async fn copy_async<R, W>(from: R, to: W) -> io::Result<u64>
+    where
+        R: AsRawFd,
+        W: AsRawFd,
+    {
+        use tokio::io::{unix::AsyncFd, Interest};
+
+        set_nonblocking(from.as_raw_fd())?;
+        set_nonblocking(to.as_raw_fd())?;
+        let src = AsyncFd::with_interest(from, Interest::READABLE)?;
+        let dst = AsyncFd::with_interest(to, Interest::WRITABLE)?;
+
+        let mut written = 0;
+        loop {
+            let mut readable = src.readable().await?;
+            let mut writable = dst.writable().await?;
+            match splice_once(src.as_raw_fd(), dst.as_raw_fd(), libc::SPLICE_F_NONBLOCK) {
+                Ok(0) => return Ok(written),
+                Ok(n) => written += n as u64,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    // splice doesn't tell which side would block, ask both of them.
+                    if !poll_now(src.as_raw_fd(), libc::POLLIN)? {
+                        readable.clear_ready();
+                    }
+                    if !poll_now(dst.as_raw_fd(), libc::POLLOUT)? {
+                        writable.clear_ready();
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{
+            fs::File,
+            io::{Read, Write},
+        };
+
+        use super::*;
+
+        fn pipe() -> (File, File) {
+            let (rd, wr) = nix::unistd::pipe().unwrap();
+            (File::from(rd), File::from(wr))
+        }
+
+        #[test]
+        fn test_is_pipe() {
+            let (rd, wr) = pipe();
+            assert!(is_pipe(rd.as_raw_fd()));
+            assert!(is_pipe(wr.as_raw_fd()));
+
+            let file = tempfile::tempfile().unwrap();
+            assert!(!is_pipe(file.as_raw_fd()));
+            assert!(!is_pipe(-1));
+        }
+
+        #[test]
+        fn test_copy() {
+            let (src_rd, mut src_wr) = pipe();
+            let (dst_rd, dst_wr) = pipe();
+            let data: Vec<u8> = (0..256 * 1024).map(|i| i as u8).collect();
+
+            let writer = {
+                let data = data.clone();
+                std::thread::spawn(move || src_wr.write_all(&data).unwrap())
+            };
+            let reader = std::thread::spawn(move || {
+                let mut dst_rd = dst_rd;
+                let mut out = Vec::new();
+                dst_rd.read_to_end(&mut out).unwrap();
+                out
+            });
+
+            let n = copy(&src_rd, &dst_wr).unwrap();
+            drop(dst_wr);
+            writer.join().unwrap();
+            assert_eq!(n, data.len() as u64);
+            assert_eq!(reader.join().unwrap(), data);
+        }
+
+        #[cfg(feature = "async")]
+        #[tokio::test]
+        async fn test_copy_async() {
+            let (src_rd, mut src_wr) = pipe();
+            let (mut dst_rd, dst_wr) = pipe();
+            let data: Vec<u8> = (0..256 * 1024).map(|i| i as u8).collect();
+
+            let writer = {
+                let data = data.clone();
+                std::thread::spawn(move || src_wr.write_all(&data).unwrap())
+            };
+            let reader = std::thread::spawn(move || {
+                let mut out = Vec::new();
+                dst_rd.read_to_end(&mut out).unwrap();
+                out
+            });
+
+            let n = copy_async(src_rd, dst_wr).await.unwrap();
+            writer.join().unwrap();
+            assert_eq!(n, data.len() as u64);
+            assert_eq!(reader.join().unwrap(), data);
+        }
+    }
+}
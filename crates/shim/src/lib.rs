@@ -55,10 +55,12 @@ pub mod error;
 mod args;
 #[cfg(feature = "async")]
 pub mod asynchronous;
+pub mod auth;
 pub mod cgroup;
 pub mod event;
 pub mod io;
 mod logger;
+pub mod metrics;
 pub mod monitor;
 pub mod mount;
 mod reap;
@@ -127,6 +129,12 @@ pub struct Config {
     pub no_reaper: bool,
     /// Disables setting the shim as a child subreaper.
     pub no_sub_reaper: bool,
+    /// Directory of the unix sockets to serve metrics on, one socket per shim instance.
+    /// Falls back to the `SHIM_METRICS_DIR` environment variable, metrics are not served if
+    /// neither is set. Ignored unless the `metrics` feature is enabled.
+    pub metrics_dir: Option<String>,
+    /// Processes allowed to call the task service, any local process if empty.
+    pub allowed_peers: auth::PeerAllowList,
 }
 
 /// Startup options received from containerd to start new shim instance.
@@ -159,6 +167,15 @@ pub const SOCKET_ROOT: &str = "/run/containerd";
 #[cfg(target_os = "macos")]
 pub const SOCKET_ROOT: &str = "/var/run/containerd";
 
+/// Directory of shim sockets, which is under `$XDG_RUNTIME_DIR` in rootless mode.
+pub fn socket_root() -> String {
+    if util::is_rootless() {
+        format!("{}/containerd", util::xdg_runtime_dir())
+    } else {
+        SOCKET_ROOT.to_string()
+    }
+}
+
 /// Make socket path from containerd socket path, namespace and id.
 pub fn socket_address(socket_path: &str, namespace: &str, id: &str) -> String {
     let path = PathBuf::from(socket_path)
@@ -173,7 +190,7 @@ pub fn socket_address(socket_path: &str, namespace: &str, id: &str) -> String {
         hasher.finish()
     };
 
-    format!("unix://{}/{:x}.sock", SOCKET_ROOT, hash)
+    format!("unix://{}/{:x}.sock", socket_root(), hash)
 }
 
 fn parse_sockaddr(addr: &str) -> &str {
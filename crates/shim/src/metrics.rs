@@ -0,0 +1,334 @@
+/*
+   Copyright The containerd Authors.
+
+   Licensed under the Apache License, Version 2.0 (the "License");
+   you may not use this file except in compliance with the License.
+   You may obtain a copy of the License at
+
+       http://www.apache.org/licenses/LICENSE-2.0
+
+   Unless required by applicable law or agreed to in writing, software
+   distributed under the License is distributed on an "AS IS" BASIS,
+   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+   See the License for the specific language governing permissions and
+   limitations under the License.
+*/
+
+//! Opt-in Prometheus metrics of the shim process.
+//!
+//! Metrics are only collected when the `metrics` feature is enabled. Without it every helper in
+//! this module is a no-op, so shim implementations can record metrics unconditionally.
+//!
+//! The collected metrics are served in the Prometheus text exposition format on a unix socket,
+//! see [`Config::metrics_dir`](crate::Config::metrics_dir).
+
+use std::time::Duration;
+
+/// Environment variable to configure the directory of metrics sockets,
+/// used when [`Config::metrics_dir`](crate::Config::metrics_dir) is not set.
+pub const METRICS_DIR_ENV: &str = "SHIM_METRICS_DIR";
+
+#[cfg(feature = "metrics")]
+mod registry {
+    use lazy_static::lazy_static;
+    use prometheus::{
+        exponential_buckets, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+        Opts, Registry,
+    };
+
+    pub(super) struct Metrics {
+        pub registry: Registry,
+        pub rpc_duration: HistogramVec,
+        pub runc_duration: HistogramVec,
+        pub runc_failures: IntCounterVec,
+        pub publish_failures: IntCounterVec,
+        pub containers: IntGauge,
+        pub processes: IntGauge,
+        pub oom_events: IntCounter,
+    }
+
+    impl Metrics {
+        fn new() -> prometheus::Result<Self> {
+            let registry = Registry::new_custom(Some("containerd_shim".to_string()), None)?;
+            // 1ms to ~16s
+            let buckets = exponential_buckets(0.001, 2.0, 15)?;
+            let rpc_duration = HistogramVec::new(
+                HistogramOpts::new("rpc_duration_seconds", "Latency of ttrpc requests")
+                    .buckets(buckets.clone()),
+                &["service", "method"],
+            )?;
+            let runc_duration = HistogramVec::new(
+                HistogramOpts::new(
+                    "runtime_command_duration_seconds",
+                    "Duration of runtime subcommands",
+                )
+                .buckets(buckets),
+                &["command"],
+            )?;
+            let runc_failures = IntCounterVec::new(
+                Opts::new(
+                    "runtime_command_failures_total",
+                    "Number of failed runtime subcommands",
+                ),
+                &["command"],
+            )?;
+            let publish_failures = IntCounterVec::new(
+                Opts::new(
+                    "event_publish_failures_total",
+                    "Number of events failed to publish to containerd",
+                ),
+                &["topic"],
+            )?;
+            let containers = IntGauge::new("containers", "Number of live containers")?;
+            let processes = IntGauge::new("processes", "Number of live exec processes")?;
+            let oom_events = IntCounter::new("oom_events_total", "Number of OOM events")?;
+
+            registry.register(Box::new(rpc_duration.clone()))?;
+            registry.register(Box::new(runc_duration.clone()))?;
+            registry.register(Box::new(runc_failures.clone()))?;
+            registry.register(Box::new(publish_failures.clone()))?;
+            registry.register(Box::new(containers.clone()))?;
+            registry.register(Box::new(processes.clone()))?;
+            registry.register(Box::new(oom_events.clone()))?;
+
+            Ok(Self {
+                registry,
+                rpc_duration,
+                runc_duration,
+                runc_failures,
+                publish_failures,
+                containers,
+                processes,
+                oom_events,
+            })
+        }
+    }
+
+    lazy_static! {
+        pub(super) static ref METRICS: Metrics =
+            Metrics::new().expect("failed to register shim metrics");
+    }
+}
+
+/// Records the latency of a ttrpc request when dropped.
+pub struct RpcTimer {
+    #[cfg(feature = "metrics")]
+    _timer: prometheus::HistogramTimer,
+}
+
+/// Start timing a ttrpc request, the latency is observed when the returned timer is dropped.
+pub fn rpc_timer(_service: &str, _method: &str) -> RpcTimer {
+    RpcTimer {
+        #[cfg(feature = "metrics")]
+        _timer: registry::METRICS
+            .rpc_duration
+            .with_label_values(&[_service, _method])
+            .start_timer(),
+    }
+}
+
+/// Record the duration and result of a runtime subcommand, such as `runc create`.
+pub fn observe_runtime_command(_command: &str, _duration: Duration, _success: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let m = &registry::METRICS;
+        m.runc_duration
+            .with_label_values(&[_command])
+            .observe(_duration.as_secs_f64());
+        if !_success {
+            m.runc_failures.with_label_values(&[_command]).inc();
+        }
+    }
+}
+
+/// Record an event that failed to publish to containerd.
+pub fn inc_publish_failures(_topic: &str) {
+    #[cfg(feature = "metrics")]
+    registry::METRICS
+        .publish_failures
+        .with_label_values(&[_topic])
+        .inc();
+}
+
+/// Record a container being added to the shim.
+pub fn inc_containers() {
+    #[cfg(feature = "metrics")]
+    registry::METRICS.containers.inc();
+}
+
+/// Record a container being removed from the shim.
+pub fn dec_containers() {
+    #[cfg(feature = "metrics")]
+    registry::METRICS.containers.dec();
+}
+
+/// Record an exec process being added to a container.
+pub fn inc_processes() {
+    #[cfg(feature = "metrics")]
+    registry::METRICS.processes.inc();
+}
+
+/// Record an exec process being removed from a container.
+pub fn dec_processes() {
+    #[cfg(feature = "metrics")]
+    registry::METRICS.processes.dec();
+}
+
+/// Record an OOM event of a container.
+pub fn inc_oom_events() {
+    #[cfg(feature = "metrics")]
+    registry::METRICS.oom_events.inc();
+}
+
+/// Encode all collected metrics in the Prometheus text exposition format.
+#[cfg(feature = "metrics")]
+pub fn gather() -> crate::Result<String> {
+    use prometheus::{Encoder, TextEncoder};
+
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&registry::METRICS.registry.gather(), &mut buf)
+        .map_err(|e| crate::Error::Other(format!("failed to encode metrics: {}", e)))?;
+    String::from_utf8(buf).map_err(|e| crate::Error::Other(e.to_string()))
+}
+
+/// Build the path of the metrics socket of a shim instance from the configured directory.
+pub fn metrics_socket(dir: &str, namespace: &str, id: &str) -> String {
+    std::path::Path::new(crate::parse_sockaddr(dir))
+        .join(namespace)
+        .join(format!("{}.sock", id))
+        .display()
+        .to_string()
+}
+
+#[cfg(feature = "metrics")]
+fn http_response(body: &str) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+/// Serve metrics over HTTP on the unix socket at `address`.
+///
+/// Every connection is answered with the current metrics regardless of the request path.
+#[cfg(all(feature = "metrics", feature = "async"))]
+pub async fn serve(address: &str) -> crate::Result<()> {
+    use log::warn;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{error::Error, io_error};
+
+    let path = crate::parse_sockaddr(address).to_string();
+    if let Some(parent) = std::path::Path::new(&path).parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(io_error!(e, "create metrics socket dir"))?;
+    }
+    let _ = tokio::fs::remove_file(&path).await;
+    let listener = tokio::net::UnixListener::bind(&path).map_err(io_error!(e, "bind {}", path))?;
+    loop {
+        let (mut stream, _) = listener
+            .accept()
+            .await
+            .map_err(io_error!(e, "accept metrics connection"))?;
+        tokio::spawn(async move {
+            // The request is not interpreted, only drain what the client sent.
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            let body = gather().unwrap_or_else(|e| {
+                warn!("failed to gather metrics: {}", e);
+                String::new()
+            });
+            stream
+                .write_all(http_response(&body).as_bytes())
+                .await
+                .unwrap_or_else(|e| warn!("failed to write metrics: {}", e));
+        });
+    }
+}
+
+/// Serve metrics over HTTP on the unix socket at `address` in a background thread.
+///
+/// Every connection is answered with the current metrics regardless of the request path.
+#[cfg(all(feature = "metrics", not(feature = "async")))]
+pub fn serve(address: &str) -> crate::Result<std::thread::JoinHandle<()>> {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixListener,
+    };
+
+    use log::warn;
+
+    use crate::{error::Error, io_error};
+
+    let path = crate::parse_sockaddr(address).to_string();
+    if let Some(parent) = std::path::Path::new(&path).parent() {
+        std::fs::create_dir_all(parent).map_err(io_error!(e, "create metrics socket dir"))?;
+    }
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).map_err(io_error!(e, "bind {}", path))?;
+    let handle = std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("failed to accept metrics connection: {}", e);
+                    continue;
+                }
+            };
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf);
+            let body = gather().unwrap_or_else(|e| {
+                warn!("failed to gather metrics: {}", e);
+                String::new()
+            });
+            stream
+                .write_all(http_response(&body).as_bytes())
+                .unwrap_or_else(|e| warn!("failed to write metrics: {}", e));
+        }
+    });
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_socket() {
+        assert_eq!(
+            metrics_socket("unix:///run/shim-metrics", "k8s.io", "abc"),
+            "/run/shim-metrics/k8s.io/abc.sock"
+        );
+        assert_eq!(
+            metrics_socket("/run/shim-metrics", "default", "abc"),
+            "/run/shim-metrics/default/abc.sock"
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_gather() {
+        {
+            let _timer = rpc_timer("task", "create");
+        }
+        observe_runtime_command("create", Duration::from_millis(3), false);
+        inc_publish_failures("/tasks/exit");
+        inc_containers();
+        inc_oom_events();
+
+        let text = gather().unwrap();
+        assert!(text.contains(
+            "containerd_shim_rpc_duration_seconds_count{method=\"create\",service=\"task\"} 1"
+        ));
+        assert!(
+            text.contains("containerd_shim_runtime_command_failures_total{command=\"create\"} 1")
+        );
+        assert!(
+            text.contains("containerd_shim_event_publish_failures_total{topic=\"/tasks/exit\"} 1")
+        );
+        assert!(text.contains("containerd_shim_oom_events_total 1"));
+    }
+}
@@ -20,12 +20,15 @@ use std::{
     env,
     ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Not},
     path::Path,
+    process::{Command, Stdio},
 };
 
 use lazy_static::lazy_static;
-use log::error;
+use log::{error, warn};
 #[cfg(target_os = "linux")]
-use nix::mount::{mount, MsFlags};
+use nix::errno::Errno;
+#[cfg(target_os = "linux")]
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
 #[cfg(target_os = "linux")]
 use nix::unistd::{fork, ForkResult};
 use regex::Regex;
@@ -33,6 +36,7 @@ use regex::Regex;
 use crate::error::{Error, Result};
 #[cfg(not(feature = "async"))]
 use crate::monitor::{monitor_subscribe, wait_pid, Topic};
+use crate::util::is_rootless;
 
 #[cfg(target_os = "linux")]
 struct Flag {
@@ -42,6 +46,19 @@ struct Flag {
 
 const OVERLAY_LOWERDIR_PREFIX: &str = "lowerdir=";
 
+/// Helper binary to mount overlay filesystems without privileges.
+pub const FUSE_OVERLAYFS: &str = "fuse-overlayfs";
+
+/// Binaries exec'ed to mount filesystems of type `<prefix><subtype>`, following containerd's
+/// convention, e.g. `fuse3.fuse-overlayfs` is mounted by `mount.fuse3`.
+const MOUNT_HELPERS: [(&str, &str); 2] = [("fuse.", "mount.fuse"), ("fuse3.", "mount.fuse3")];
+
+/// Retries of unmounting a busy mount point, and the interval between them.
+#[cfg(target_os = "linux")]
+const UNMOUNT_RETRIES: u32 = 50;
+#[cfg(target_os = "linux")]
+const UNMOUNT_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
 #[cfg(target_os = "linux")]
 lazy_static! {
     static ref MOUNT_FLAGS: HashMap<&'static str, Flag> = {
@@ -354,7 +371,7 @@ impl LowerdirCompactor {
     }
 }
 
-enum MountExitCode {
+pub(crate) enum MountExitCode {
     NixUnknownErr,
     ChdirErr,
     Success,
@@ -418,6 +435,162 @@ impl From<MountExitCode> for Result<()> {
     }
 }
 
+#[cfg(feature = "async")]
+impl From<Result<()>> for MountExitCode {
+    fn from(res: Result<()>) -> Self {
+        match res {
+            Ok(()) => MountExitCode::Success,
+            Err(Error::MountError { err, .. }) => err.into(),
+            Err(_) => MountExitCode::NixUnknownErr,
+        }
+    }
+}
+
+/// Convert the result of a mount done in a forked process to its exit code.
+#[cfg(feature = "async")]
+pub(crate) fn mount_exit_code(res: Result<()>) -> i32 {
+    MountExitCode::from(res).into()
+}
+
+/// Convert the exit code of a forked mount process back to the mount result.
+#[cfg(feature = "async")]
+pub(crate) fn mount_exit_result(code: i32) -> Result<()> {
+    MountExitCode::from(code).into()
+}
+
+/// Shorten the `lowerdir` option of overlay mounts that would exceed the one page limit
+/// of the mount data, by making the lower dirs relative to their longest common prefix.
+///
+/// Returns the dir the mount has to be done in if the options were compacted.
+#[cfg(target_os = "linux")]
+pub(crate) fn compact_lowerdir_options(
+    fs_type: Option<&str>,
+    options: &[String],
+) -> (Option<String>, Vec<String>) {
+    // NOTE: 512 id a buffer during pagesize check.
+    if fs_type.unwrap_or("") == "overlay" && options_size(options) >= page_size::get() - 512 {
+        LowerdirCompactor::new(options).compact()
+    } else {
+        (None, options.to_vec())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn compact_lowerdir_options(
+    _fs_type: Option<&str>,
+    options: &[String],
+) -> (Option<String>, Vec<String>) {
+    (None, options.to_vec())
+}
+
+/// Build the command of the helper binary mounting `fs_type`, if it needs one.
+///
+/// Like containerd, the helper is invoked as `<helper> <source> <target> [-o <option>]... -t <subtype>`.
+pub fn mount_helper_command(
+    fs_type: Option<&str>,
+    source: Option<&str>,
+    options: &[String],
+    target: impl AsRef<Path>,
+) -> Option<Command> {
+    let fs_type = fs_type?;
+    let (prefix, helper) = MOUNT_HELPERS
+        .iter()
+        .find(|(prefix, _)| fs_type.starts_with(prefix))?;
+    let mut cmd = Command::new(helper);
+    cmd.arg(source.unwrap_or("none")).arg(target.as_ref());
+    for o in options {
+        cmd.arg("-o").arg(o);
+    }
+    cmd.arg("-t")
+        .arg(fs_type.trim_start_matches(prefix))
+        .stdin(Stdio::null());
+    Some(cmd)
+}
+
+/// Run a mount helper to completion, waiting for it through the reaper of the shim.
+#[cfg(not(feature = "async"))]
+#[cfg(target_os = "linux")]
+fn exec_mount_helper(mut cmd: Command) -> Result<()> {
+    let program = cmd.get_program().to_string_lossy().to_string();
+    let s = monitor_subscribe(Topic::All)?;
+    let child = cmd.spawn().map_err(io_error!(e, "spawn {}", program))?;
+    match wait_pid(child.id() as i32, s) {
+        0 => Ok(()),
+        code => Err(other!("{} exited with code {}", program, code)),
+    }
+}
+
+/// Unmount `target`, retrying while it is busy.
+///
+/// Nothing being mounted on `target`, or `target` not existing, is not an error.
+#[cfg(target_os = "linux")]
+pub fn unmount(target: impl AsRef<Path>, flags: MntFlags) -> Result<()> {
+    let target = target.as_ref();
+    let mut retries = 0;
+    loop {
+        match umount2(target, flags) {
+            Ok(()) | Err(Errno::EINVAL) | Err(Errno::ENOENT) => return Ok(()),
+            Err(Errno::EBUSY) if retries < UNMOUNT_RETRIES => {
+                retries += 1;
+                std::thread::sleep(UNMOUNT_RETRY_INTERVAL);
+            }
+            Err(e) => {
+                return Err(Error::MountError {
+                    context: format!("Unmount {}", target.display()),
+                    err: e,
+                })
+            }
+        }
+    }
+}
+
+/// Unmount all the mounts stacked on `target`, e.g. several rootfs mounts of a container.
+#[cfg(target_os = "linux")]
+pub fn unmount_all(target: impl AsRef<Path>, flags: MntFlags) -> Result<()> {
+    let target = target.as_ref();
+    loop {
+        match umount2(target, flags) {
+            Ok(()) => continue,
+            Err(Errno::EINVAL) | Err(Errno::ENOENT) => return Ok(()),
+            // leave the retries of a busy mount point to unmount
+            Err(Errno::EBUSY) => unmount(target, flags)?,
+            Err(e) => {
+                return Err(Error::MountError {
+                    context: format!("Unmount {}", target.display()),
+                    err: e,
+                })
+            }
+        }
+    }
+}
+
+/// Whether a failed mount of `fs_type` should be retried with fuse-overlayfs,
+/// as the kernel may refuse overlay mounts in the user namespace of a rootless shim.
+pub fn use_fuse_overlayfs(fs_type: Option<&str>) -> bool {
+    fs_type == Some("overlay") && is_rootless()
+}
+
+/// Build the command mounting an overlay filesystem at `target` with fuse-overlayfs,
+/// `options` are passed through as they are for the kernel overlay filesystem.
+pub fn fuse_overlayfs_command(options: &[String], target: impl AsRef<Path>) -> Command {
+    let mut cmd = Command::new(FUSE_OVERLAYFS);
+    cmd.arg("-o")
+        .arg(options.join(","))
+        .arg(target.as_ref())
+        .stdin(Stdio::null());
+    cmd
+}
+
+#[cfg(not(feature = "async"))]
+#[cfg(target_os = "linux")]
+fn mount_fuse_overlayfs(options: &[String], workdir: Option<String>, target: &Path) -> Result<()> {
+    let mut cmd = fuse_overlayfs_command(options, target);
+    if let Some(workdir) = workdir {
+        cmd.current_dir(workdir);
+    }
+    exec_mount_helper(cmd)
+}
+
 #[cfg(not(feature = "async"))]
 #[cfg(target_os = "linux")]
 pub fn mount_rootfs(
@@ -426,17 +599,13 @@ pub fn mount_rootfs(
     options: &[String],
     target: impl AsRef<Path>,
 ) -> Result<()> {
-    //TODO add helper to mount fuse
+    if let Some(cmd) = mount_helper_command(fs_type, source, options, target.as_ref()) {
+        return exec_mount_helper(cmd);
+    }
+
     let max_size = page_size::get();
     // avoid hitting one page limit of mount argument buffer
-    //
-    // NOTE: 512 id a buffer during pagesize check.
-    let (chdir, options) =
-        if fs_type.unwrap_or("") == "overlay" && options_size(options) >= max_size - 512 {
-            LowerdirCompactor::new(options).compact()
-        } else {
-            (None, options.to_vec())
-        };
+    let (chdir, options) = compact_lowerdir_options(fs_type, options);
 
     let mut flags: MsFlags = MsFlags::from_bits(0).unwrap();
     let mut data = Vec::new();
@@ -467,7 +636,17 @@ pub fn mount_rootfs(
     match unsafe { fork() } {
         Ok(ForkResult::Parent { child, .. }) => {
             let code: MountExitCode = wait_pid(i32::from(child), s).into();
-            code.into()
+            let res: Result<()> = code.into();
+            match res {
+                Err(e) if use_fuse_overlayfs(fs_type) => {
+                    warn!(
+                        "mount overlay failed: {}, fall back to {}",
+                        e, FUSE_OVERLAYFS
+                    );
+                    mount_fuse_overlayfs(&options, chdir, target.as_ref())
+                }
+                res => res,
+            }
         }
         Ok(ForkResult::Child) => {
             if let Some(workdir) = chdir {
@@ -541,8 +720,8 @@ pub fn mount_rootfs(
     options: &[String],
     target: impl AsRef<Path>,
 ) -> Result<()> {
-    //TODO add helper to mount fuse
-    //TODO compactLowerdirOption for overlay
+    // NOTE: mounts with helpers and overlay mounts with compacted lowerdir are handled by
+    // the async `mount_rootfs` in `asynchronous::util`, which waits for child processes.
     let mut flags: MsFlags = MsFlags::from_bits(0).unwrap();
     let mut data = Vec::new();
     options.iter().for_each(|x| {
@@ -717,4 +896,77 @@ mod tests {
             assert_eq!(options, expected_options);
         }
     }
+
+    #[test]
+    fn test_compact_lowerdir_options() {
+        let short = vec!["lowerdir=/snapshots/1/fs:/snapshots/2/fs".to_string()];
+        assert_eq!(
+            compact_lowerdir_options(Some("overlay"), &short),
+            (None, short.clone())
+        );
+
+        let lowerdirs = (0..1000)
+            .map(|i| format!("/var/lib/containerd/snapshots/{}/fs", i))
+            .collect::<Vec<String>>();
+        let long = vec![format!("lowerdir={}", lowerdirs.join(":"))];
+        assert_eq!(
+            compact_lowerdir_options(Some("ext4"), &long),
+            (None, long.clone())
+        );
+        let (chdir, options) = compact_lowerdir_options(Some("overlay"), &long);
+        assert_eq!(chdir, Some("/var/lib/containerd/snapshots/".to_string()));
+        assert!(options[0].starts_with("lowerdir=0/fs:1/fs:"));
+    }
+
+    #[test]
+    fn test_mount_helper_command() {
+        let options = vec!["lowerdir=/a:/b".to_string(), "upperdir=/c".to_string()];
+        assert!(mount_helper_command(Some("overlay"), None, &options, "/rootfs").is_none());
+        assert!(mount_helper_command(None, None, &options, "/rootfs").is_none());
+
+        let cmd = mount_helper_command(
+            Some("fuse3.fuse-overlayfs"),
+            Some("overlay"),
+            &options,
+            "/rootfs",
+        )
+        .unwrap();
+        assert_eq!(cmd.get_program(), "mount.fuse3");
+        let args = cmd
+            .get_args()
+            .map(|x| x.to_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            args,
+            vec![
+                "overlay",
+                "/rootfs",
+                "-o",
+                "lowerdir=/a:/b",
+                "-o",
+                "upperdir=/c",
+                "-t",
+                "fuse-overlayfs"
+            ]
+        );
+
+        let cmd = mount_helper_command(Some("fuse.sshfs"), Some("host:/"), &[], "/mnt").unwrap();
+        assert_eq!(cmd.get_program(), "mount.fuse");
+        let args = cmd
+            .get_args()
+            .map(|x| x.to_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(args, vec!["host:/", "/mnt", "-t", "sshfs"]);
+    }
+
+    #[test]
+    fn test_unmount_not_mounted() {
+        // unprivileged users get EPERM before the target is checked
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        unmount_all(dir.path(), MntFlags::empty()).unwrap();
+        unmount_all(dir.path().join("missing"), MntFlags::empty()).unwrap();
+    }
 }
@@ -58,7 +58,9 @@ use util::{read_address, write_address};
 
 use crate::{
     api::DeleteResponse,
-    args, logger, parse_sockaddr,
+    args,
+    auth::AuthorizedTask,
+    logger, parse_sockaddr,
     protos::{
         protobuf::Message,
         shim::shim_ttrpc::{create_task, Task},
@@ -203,8 +205,22 @@ where
                 logger::init(flags.debug)?;
             }
 
+            #[cfg(feature = "metrics")]
+            let metrics_address = config
+                .metrics_dir
+                .clone()
+                .or_else(|| env::var(crate::metrics::METRICS_DIR_ENV).ok())
+                .map(|dir| crate::metrics::metrics_socket(&dir, &flags.namespace, &flags.id));
+            #[cfg(feature = "metrics")]
+            if let Some(address) = &metrics_address {
+                if let Err(e) = crate::metrics::serve(address) {
+                    warn!("failed to serve metrics on {}: {}", address, e);
+                }
+            }
+
             let publisher = publisher::RemotePublisher::new(&ttrpc_address)?;
             let task = shim.create_task_service(publisher);
+            let task = AuthorizedTask::new(task, config.allowed_peers.clone());
             let task_service = create_task(Arc::new(Box::new(task)));
             let mut server = Server::new().register_service(task_service);
             server = server.add_listener(SOCKET_FD)?;
@@ -221,6 +237,10 @@ where
             // socket might be leaking.
             let address = read_address()?;
             remove_socket_silently(&address);
+            #[cfg(feature = "metrics")]
+            if let Some(address) = metrics_address {
+                remove_socket_silently(&address);
+            }
             Ok(())
         }
     }
@@ -27,6 +27,7 @@ use containerd_shim_protos as client;
 
 use crate::{
     error::Result,
+    metrics,
     util::{connect, convert_to_any, timestamp},
 };
 
@@ -72,7 +73,10 @@ impl RemotePublisher {
         let mut req = events::ForwardRequest::new();
         req.set_envelope(envelope);
 
-        self.client.forward(ctx, &req)?;
+        self.client.forward(ctx, &req).map_err(|e| {
+            metrics::inc_publish_failures(topic);
+            e
+        })?;
 
         Ok(())
     }
@@ -168,11 +168,28 @@ pub fn convert_to_any(obj: Box<dyn MessageDyn>) -> Result<Any> {
 
 /// Returns a temp dir. If the environment variable "XDG_RUNTIME_DIR" is set, return its value.
 /// Otherwise if `std::env::temp_dir()` failed, return current dir or return the temp dir depended on OS.
-pub(crate) fn xdg_runtime_dir() -> String {
+pub fn xdg_runtime_dir() -> String {
     env::var("XDG_RUNTIME_DIR")
         .unwrap_or_else(|_| env::temp_dir().to_str().unwrap_or(".").to_string())
 }
 
+/// Env var forcing the shim into rootless mode when set to `1` or `true`, for a shim running as
+/// root of a user namespace, e.g. under RootlessKit.
+pub const ROOTLESS_ENV: &str = "CONTAINERD_SHIM_ROOTLESS";
+
+/// Returns true if the shim runs rootless, either as a non-root user or with
+/// [`ROOTLESS_ENV`] set.
+///
+/// In rootless mode, runtime state is kept under `$XDG_RUNTIME_DIR` and privileged
+/// operations such as moving processes between cgroups are allowed to fail.
+pub fn is_rootless() -> bool {
+    lazy_static::lazy_static! {
+        static ref ROOTLESS: bool = !nix::unistd::geteuid().is_root()
+            || matches!(env::var(ROOTLESS_ENV).as_deref(), Ok("1") | Ok("true"));
+    }
+    *ROOTLESS
+}
+
 pub trait IntoOption
 where
     Self: Sized,